@@ -1,17 +1,25 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 
 use crate::common::*;
+use crate::eval::*;
+use crate::lsp::*;
 use crate::terminal::*;
 use crate::undo::*;
+use crate::view::*;
+
+const MAX_CHANGES: usize = 100;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     Normal,
     Insert,
+    Replace,
     Visual,
     VisualLine,
+    Command,
 }
 
 impl Mode {
@@ -19,8 +27,10 @@ impl Mode {
         match self {
             Mode::Normal => (Color::Magenta, Color::Red),
             Mode::Insert => (Color::Blue, Color::Cyan),
+            Mode::Replace => (Color::Red, Color::Yellow),
             Mode::Visual => (Color::Yellow, Color::White),
             Mode::VisualLine => (Color::Yellow, Color::White),
+            Mode::Command => (Color::Green, Color::White),
         }
     }
 }
@@ -33,8 +43,10 @@ impl fmt::Display for Mode {
             match self {
                 Mode::Normal => "Normal",
                 Mode::Insert => "Insert",
+                Mode::Replace => "Replace",
                 Mode::Visual => "Visual",
                 Mode::VisualLine => "Visual-Line",
+                Mode::Command => "Command",
             }
         )
     }
@@ -42,74 +54,168 @@ impl fmt::Display for Mode {
 
 pub struct Buffer {
     pub contents: Vec<String32>,
+    pub disk_contents: Vec<String32>, // The file as it was last read or written
     pub file: File,
+    pub path: String,
+    pub modified: bool,
     pub clip: Vec<String32>,
     pub clip_lines: bool,
-    pub scroll: Coord,
-    pub cursor: Coord,
-    pub cursor_col_goal: usize,
-    pub selection_start: Coord,
     pub mode: Mode,
     pub history: History,
     pub show_history: bool,
+    pub changes: Vec<TextAction>, // Edits not yet seen by the other views of the buffer
+    pub last_position: (Coord, Coord), // Cursor and scroll when the buffer was last shown
+    pub visual_marks: (Coord, Coord), // Bounds of the last selection, for '< and '>
+    pub marks: HashMap<char, Coord>, // Set with m, uppercase ones are in only one buffer
+    pub change_list: Vec<Coord>,  // Places of the latest edits, oldest first
+    pub change_index: usize,      // Position in the change list while moving with g; and g,
+    pub evaluator: Option<Box<dyn Evaluator>>, // Created on first use, keeps its variables
+    pub diagnostics: Vec<Diagnostic>, // From the language server, sorted by position
+    pub swap_pending: bool,       // Edited since the swap file was last written
+    pub swap_written: bool,       // The swap file next to the file is ours to remove
 }
 
 impl Buffer {
-    pub fn from_file(mut file: File, undofile: bool) -> std::io::Result<Self> {
+    pub fn open(path: &str, undofile: bool) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .read(true)
+            .open(path)?;
+        Buffer::from_file(file, path.to_owned(), undofile)
+    }
+
+    pub fn from_file(mut file: File, path: String, undofile: bool) -> std::io::Result<Self> {
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        let mut lines = split_text(&contents);
-        lines.pop();
+        let lines = file_lines(&contents);
 
         let hist = if undofile {
-            let result = History::from_save();
-            result.unwrap_or(History::new())
+            History::from_save(&path).unwrap_or(History::new())
         } else {
             History::new()
         };
 
         Ok(Self {
+            disk_contents: lines.clone(),
             contents: lines,
             file,
+            path,
+            modified: false,
             clip: vec![Vec::new()],
             clip_lines: false,
-            scroll: (1, 1),
-            cursor: (1, 1),
-            selection_start: (1, 1),
-            cursor_col_goal: 1,
             mode: Mode::Normal,
             history: hist,
             show_history: false,
+            changes: Vec::new(),
+            last_position: ((1, 1), (1, 1)),
+            visual_marks: ((1, 1), (1, 1)),
+            marks: HashMap::new(),
+            change_list: Vec::new(),
+            change_index: 0,
+            evaluator: None,
+            diagnostics: Vec::new(),
+            swap_pending: false,
+            swap_written: false,
         })
     }
 
-    pub fn set_mode(&mut self, mode: Mode) {
+    pub fn add_action(&mut self, action: TextAction) {
+        self.modified = true;
+        self.swap_pending = true;
+        self.changes.push(action.clone());
+        self.history.add_node(action);
+    }
+
+    // Moves the marks and the change list along with an edit
+    pub fn shift_marks(&mut self, action: &TextAction) {
+        for pos in self.marks.values_mut().chain(&mut self.change_list) {
+            *pos = action.shift_coord(*pos);
+        }
+    }
+
+    // Adds the place of an edit to the change list. Edits to the same line count as one.
+    pub fn record_change(&mut self, action: &TextAction) {
+        let Some(pos) = action.position() else {
+            return;
+        };
+        match self.change_list.last_mut() {
+            Some(last) if last.0 == pos.0 => *last = pos,
+            _ => self.change_list.push(pos),
+        }
+        if self.change_list.len() > MAX_CHANGES {
+            self.change_list.remove(0);
+        }
+        self.change_index = self.change_list.len();
+    }
+
+    // What the file holds now, which may have been changed by another program
+    pub fn read_disk(&self) -> std::io::Result<Vec<String32>> {
+        Ok(file_lines(&std::fs::read_to_string(&self.path)?))
+    }
+
+    // Programs that replace the file instead of writing to it leave the old handle pointing
+    // at a file that is no longer there
+    pub fn reopen(&mut self) -> std::io::Result<()> {
+        self.file = std::fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(&self.path)?;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        std::path::Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+    }
+
+    pub fn is_file(&self, path: &str) -> bool {
+        match (
+            std::fs::canonicalize(&self.path),
+            std::fs::canonicalize(path),
+        ) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => self.path == path,
+        }
+    }
+
+    pub fn set_mode(&mut self, view: &mut View, mode: Mode) {
         use Mode::*;
+        if matches!(self.mode, Visual | VisualLine) && mode != self.mode {
+            self.visual_marks = if view.selection_start <= view.cursor {
+                (view.selection_start, view.cursor)
+            } else {
+                (view.cursor, view.selection_start)
+            };
+        }
         match (self.mode, mode) {
             (a, b) if a == b => return,
-            (Insert, Normal) => {
-                if self.cursor.1 > 1 {
-                    self.cursor.1 -= 1;
+            (Insert | Replace, Normal) => {
+                if view.cursor.1 > 1 {
+                    view.cursor.1 -= 1;
                 }
-                if self.cursor_col_goal > 1 {
-                    self.cursor_col_goal -= 1;
+                if view.cursor_col_goal > 1 {
+                    view.cursor_col_goal -= 1;
                 }
                 self.history.stop_record();
             }
             (Visual, Normal) => {
-                if self.cursor.1 > self.contents[self.cursor.0 - 1].len() && self.cursor.1 > 1 {
-                    self.cursor.1 -= 1;
+                if view.cursor.1 > self.contents[view.cursor.0 - 1].len() && view.cursor.1 > 1 {
+                    view.cursor.1 -= 1;
                 }
             }
-            (Insert, Visual | VisualLine) => {
+            (Insert | Replace, Visual | VisualLine) => {
                 self.history.stop_record();
-                self.selection_start = self.cursor;
+                view.selection_start = view.cursor;
             }
 
             (Normal, Visual | VisualLine) => {
-                self.selection_start = self.cursor;
+                view.selection_start = view.cursor;
             }
-            (_, Insert) => {
+            (_, Insert | Replace) => {
                 self.history.start_record();
             }
             _ => (),
@@ -118,3 +224,63 @@ impl Buffer {
         self.mode = mode;
     }
 }
+
+// Lines of a file's text. The line break ending the last line does not start another.
+fn file_lines(text: &str) -> Vec<String32> {
+    let mut lines = split_text(text);
+    lines.pop();
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(name: &str) -> Buffer {
+        let path = std::env::temp_dir().join(format!("rvim-buffer-{name}-{}", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let buf = Buffer::open(path.to_str().unwrap(), false).unwrap();
+        std::fs::remove_file(path).unwrap();
+        buf
+    }
+
+    #[test]
+    fn change_list_keeps_one_place_per_line() {
+        let mut buf = buffer("changes");
+        buf.record_change(&TextAction::InsertChar {
+            pos: (2, 1),
+            cha: 'a',
+        });
+        buf.record_change(&TextAction::InsertChar {
+            pos: (2, 2),
+            cha: 'b',
+        });
+        buf.record_change(&TextAction::RemoveChar {
+            pos: (3, 1),
+            cha: 't',
+        });
+        assert_eq!(buf.change_list, [(2, 2), (3, 1)]);
+        assert_eq!(buf.change_index, 2);
+    }
+
+    #[test]
+    fn marks_move_with_edits() {
+        let mut buf = buffer("marks");
+        buf.marks.insert('a', (2, 3));
+        buf.marks.insert('b', (1, 1));
+        buf.change_list.push((3, 2));
+        buf.shift_marks(&TextAction::InsertLines {
+            start: 2,
+            stop: 3,
+            lines: vec![Vec::new(), Vec::new()],
+        });
+        assert_eq!(buf.marks[&'a'], (4, 3));
+        assert_eq!(buf.marks[&'b'], (1, 1));
+        assert_eq!(buf.change_list, [(5, 2)]);
+        buf.shift_marks(&TextAction::InsertChar {
+            pos: (4, 1),
+            cha: 'x',
+        });
+        assert_eq!(buf.marks[&'a'], (4, 4));
+    }
+}
@@ -0,0 +1,461 @@
+use std::time::Duration;
+
+use crate::buffer::*;
+use crate::filter::*;
+use crate::grep::*;
+use crate::session::*;
+use crate::sort::*;
+use crate::terminal::*;
+use crate::view::*;
+use crate::{filter_lines, rearrange_lines, update_cursor, Process};
+
+type Range = Option<(usize, usize)>;
+
+// Runs a line typed after ':'. Returns whether the editor should quit.
+pub fn run_command(process: &mut Process, term: &Terminal, line: &str) -> Result<bool, String> {
+    let (buf, view) = process.get_active();
+    let (range, line) = parse_range(line.trim(), buf, view)?;
+    let line = line.trim();
+
+    if let Some(command) = line.strip_prefix('!') {
+        let output = match range {
+            Some((start, stop)) => filter_lines(buf, view, start, stop, command)?,
+            None => {
+                let timeout = Duration::from_secs(crate::CONFIG.read().unwrap().filter_timeout);
+                let output = run_filter(command, "", timeout)?;
+                Some(output.stdout.trim_end().to_owned() + &output.stderr)
+            }
+        };
+        process.message = output.filter(|msg| !msg.is_empty());
+        return Ok(false);
+    }
+
+    let (name, args) = match line.split_once(' ') {
+        Some((name, args)) => (name, args.trim()),
+        None => (line, ""),
+    };
+    let (name, force) = match name.strip_suffix('!') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    match name {
+        "" => {
+            // A lone range moves to its last line
+            if let Some((_, stop)) = range {
+                let (buf, view) = process.get_active();
+                view.cursor.0 = stop;
+                update_cursor(buf, view);
+            }
+            Ok(false)
+        }
+        "w" | "write" => {
+            process.write_active(force)?;
+            Ok(false)
+        }
+        "q" | "quit" => Ok(!process.close_view(process.active_view)),
+        "wq" | "x" => {
+            process.write_active(force)?;
+            Ok(!process.close_view(process.active_view))
+        }
+        "sp" | "split" => {
+            process.split_view(Direction::Horizontal, term)?;
+            open_arg(process, args)
+        }
+        "vs" | "vsplit" => {
+            process.split_view(Direction::Vertical, term)?;
+            open_arg(process, args)
+        }
+        "clo" | "close" => {
+            if process.close_view(process.active_view) {
+                Ok(false)
+            } else {
+                Err("Cannot close last window".to_owned())
+            }
+        }
+        "on" | "only" => {
+            process.only_view();
+            Ok(false)
+        }
+        "e" | "edit" => {
+            if args.is_empty() {
+                return Err("No file name".to_owned());
+            }
+            open_arg(process, args)
+        }
+        "ls" | "buffers" | "files" => {
+            process.message = Some(list_buffers(process));
+            Ok(false)
+        }
+        "bn" | "bnext" => {
+            let next = (process.views[process.active_view].buffer + 1) % process.buffers.len();
+            process.switch_buffer(next);
+            Ok(false)
+        }
+        "bp" | "bprevious" | "bN" | "bNext" => {
+            let len = process.buffers.len();
+            let prev = (process.views[process.active_view].buffer + len - 1) % len;
+            process.switch_buffer(prev);
+            Ok(false)
+        }
+        "b" | "buffer" => {
+            let index = find_buffer(process, args)?;
+            process.switch_buffer(index);
+            Ok(false)
+        }
+        "bd" | "bdelete" => {
+            let index = if args.is_empty() {
+                process.views[process.active_view].buffer
+            } else {
+                find_buffer(process, args)?
+            };
+            process.delete_buffer(index, force)?;
+            Ok(false)
+        }
+        "gr" | "grep" => {
+            let args = split_args(args);
+            let Some((pattern, paths)) = args.split_first() else {
+                return Err("Usage: grep pattern [paths]".to_owned());
+            };
+            process.quickfix = Quickfix {
+                entries: grep(pattern, paths)?,
+                open: true,
+                focused: true,
+                ..Quickfix::default()
+            };
+            Ok(false)
+        }
+        "rename" => {
+            if args.is_empty() {
+                return Err("Usage: rename new_name".to_owned());
+            }
+            let view = &process.views[process.active_view];
+            process
+                .lsp
+                .rename(&process.buffers[view.buffer], view.cursor, args)?;
+            Ok(false)
+        }
+        "format" => {
+            let view = &process.views[process.active_view];
+            process.lsp.format(&process.buffers[view.buffer])?;
+            Ok(false)
+        }
+        "ea" | "earlier" | "lat" | "later" => {
+            let sign = if name.starts_with('e') { -1 } else { 1 };
+            let amount = if args.is_empty() { "1" } else { args };
+            let (number, unit) = amount.split_at(
+                amount
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(amount.len()),
+            );
+            let Ok(number) = number.parse::<i64>() else {
+                return Err(format!("Invalid argument: {args}"));
+            };
+            let seconds = match unit {
+                "" => {
+                    process.time_travel(|history| {
+                        history.step_chronological((sign * number) as isize)
+                    });
+                    return Ok(false);
+                }
+                "f" => {
+                    process.time_travel(|history| history.step_saves((sign * number) as isize));
+                    return Ok(false);
+                }
+                "s" => Some(number),
+                "m" => number.checked_mul(60),
+                "h" => number.checked_mul(60 * 60),
+                "d" => number.checked_mul(60 * 60 * 24),
+                _ => None,
+            };
+            let Some(seconds) = seconds else {
+                return Err(format!("Invalid argument: {args}"));
+            };
+            process.time_travel(|history| history.step_time(sign * seconds));
+            Ok(false)
+        }
+        "checkpoint" => {
+            let history = &mut process.get_active_buffer().history;
+            if args.is_empty() {
+                let names = history.list_checkpoints();
+                process.message = Some(if names.is_empty() {
+                    "No checkpoints".to_owned()
+                } else {
+                    names.join("\n")
+                });
+            } else {
+                history.add_checkpoint(args);
+            }
+            Ok(false)
+        }
+        "u" | "undo" => {
+            if args.is_empty() {
+                process.time_travel(|history| history.undo().into_iter().collect());
+                return Ok(false);
+            }
+            let history = &process.get_active_buffer().history;
+            let Some(location) = history.find_checkpoint(args) else {
+                return Err(format!("No checkpoint named {args}"));
+            };
+            process.time_travel(|history| history.goto_location(location));
+            Ok(false)
+        }
+        "undotree" => {
+            let usage = || "Usage: undotree export file.dot|file.json".to_owned();
+            let Some(("export", path)) = args.split_once(' ') else {
+                return Err(usage());
+            };
+            let path = path.trim();
+            let history = &process.get_active_buffer().history;
+            let contents = match std::path::Path::new(path).extension() {
+                Some(ext) if ext == "dot" || ext == "gv" => history.export_dot(),
+                Some(ext) if ext == "json" => history.export_json(),
+                _ => return Err(usage()),
+            };
+            std::fs::write(path, contents).map_err(|err| format!("{path}: {err}"))?;
+            process.message = Some(format!("Exported undo tree to {path}"));
+            Ok(false)
+        }
+        "mks" | "mksession" => {
+            let path = if args.is_empty() {
+                "session.json"
+            } else {
+                args
+            };
+            if !force && std::path::Path::new(path).exists() {
+                return Err(format!("{path} exists (add ! to override)"));
+            }
+            write_session(path, &process.session())?;
+            process.message = Some(format!("Wrote session to {path}"));
+            Ok(false)
+        }
+        "sor" | "sort" => {
+            let options = parse_sort_args(args, force)?;
+            let (buf, view) = process.get_active();
+            let (start, stop) = range.unwrap_or((1, buf.contents.len()));
+            rearrange_lines(buf, view, start, stop, "Sort", |lines| {
+                sort_lines(lines, &options)
+            });
+            Ok(false)
+        }
+        "align" => {
+            if args.is_empty() {
+                return Err("Usage: align delimiter".to_owned());
+            }
+            let (buf, view) = process.get_active();
+            let (start, stop) = range.unwrap_or((1, buf.contents.len()));
+            rearrange_lines(buf, view, start, stop, "Align", |lines| {
+                align_lines(lines, args)
+            });
+            Ok(false)
+        }
+        "cn" | "cnext" => {
+            process.goto_quickfix(process.quickfix.current + 1)?;
+            Ok(false)
+        }
+        "cp" | "cprevious" | "cN" | "cNext" => {
+            if process.quickfix.current == 0 {
+                return Err("No more items".to_owned());
+            }
+            process.goto_quickfix(process.quickfix.current - 1)?;
+            Ok(false)
+        }
+        "cc" => {
+            let index = match args.parse::<usize>() {
+                Ok(number) if number > 0 => number - 1,
+                _ => process.quickfix.current,
+            };
+            process.goto_quickfix(index)?;
+            Ok(false)
+        }
+        "cope" | "copen" => {
+            process.quickfix.open = true;
+            process.quickfix.focused = true;
+            Ok(false)
+        }
+        "ccl" | "cclose" => {
+            process.quickfix.open = false;
+            process.quickfix.focused = false;
+            Ok(false)
+        }
+        _ => Err(format!("Not an editor command: {line}")),
+    }
+}
+
+fn open_arg(process: &mut Process, path: &str) -> Result<bool, String> {
+    if !path.is_empty() {
+        process.open_buffer(path)?;
+    }
+    Ok(false)
+}
+
+// Accepts a buffer number or a unique part of its path
+fn find_buffer(process: &Process, arg: &str) -> Result<usize, String> {
+    if let Ok(number) = arg.parse::<usize>() {
+        if number >= 1 && number <= process.buffers.len() {
+            return Ok(number - 1);
+        }
+        return Err(format!("Buffer {number} does not exist"));
+    }
+    let matches: Vec<usize> = (0..process.buffers.len())
+        .filter(|i| process.buffers[*i].path.contains(arg))
+        .collect();
+    match matches[..] {
+        [index] => Ok(index),
+        [] => Err(format!("No matching buffer for {arg}")),
+        _ => Err(format!("More than one match for {arg}")),
+    }
+}
+
+fn list_buffers(process: &Process) -> String {
+    let active = process.views[process.active_view].buffer;
+    process
+        .buffers
+        .iter()
+        .enumerate()
+        .map(|(i, buf)| {
+            let current = if i == active { '%' } else { ' ' };
+            let shown = if process.views.iter().any(|view| view.buffer == i) {
+                'a'
+            } else {
+                'h'
+            };
+            let modified = if buf.modified { '+' } else { ' ' };
+            format!("{:>3} {current}{shown} {modified} \"{}\"", i + 1, buf.path)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Splits command arguments on whitespace, keeping quoted parts together
+pub fn split_args(args: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut in_arg = false;
+    let mut chars = args.chars();
+    while let Some(cha) = chars.next() {
+        match (cha, quote) {
+            ('\\', _) => {
+                if let Some(next) = chars.next() {
+                    if next != '"' && next != '\'' && next != ' ' {
+                        current.push('\\');
+                    }
+                    current.push(next);
+                }
+                in_arg = true;
+            }
+            ('"' | '\'', None) => {
+                quote = Some(cha);
+                in_arg = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if in_arg {
+                    result.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (c, _) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        result.push(current);
+    }
+    result
+}
+
+fn parse_number(text: &str) -> (Option<usize>, &str) {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    (text[..end].parse().ok(), &text[end..])
+}
+
+// Parses a line address like "12", ".", "$", "'<", "'a" or ".+3"
+fn parse_address<'a>(text: &'a str, buf: &Buffer, view: &View) -> (Option<isize>, &'a str) {
+    let (mut line, mut rest) = if let Some(rest) = text.strip_prefix('.') {
+        (Some(view.cursor.0 as isize), rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (Some(buf.contents.len() as isize), rest)
+    } else if let Some(rest) = text.strip_prefix("'<") {
+        (Some(buf.visual_marks.0 .0 as isize), rest)
+    } else if let Some(rest) = text.strip_prefix("'>") {
+        (Some(buf.visual_marks.1 .0 as isize), rest)
+    } else if let Some(pos) = text
+        .strip_prefix('\'')
+        .and_then(|rest| rest.chars().next())
+        .and_then(|name| buf.marks.get(&name))
+    {
+        (Some(pos.0 as isize), &text[2..])
+    } else {
+        let (number, rest) = parse_number(text);
+        (number.map(|n| n as isize), rest)
+    };
+
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let (number, after) = parse_number(&rest[1..]);
+        let offset = number.unwrap_or(1) as isize;
+        let base = line.unwrap_or(view.cursor.0 as isize);
+        line = Some(if sign == '+' {
+            base + offset
+        } else {
+            base - offset
+        });
+        rest = after;
+    }
+    (line, rest)
+}
+
+// Splits a leading line range like "%", "5,10", ".,.+3" or "'<,'>" off a command
+fn parse_range<'a>(line: &'a str, buf: &Buffer, view: &View) -> Result<(Range, &'a str), String> {
+    let (start, stop, rest) = if let Some(rest) = line.strip_prefix('%') {
+        (Some(1), Some(buf.contents.len() as isize), rest)
+    } else {
+        let (start, rest) = parse_address(line, buf, view);
+        if let Some(rest) = rest.strip_prefix(',') {
+            let start = start.or(Some(view.cursor.0 as isize));
+            let (stop, rest) = parse_address(rest, buf, view);
+            (start, stop.or(Some(view.cursor.0 as isize)), rest)
+        } else {
+            (start, start, rest)
+        }
+    };
+    let (Some(start), Some(stop)) = (start, stop) else {
+        return Ok((None, rest));
+    };
+    let (start, stop) = (start.min(stop), start.max(stop));
+    if start < 1 || stop > buf.contents.len() as isize {
+        return Err("Invalid range".to_owned());
+    }
+    Ok((Some((start as usize, stop as usize)), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_args_on_whitespace() {
+        assert_eq!(split_args("  foo   bar\tbaz "), ["foo", "bar", "baz"]);
+        assert!(split_args("   ").is_empty());
+    }
+
+    #[test]
+    fn split_args_keeps_quoted_parts_together() {
+        assert_eq!(split_args("\"a b\" 'c d'"), ["a b", "c d"]);
+        assert_eq!(split_args("x\"y z\"w"), ["xy zw"]);
+        assert_eq!(split_args("'a \"b\" c'"), ["a \"b\" c"]);
+        assert_eq!(split_args("\"\" b"), ["", "b"]);
+    }
+
+    #[test]
+    fn split_args_escapes() {
+        assert_eq!(split_args("a\\ b c"), ["a b", "c"]);
+        assert_eq!(split_args("\\\"q\\\""), ["\"q\""]);
+        // Backslashes before other characters are kept, as regexes need them
+        assert_eq!(split_args("\\d+ \\w"), ["\\d+", "\\w"]);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::common::*;
+
+// Splits a comment string like "// %s" or "/* %s */" into the parts before and after the text
+fn comment_parts(commentstring: &str) -> (String32, String32) {
+    let (before, after) = commentstring
+        .split_once("%s")
+        .unwrap_or((commentstring, ""));
+    (before.chars().collect(), after.chars().collect())
+}
+
+fn trim(text: &[char]) -> &[char] {
+    let start = text.iter().take_while(|c| c.is_whitespace()).count();
+    let end = text.len()
+        - text[start..]
+            .iter()
+            .rev()
+            .take_while(|c| c.is_whitespace())
+            .count();
+    &text[start..end]
+}
+
+fn is_blank(line: &[char]) -> bool {
+    line.iter().all(|c| c.is_whitespace())
+}
+
+// The line without its comment markers, if it is commented
+fn uncomment(line: &[char], before: &[char], after: &[char]) -> Option<String32> {
+    let indent = line.iter().take_while(|c| c.is_whitespace()).count();
+    let rest = line[indent..].strip_prefix(trim(before))?;
+    let rest = rest.strip_suffix(trim(after))?;
+    // The space between the marker and the text was added with it
+    let rest = rest.strip_prefix(&[' ']).unwrap_or(rest);
+    let rest = if after.first() == Some(&' ') {
+        rest.strip_suffix(&[' ']).unwrap_or(rest)
+    } else {
+        rest
+    };
+    let mut uncommented = line[..indent].to_vec();
+    uncommented.extend_from_slice(rest);
+    Some(uncommented)
+}
+
+// Comments out the lines, or uncomments them if they all are commented already. Blank lines
+// are left alone, and the markers line up at the smallest indentation of the other lines.
+pub fn toggle_comments(lines: &[String32], commentstring: &str) -> Vec<String32> {
+    let (before, after) = comment_parts(commentstring);
+    let uncommented: Option<Vec<String32>> = lines
+        .iter()
+        .map(|line| {
+            if is_blank(line) {
+                Some(line.clone())
+            } else {
+                uncomment(line, &before, &after)
+            }
+        })
+        .collect();
+    if let Some(uncommented) = uncommented.filter(|_| lines.iter().any(|l| !is_blank(l))) {
+        return uncommented;
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|line| !is_blank(line))
+        .map(|line| line.iter().take_while(|c| c.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|line| {
+            if is_blank(line) {
+                return line.clone();
+            }
+            let mut commented = line[..indent].to_vec();
+            commented.extend_from_slice(&before);
+            commented.extend_from_slice(&line[indent..]);
+            commented.extend_from_slice(&after);
+            commented
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toggle(lines: &[&str], commentstring: &str) -> Vec<String> {
+        let lines: Vec<String32> = lines.iter().map(|line| line.chars().collect()).collect();
+        toggle_comments(&lines, commentstring)
+            .iter()
+            .map(|line| line.iter().collect())
+            .collect()
+    }
+
+    #[test]
+    fn comments_line_up_at_the_smallest_indent() {
+        let lines = ["  a", "", "    b"];
+        let commented = toggle(&lines, "// %s");
+        assert_eq!(commented, ["  // a", "", "  //   b"]);
+        let commented: Vec<&str> = commented.iter().map(String::as_str).collect();
+        assert_eq!(toggle(&commented, "// %s"), lines);
+    }
+
+    #[test]
+    fn block_comments() {
+        assert_eq!(toggle(&["x = 1"], "/* %s */"), ["/* x = 1 */"]);
+        assert_eq!(toggle(&["/* x = 1 */"], "/* %s */"), ["x = 1"]);
+        assert_eq!(toggle(&["<!--x-->"], "<!-- %s -->"), ["x"]);
+    }
+
+    #[test]
+    fn uncomments_without_the_space() {
+        assert_eq!(toggle(&["//a", "  // b"], "// %s"), ["a", "  b"]);
+        assert_eq!(toggle(&["#x"], "#%s"), ["x"]);
+    }
+
+    #[test]
+    fn partly_commented_lines_are_all_commented() {
+        assert_eq!(toggle(&["// a", "b"], "// %s"), ["// // a", "// b"]);
+    }
+
+    #[test]
+    fn blank_lines_are_left_alone() {
+        assert_eq!(toggle(&["", "  "], "// %s"), ["", "  "]);
+    }
+}
@@ -31,7 +31,7 @@ pub fn preview_lines(lines: &Vec<String32>, length: usize) -> String {
     preview
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Rect {
     pub top: usize,
     pub left: usize,
@@ -52,11 +52,11 @@ impl Rect {
         self.right - self.left + 1
     }
 
-    fn contains(&self, pos: Coord) -> bool {
+    pub fn contains(&self, pos: Coord) -> bool {
         self.top <= pos.0 && pos.0 <= self.bottom && self.left <= pos.1 && pos.1 <= self.right
     }
 
-    fn to_relative(&self, pos: Coord) -> Coord {
+    pub fn to_relative(&self, pos: Coord) -> Coord {
         (pos.0 - self.top, pos.1 - self.left)
     }
 
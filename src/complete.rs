@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::buffer::*;
+use crate::common::*;
+
+pub struct Candidate {
+    pub text: String,
+    pub source: &'static str, // Shown next to the candidate in the menu
+}
+
+pub struct CompletionContext<'a> {
+    pub prefix: &'a str,
+    pub buffers: &'a [Buffer],
+    pub active: usize, // Index of the buffer being edited
+    pub cursor: Coord,
+}
+
+// Anything that can suggest ways to finish the word before the cursor
+pub trait CompletionSource {
+    fn candidates(&self, context: &CompletionContext) -> Vec<Candidate>;
+}
+
+fn is_word_char(cha: char) -> bool {
+    cha.is_alphanumeric() || cha == '_'
+}
+
+fn is_path_char(cha: char) -> bool {
+    is_word_char(cha) || matches!(cha, '/' | '.' | '-' | '~')
+}
+
+// Column where the word being completed starts. Paths count as one word.
+pub fn completion_start(line: &[char], col: usize) -> usize {
+    let before = &line[..col - 1];
+    let path_start = before
+        .iter()
+        .rposition(|c| !is_path_char(*c))
+        .map_or(0, |i| i + 1);
+    if before[path_start..].contains(&'/') {
+        return path_start + 1;
+    }
+    before
+        .iter()
+        .rposition(|c| !is_word_char(*c))
+        .map_or(0, |i| i + 1)
+        + 1
+}
+
+// Words from the buffer being edited, nearest after the cursor first, then the other buffers
+pub struct BufferWords;
+
+impl CompletionSource for BufferWords {
+    fn candidates(&self, context: &CompletionContext) -> Vec<Candidate> {
+        if context.prefix.contains('/') {
+            return Vec::new();
+        }
+        let prefix: Vec<char> = context.prefix.chars().collect();
+        let active = &context.buffers[context.active].contents;
+        let row = context.cursor.0 - 1;
+        let lines = active[row..].iter().chain(&active[..row]).chain(
+            context
+                .buffers
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != context.active)
+                .flat_map(|(_, buf)| &buf.contents),
+        );
+
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        for line in lines {
+            for word in line.split(|c| !is_word_char(*c)) {
+                if word.len() > prefix.len() && word.starts_with(&prefix) && seen.insert(word) {
+                    candidates.push(Candidate {
+                        text: word.iter().collect(),
+                        source: "buf",
+                    });
+                }
+            }
+        }
+        candidates
+    }
+}
+
+// Entries of the directory named by the path before the cursor
+pub struct FilePaths;
+
+impl CompletionSource for FilePaths {
+    fn candidates(&self, context: &CompletionContext) -> Vec<Candidate> {
+        let Some((dir, name)) = context.prefix.rsplit_once('/') else {
+            return Vec::new();
+        };
+        let dir_path = match dir.strip_prefix('~') {
+            Some(rest) => std::env::var("HOME").unwrap_or_default() + rest,
+            None if dir.is_empty() => "/".to_owned(),
+            None => dir.to_owned(),
+        };
+        let Ok(entries) = fs::read_dir(Path::new(&dir_path)) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let mut file_name = entry.file_name().into_string().ok()?;
+                if !file_name.starts_with(name) || (file_name.starts_with('.') && name.is_empty()) {
+                    return None;
+                }
+                if entry.file_type().ok()?.is_dir() {
+                    file_name.push('/');
+                }
+                Some(file_name)
+            })
+            .collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|file_name| Candidate {
+                text: format!("{dir}/{file_name}"),
+                source: "file",
+            })
+            .collect()
+    }
+}
+
+pub struct Completion {
+    pub start: Coord,   // Where the completed word starts
+    pub prefix: String, // What was typed before completing
+    pub candidates: Vec<Candidate>,
+    pub selected: Option<usize>, // None puts back the typed prefix
+}
+
+impl Completion {
+    // Moves through the candidates, passing the typed prefix between the last and the first
+    pub fn cycle(&mut self, forward: bool) {
+        let count = self.candidates.len();
+        self.selected = match (self.selected, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(count - 1),
+            (Some(i), true) if i + 1 < count => Some(i + 1),
+            (Some(i), false) if i > 0 => Some(i - 1),
+            _ => None,
+        };
+    }
+
+    pub fn current_text(&self) -> &str {
+        match self.selected {
+            Some(i) => &self.candidates[i].text,
+            None => &self.prefix,
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
@@ -29,13 +30,29 @@ pub fn config_path() -> Option<String> {
 
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub logging: bool,
     pub relative_number: bool,
     pub wrap: bool,
     pub tab_width: u8,
     pub undofile: bool,
+    pub undolevels: usize,     // Most changes kept in the undo tree
+    pub undo_max_age: u64,     // Days changes are kept, 0 for no limit
+    pub undo_max_bytes: usize, // Size of the stored changes, 0 for no limit
     pub clipboard: bool,
+    pub autoindent: bool, // New lines start with the indentation of the line before
+    pub smartindent: bool, // One level deeper after an opening bracket, back on a closing one
+    pub autopairs: bool,  // Typing a bracket or quote adds the closing one
+    pub redo_on_r: bool,  // r redoes like Ctrl-R instead of replacing a character
+    pub swapfile: bool,
+    pub swap_interval: u64, // Seconds between writes of the swap files
+    pub filters: HashMap<String, String>, // Visual mode key -> shell command
+    pub comments: HashMap<String, String>, // File extension -> comment with %s for the text
+    pub match_pairs: Vec<String>, // What % jumps between, like "(:)" or "begin:end"
+    pub filter_timeout: u64, // Seconds
+    pub evaluator: String,  // "arithmetic", "talculia" or a REPL command
+    pub language_servers: HashMap<String, String>, // File extension -> server command
 }
 
 impl Default for Config {
@@ -46,7 +63,47 @@ impl Default for Config {
             wrap: false,
             tab_width: 4,
             undofile: false,
+            undolevels: 1000,
+            undo_max_age: 0,
+            undo_max_bytes: 0,
             clipboard: false,
+            autoindent: true,
+            smartindent: true,
+            autopairs: false,
+            redo_on_r: false,
+            swapfile: true,
+            swap_interval: 4,
+            filters: HashMap::from([(
+                "e".to_owned(),
+                "python -c 'import sys; print(eval(sys.stdin.read()))'".to_owned(),
+            )]),
+            comments: HashMap::from(
+                [
+                    ("rs", "// %s"),
+                    ("c", "// %s"),
+                    ("h", "// %s"),
+                    ("cpp", "// %s"),
+                    ("js", "// %s"),
+                    ("ts", "// %s"),
+                    ("go", "// %s"),
+                    ("java", "// %s"),
+                    ("py", "# %s"),
+                    ("sh", "# %s"),
+                    ("toml", "# %s"),
+                    ("yaml", "# %s"),
+                    ("lua", "-- %s"),
+                    ("sql", "-- %s"),
+                    ("hs", "-- %s"),
+                    ("css", "/* %s */"),
+                    ("html", "<!-- %s -->"),
+                    ("md", "<!-- %s -->"),
+                ]
+                .map(|(ext, comment)| (ext.to_owned(), comment.to_owned())),
+            ),
+            match_pairs: ["(:)", "[:]", "{:}"].map(String::from).to_vec(),
+            filter_timeout: 5,
+            evaluator: "arithmetic".to_owned(),
+            language_servers: HashMap::new(),
         }
     }
 }
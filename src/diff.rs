@@ -0,0 +1,333 @@
+use std::cmp::min;
+
+use crate::common::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line {
+    Same(usize, usize), // Indices into the old and the new text
+    Removed(usize),
+    Added(usize),
+}
+
+// Lines of the middle parts that differ, using the longest common subsequence
+fn diff_lines(old: &[String32], new: &[String32]) -> Vec<Line> {
+    // Lines shared at the start and end are common for edits, and cheap to skip
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut lines: Vec<Line> = (0..prefix).map(|i| Line::Same(i, i)).collect();
+    let (n, m) = (old_mid.len(), new_mid.len());
+    if n * m > 4_000_000 {
+        // Too big to compare line by line, so show it all as replaced
+        lines.extend((0..n).map(|i| Line::Removed(prefix + i)));
+        lines.extend((0..m).map(|j| Line::Added(prefix + j)));
+    } else {
+        let mut lengths = vec![vec![0u32; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lengths[i][j] = if old_mid[i] == new_mid[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_mid[i] == new_mid[j] {
+                lines.push(Line::Same(prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if j == m || (i < n && lengths[i + 1][j] >= lengths[i][j + 1]) {
+                lines.push(Line::Removed(prefix + i));
+                i += 1;
+            } else {
+                lines.push(Line::Added(prefix + j));
+                j += 1;
+            }
+        }
+    }
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+    lines.extend((0..suffix).map(|k| Line::Same(old_end + k, new_end + k)));
+    lines
+}
+
+// A unified diff with the given number of context lines around each change
+pub fn unified_diff(old: &[String32], new: &[String32], context: usize) -> Vec<String> {
+    let lines = diff_lines(old, new);
+    let changed: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Line::Same(..)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut output = Vec::new();
+    let mut index = 0;
+    while index < changed.len() {
+        // Changes closer than twice the context share a hunk
+        let first = changed[index];
+        let mut last = first;
+        while index + 1 < changed.len() && changed[index + 1] <= last + 2 * context + 1 {
+            index += 1;
+            last = changed[index];
+        }
+        index += 1;
+        let start = first.saturating_sub(context);
+        let stop = min(last + context + 1, lines.len());
+        let hunk = &lines[start..stop];
+
+        let old_count = hunk.iter().filter(|l| !matches!(l, Line::Added(_))).count();
+        let new_count = hunk
+            .iter()
+            .filter(|l| !matches!(l, Line::Removed(_)))
+            .count();
+        let old_start = hunk.iter().find_map(|line| match line {
+            Line::Same(i, _) | Line::Removed(i) => Some(*i + 1),
+            Line::Added(_) => None,
+        });
+        let new_start = hunk.iter().find_map(|line| match line {
+            Line::Same(_, j) | Line::Added(j) => Some(*j + 1),
+            Line::Removed(_) => None,
+        });
+        output.push(format!(
+            "@@ -{},{} +{},{} @@",
+            old_start.unwrap_or(0),
+            old_count,
+            new_start.unwrap_or(0),
+            new_count
+        ));
+        for line in hunk {
+            output.push(match line {
+                Line::Same(i, _) => format!(" {}", old[*i].iter().collect::<String>()),
+                Line::Removed(i) => format!("-{}", old[*i].iter().collect::<String>()),
+                Line::Added(j) => format!("+{}", new[*j].iter().collect::<String>()),
+            });
+        }
+    }
+    output
+}
+
+// Blocks of old lines that differ, as the range they cover in the old text and the new lines
+// that replace them
+pub fn changed_blocks(old: &[String32], new: &[String32]) -> Vec<(usize, usize, Vec<String32>)> {
+    let mut blocks: Vec<(usize, usize, Vec<String32>)> = Vec::new();
+    let mut in_block = false;
+    let mut i = 0; // Old lines passed so far
+    for line in diff_lines(old, new) {
+        if let Line::Same(..) = line {
+            in_block = false;
+            i += 1;
+            continue;
+        }
+        if !in_block {
+            blocks.push((i, i, Vec::new()));
+            in_block = true;
+        }
+        let block = blocks.last_mut().unwrap();
+        match line {
+            Line::Removed(_) => {
+                i += 1;
+                block.1 = i;
+            }
+            Line::Added(j) => block.2.push(new[j].clone()),
+            Line::Same(..) => (),
+        }
+    }
+    blocks
+}
+
+// For every old line, the new line it was kept as
+fn matching_lines(old: &[String32], new: &[String32]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; old.len()];
+    for line in diff_lines(old, new) {
+        if let Line::Same(i, j) = line {
+            matches[i] = Some(j);
+        }
+    }
+    matches
+}
+
+// Combines two edited versions of a text. Blocks that were changed differently in both are
+// kept with conflict markers around them. Returns the text and the number of conflicts.
+pub fn merge3(base: &[String32], ours: &[String32], theirs: &[String32]) -> (Vec<String32>, usize) {
+    let in_ours = matching_lines(base, ours);
+    let in_theirs = matching_lines(base, theirs);
+    let mut merged = Vec::new();
+    let mut conflicts = 0;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // The next base line both versions kept
+        let stable = (i..base.len()).find_map(|m| Some((m, in_ours[m]?, in_theirs[m]?)));
+        let (m, jm, km) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+        if (m, jm, km) == (i, j, k) {
+            if m == base.len() {
+                break;
+            }
+            merged.push(base[m].clone());
+            (i, j, k) = (m + 1, jm + 1, km + 1);
+            continue;
+        }
+        let (old, a, b) = (&base[i..m], &ours[j..jm], &theirs[k..km]);
+        if a == old || a == b {
+            merged.extend_from_slice(b);
+        } else if b == old {
+            merged.extend_from_slice(a);
+        } else {
+            conflicts += 1;
+            merged.push("<<<<<<< buffer".chars().collect());
+            merged.extend_from_slice(a);
+            merged.push("=======".chars().collect());
+            merged.extend_from_slice(b);
+            merged.push(">>>>>>> disk".chars().collect());
+        }
+        (i, j, k) = (m, jm, km);
+    }
+    (merged, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String32> {
+        text.split('\n')
+            .map(|line| line.chars().collect())
+            .collect()
+    }
+
+    #[test]
+    fn diff_keeps_the_common_lines() {
+        let old = lines("a\nb\nc\nd");
+        let new = lines("a\nx\nc\nd\ne");
+        assert_eq!(
+            diff_lines(&old, &new),
+            [
+                Line::Same(0, 0),
+                Line::Removed(1),
+                Line::Added(1),
+                Line::Same(2, 2),
+                Line::Same(3, 3),
+                Line::Added(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_of_equal_and_empty_texts() {
+        let text = lines("a\nb");
+        assert_eq!(
+            diff_lines(&text, &text),
+            [Line::Same(0, 0), Line::Same(1, 1)]
+        );
+        assert_eq!(diff_lines(&[], &text), [Line::Added(0), Line::Added(1)]);
+        assert_eq!(diff_lines(&text, &[]), [Line::Removed(0), Line::Removed(1)]);
+    }
+
+    #[test]
+    fn diff_finds_moved_lines_in_the_middle() {
+        let old = lines("start\na\nb\nc\nend");
+        let new = lines("start\nb\nc\na\nend");
+        let diff = diff_lines(&old, &new);
+        let same = diff.iter().filter(|l| matches!(l, Line::Same(..))).count();
+        assert_eq!(same, 4);
+        assert_eq!(diff.len(), 6);
+    }
+
+    #[test]
+    fn unified_diff_hunks() {
+        let old = lines("1\n2\n3\n4\n5\n6\n7\n8\n9");
+        let new = lines("1\n2\nthree\n4\n5\n6\n7\n8\n9\n10");
+        assert_eq!(
+            unified_diff(&old, &new, 1),
+            [
+                "@@ -2,3 +2,3 @@",
+                " 2",
+                "-3",
+                "+three",
+                " 4",
+                "@@ -9,1 +9,2 @@",
+                " 9",
+                "+10",
+            ]
+        );
+    }
+
+    #[test]
+    fn close_changes_share_a_hunk() {
+        let old = lines("1\n2\n3\n4\n5");
+        let new = lines("one\n2\n3\n4\nfive");
+        let diff = unified_diff(&old, &new, 2);
+        assert_eq!(diff[0], "@@ -1,5 +1,5 @@");
+        assert_eq!(diff.iter().filter(|line| line.starts_with("@@")).count(), 1);
+    }
+
+    #[test]
+    fn unified_diff_of_equal_texts_is_empty() {
+        let text = lines("a\nb");
+        assert!(unified_diff(&text, &text, 3).is_empty());
+    }
+
+    #[test]
+    fn changed_blocks_for_pure_insertions() {
+        assert_eq!(
+            changed_blocks(&lines("a\nb"), &lines("a\nx\ny\nb")),
+            [(1, 1, lines("x\ny"))]
+        );
+        assert_eq!(
+            changed_blocks(&lines("a"), &lines("x\na\ny")),
+            [(0, 0, lines("x")), (1, 1, lines("y"))]
+        );
+    }
+
+    #[test]
+    fn changed_blocks_for_removals_and_replacements() {
+        assert_eq!(
+            changed_blocks(&lines("a\nb\nc"), &lines("a\nc")),
+            [(1, 2, Vec::new())]
+        );
+        assert_eq!(
+            changed_blocks(&lines("1\n2\n3\n4"), &lines("1\ntwo\n3\n4\n5")),
+            [(1, 2, lines("two")), (4, 4, lines("5"))]
+        );
+        assert!(changed_blocks(&lines("a\nb"), &lines("a\nb")).is_empty());
+    }
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, usize) {
+        let (merged, conflicts) = merge3(&lines(base), &lines(ours), &lines(theirs));
+        let text: Vec<String> = merged.iter().map(|line| line.iter().collect()).collect();
+        (text.join("\n"), conflicts)
+    }
+
+    #[test]
+    fn merge_takes_changes_from_both_sides() {
+        assert_eq!(
+            merge("a\nb\nc", "A\nb\nc", "a\nb\nC"),
+            ("A\nb\nC".to_owned(), 0)
+        );
+        assert_eq!(
+            merge("a\nb\nc", "a\nnew\nb\nc", "a\nb"),
+            ("a\nnew\nb".to_owned(), 0)
+        );
+        // The same change on both sides is not a conflict
+        assert_eq!(merge("a\nb", "a\nx", "a\nx"), ("a\nx".to_owned(), 0));
+    }
+
+    #[test]
+    fn merge_marks_conflicts() {
+        assert_eq!(
+            merge("a\nb\nc", "a\nours\nc", "a\ntheirs\nc"),
+            (
+                "a\n<<<<<<< buffer\nours\n=======\ntheirs\n>>>>>>> disk\nc".to_owned(),
+                1
+            )
+        );
+    }
+}
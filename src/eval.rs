@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::*;
+use crate::filter::*;
+
+pub enum Evaluation {
+    Value(String),   // Replaces the evaluated text
+    Binding(String), // A variable was defined, shown as a message
+}
+
+// Evaluates one line of text at a time, keeping variables between calls
+pub trait Evaluator {
+    fn evaluate(&mut self, line: &str) -> Result<Evaluation, String>;
+}
+
+// Picks the evaluator named in the config. Anything that is not a built in name is run as a
+// REPL command.
+pub fn new_evaluator() -> Result<Box<dyn Evaluator>, String> {
+    let name = CONFIG.read().unwrap().evaluator.clone();
+    match name.as_str() {
+        "arithmetic" => Ok(Box::new(Arithmetic::default())),
+        #[cfg(feature = "talculia")]
+        "talculia" => Ok(Box::new(Talculia::default())),
+        #[cfg(not(feature = "talculia"))]
+        "talculia" => Err("rvim was built without the talculia feature".to_owned()),
+        command => Ok(Box::new(Repl::new(command))),
+    }
+}
+
+// Splits "name = expression" into its parts
+fn split_assignment(line: &str) -> Option<(&str, &str)> {
+    let (name, expr) = line.split_once('=')?;
+    let name = name.trim();
+    if expr.starts_with('=') || !is_identifier(name) {
+        return None;
+    }
+    Some((name, expr.trim()))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+// A small calculator with the usual operators, a few functions and variables
+#[derive(Default)]
+pub struct Arithmetic {
+    variables: HashMap<String, f64>,
+}
+
+impl Evaluator for Arithmetic {
+    fn evaluate(&mut self, line: &str) -> Result<Evaluation, String> {
+        if let Some((name, expr)) = split_assignment(line) {
+            let value = self.parse(expr)?;
+            self.variables.insert(name.to_owned(), value);
+            return Ok(Evaluation::Binding(format!(
+                "{name} = {}",
+                format_number(value)
+            )));
+        }
+        let value = self.parse(line)?;
+        self.variables.insert("ans".to_owned(), value);
+        Ok(Evaluation::Value(format_number(value)))
+    }
+}
+
+impl Arithmetic {
+    fn parse(&self, expr: &str) -> Result<f64, String> {
+        let mut parser = Parser {
+            chars: expr.chars().collect(),
+            pos: 0,
+            variables: &self.variables,
+        };
+        let value = parser.sum()?;
+        parser.skip_spaces();
+        if let Some(c) = parser.peek() {
+            return Err(format!("Unexpected '{c}' at column {}", parser.pos + 1));
+        }
+        Ok(value)
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    variables: &'a HashMap<String, f64>,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    // Consumes the next non-space character if it is one of the given ones
+    fn eat(&mut self, options: &str) -> Option<char> {
+        self.skip_spaces();
+        let c = self.peek().filter(|c| options.contains(*c))?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        while let Some(op) = self.eat("+-") {
+            let rhs = self.product()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.eat("*/%") {
+            let rhs = self.unary()?;
+            value = match op {
+                '*' => value * rhs,
+                '/' if rhs == 0.0 => return Err("Division by zero".to_owned()),
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        match self.eat("+-") {
+            Some('-') => Ok(-self.unary()?),
+            Some(_) => self.unary(),
+            None => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.eat("^").is_some() {
+            // Right associative, and binds tighter than a unary minus on the left
+            let exponent = self.unary()?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        self.skip_spaces();
+        let start = self.pos;
+        match self.peek() {
+            None => Err("Unexpected end of expression".to_owned()),
+            Some('(') => {
+                self.pos += 1;
+                let value = self.sum()?;
+                match self.eat(")") {
+                    Some(_) => Ok(value),
+                    None => Err(format!("Missing ')' for '(' at column {}", start + 1)),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                // Exponent, as in 1.5e3
+                if self.peek() == Some('e')
+                    && self
+                        .chars
+                        .get(self.pos + 1)
+                        .is_some_and(|c| c.is_ascii_digit() || *c == '-')
+                {
+                    self.pos += 2;
+                    while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        self.pos += 1;
+                    }
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                text.parse().map_err(|_| format!("Invalid number '{text}'"))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                self.skip_spaces();
+                if self.peek() == Some('(') {
+                    let arg = self.atom()?;
+                    return call_function(&name, arg);
+                }
+                match name.as_str() {
+                    "pi" => Ok(std::f64::consts::PI),
+                    "e" => Ok(std::f64::consts::E),
+                    _ => self
+                        .variables
+                        .get(&name)
+                        .copied()
+                        .ok_or_else(|| format!("Unknown variable '{name}'")),
+                }
+            }
+            Some(c) => Err(format!("Unexpected '{c}' at column {}", start + 1)),
+        }
+    }
+}
+
+fn call_function(name: &str, arg: f64) -> Result<f64, String> {
+    Ok(match name {
+        "sqrt" => arg.sqrt(),
+        "abs" => arg.abs(),
+        "sin" => arg.sin(),
+        "cos" => arg.cos(),
+        "tan" => arg.tan(),
+        "ln" => arg.ln(),
+        "log" => arg.log10(),
+        "exp" => arg.exp(),
+        "floor" => arg.floor(),
+        "ceil" => arg.ceil(),
+        "round" => arg.round(),
+        _ => return Err(format!("Unknown function '{name}'")),
+    })
+}
+
+// Earlier definitions are substituted into the text before it is handed to talculia
+#[cfg(any(feature = "talculia", test))]
+fn substitute_variables(expr: &str, variables: &HashMap<String, String>) -> String {
+    let mut text = expr.to_owned();
+    for (name, value) in variables {
+        let pattern = regex::Regex::new(&format!(r"\b{}\b", regex::escape(name))).unwrap();
+        text = pattern
+            .replace_all(&text, format!("({value})").as_str())
+            .into_owned();
+    }
+    text
+}
+
+#[cfg(feature = "talculia")]
+#[derive(Default)]
+pub struct Talculia {
+    context: talculia::Context,
+    variables: HashMap<String, String>,
+}
+
+#[cfg(feature = "talculia")]
+impl Talculia {
+    fn calculate(&self, expr: &str) -> Result<String, String> {
+        let text = substitute_variables(expr, &self.variables);
+        let parsed = talculia::parse(&talculia::preparse(text))
+            .map_err(|err| format!("Could not parse {expr}: {err:?}"))?;
+        Ok(format!("{}", parsed.evaluate(&self.context)))
+    }
+}
+
+#[cfg(feature = "talculia")]
+impl Evaluator for Talculia {
+    fn evaluate(&mut self, line: &str) -> Result<Evaluation, String> {
+        if let Some((name, expr)) = split_assignment(line) {
+            let value = self.calculate(expr)?;
+            self.variables.insert(name.to_owned(), value.clone());
+            return Ok(Evaluation::Binding(format!("{name} = {value}")));
+        }
+        self.calculate(line).map(Evaluation::Value)
+    }
+}
+
+// Runs a REPL command such as "bc -l". The command has no state of its own between runs, so
+// earlier definitions are replayed before each new line.
+pub struct Repl {
+    command: String,
+    definitions: Vec<String>,
+}
+
+impl Repl {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_owned(),
+            definitions: Vec::new(),
+        }
+    }
+
+    fn run(&self, line: &str) -> Result<String, String> {
+        let mut input = self.definitions.join("\n");
+        input.push('\n');
+        input.push_str(line);
+        input.push('\n');
+        let timeout = Duration::from_secs(CONFIG.read().unwrap().filter_timeout);
+        let output = run_filter(&self.command, &input, timeout)?;
+        if !output.success || !output.stderr.is_empty() {
+            return Err(if output.stderr.is_empty() {
+                format!("{}: command failed", self.command)
+            } else {
+                output.stderr
+            });
+        }
+        Ok(output
+            .stdout
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("")
+            .trim()
+            .to_owned())
+    }
+}
+
+impl Evaluator for Repl {
+    fn evaluate(&mut self, line: &str) -> Result<Evaluation, String> {
+        if split_assignment(line).is_some() {
+            self.run(line)?;
+            self.definitions.push(line.to_owned());
+            return Ok(Evaluation::Binding(line.trim().to_owned()));
+        }
+        self.run(line).map(Evaluation::Value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(eval: &mut Arithmetic, line: &str) -> String {
+        match eval.evaluate(line) {
+            Ok(Evaluation::Value(value)) => value,
+            Ok(Evaluation::Binding(binding)) => panic!("Expected a value, got {binding}"),
+            Err(err) => panic!("{line}: {err}"),
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match Arithmetic::default().evaluate(line) {
+            Err(err) => err,
+            Ok(_) => panic!("Expected {line} to fail"),
+        }
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        let mut eval = Arithmetic::default();
+        assert_eq!(value(&mut eval, "1 + 2 * 3"), "7");
+        assert_eq!(value(&mut eval, "(1 + 2) * 3"), "9");
+        assert_eq!(value(&mut eval, "10 - 4 - 3"), "3");
+        assert_eq!(value(&mut eval, "7 % 4"), "3");
+        assert_eq!(value(&mut eval, "2 ^ 3 ^ 2"), "512");
+        assert_eq!(value(&mut eval, "-2 ^ 2"), "-4");
+        assert_eq!(value(&mut eval, "2 ^ -1"), "0.5");
+        assert_eq!(value(&mut eval, "--3"), "3");
+    }
+
+    #[test]
+    fn numbers_functions_and_constants() {
+        let mut eval = Arithmetic::default();
+        assert_eq!(value(&mut eval, "1.5e3"), "1500");
+        assert_eq!(value(&mut eval, "2e-1"), "0.2");
+        assert_eq!(value(&mut eval, "sqrt(16) + abs(-2)"), "6");
+        assert_eq!(value(&mut eval, "floor(pi)"), "3");
+        assert_eq!(value(&mut eval, "round(e)"), "3");
+    }
+
+    #[test]
+    fn variables_and_ans() {
+        let mut eval = Arithmetic::default();
+        match eval.evaluate("x = 2 * 3") {
+            Ok(Evaluation::Binding(binding)) => assert_eq!(binding, "x = 6"),
+            _ => panic!("Expected a binding"),
+        }
+        assert_eq!(value(&mut eval, "x + 1"), "7");
+        assert_eq!(value(&mut eval, "ans * 2"), "14");
+        // A comparison is not an assignment
+        assert!(split_assignment("x == 1").is_none());
+        assert!(split_assignment("2x = 1").is_none());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("1 / 0"), "Division by zero");
+        assert_eq!(error("(1 + 2"), "Missing ')' for '(' at column 1");
+        assert_eq!(error("1 + 2)"), "Unexpected ')' at column 6");
+        assert_eq!(error("1 +"), "Unexpected end of expression");
+        assert_eq!(error("y"), "Unknown variable 'y'");
+        assert_eq!(error("foo(1)"), "Unknown function 'foo'");
+        assert_eq!(error("1.2.3"), "Invalid number '1.2.3'");
+    }
+
+    #[test]
+    fn substitute_whole_words_in_parentheses() {
+        let variables = HashMap::from([
+            ("x".to_owned(), "1 + 2".to_owned()),
+            ("y2".to_owned(), "4".to_owned()),
+        ]);
+        assert_eq!(
+            substitute_variables("x * x", &variables),
+            "(1 + 2) * (1 + 2)"
+        );
+        assert_eq!(substitute_variables("xy + y2", &variables), "xy + (4)");
+        assert_eq!(substitute_variables("sin(z)", &variables), "sin(z)");
+    }
+
+    #[cfg(not(feature = "talculia"))]
+    #[test]
+    fn talculia_needs_the_feature() {
+        let old = std::mem::replace(
+            &mut CONFIG.write().unwrap().evaluator,
+            "talculia".to_owned(),
+        );
+        let result = new_evaluator();
+        CONFIG.write().unwrap().evaluator = old;
+        assert_eq!(
+            result.err().unwrap(),
+            "rvim was built without the talculia feature"
+        );
+    }
+}
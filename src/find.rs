@@ -0,0 +1,191 @@
+use crate::common::*;
+use crate::terminal::*;
+
+// An f, F, t or T motion, kept so ; and , can repeat it
+#[derive(Clone, Copy)]
+pub struct CharFind {
+    pub cha: char,
+    pub forward: bool,
+    pub till: bool, // Stops next to the character, like t and T
+}
+
+impl CharFind {
+    pub fn new(key: char, cha: char) -> Self {
+        CharFind {
+            cha,
+            forward: key.is_lowercase(),
+            till: key.eq_ignore_ascii_case(&'t'),
+        }
+    }
+
+    pub fn reversed(self) -> Self {
+        CharFind {
+            forward: !self.forward,
+            ..self
+        }
+    }
+
+    // The column the motion moves to from the given one, finding the character count times.
+    // A repeated t or T skips the character right next to the cursor, or it would not move.
+    pub fn target(&self, line: &[char], col: usize, count: usize, repeat: bool) -> Option<usize> {
+        let skip = if self.till && repeat { 2 } else { 1 };
+        let found = if self.forward {
+            (col - 1 + skip..line.len())
+                .filter(|i| line[*i] == self.cha)
+                .nth(count - 1)?
+        } else {
+            (0..(col - 1).checked_sub(skip - 1)?)
+                .rev()
+                .filter(|i| line[*i] == self.cha)
+                .nth(count - 1)?
+        };
+        Some(match (self.till, self.forward) {
+            (true, true) => found,
+            (true, false) => found + 2,
+            _ => found + 1,
+        })
+    }
+}
+
+const LABEL_LETTERS: &str = "asdfghjklqwertyuiopzxcvbnm";
+
+// Every visible occurrence of a character, each with a two-letter label to jump to it by
+pub struct LabelJump {
+    pub targets: Vec<(Coord, [char; 2])>,
+    pub typed: Option<char>,
+}
+
+impl LabelJump {
+    pub fn new(positions: Vec<Coord>) -> Self {
+        let letters: Vec<char> = LABEL_LETTERS.chars().collect();
+        let n = letters.len();
+        let targets = positions
+            .into_iter()
+            .take(n * n)
+            .enumerate()
+            .map(|(i, pos)| (pos, [letters[i / n], letters[i % n]]))
+            .collect();
+        LabelJump {
+            targets,
+            typed: None,
+        }
+    }
+
+    // Takes a typed letter. Returns whether more are expected, and the target once both are in.
+    pub fn type_letter(&mut self, letter: char) -> (bool, Option<Coord>) {
+        match self.typed {
+            None if self.targets.iter().any(|(_, label)| label[0] == letter) => {
+                self.typed = Some(letter);
+                (true, None)
+            }
+            None => (false, None),
+            Some(first) => {
+                let target = self
+                    .targets
+                    .iter()
+                    .find(|(_, label)| *label == [first, letter])
+                    .map(|(pos, _)| *pos);
+                (false, target)
+            }
+        }
+    }
+}
+
+// Draws the labels over the text of the view, from its top left corner and scroll. After the
+// first letter only the labels starting with it are left.
+pub fn draw_labels(labels: &LabelJump, scroll: Coord, surf: &impl Surface) {
+    surf.set_color(Color::Black, Color::Yellow);
+    for (pos, label) in &labels.targets {
+        if labels.typed.is_some_and(|first| first != label[0]) {
+            continue;
+        }
+        surf.goto(pos.0 - scroll.0 + 1, pos.1 + 7 - scroll.1);
+        let shown = if labels.typed.is_some() {
+            label[1].to_string()
+        } else {
+            label.iter().collect()
+        };
+        // The second letter is cut off at the edge of the view
+        let room = surf.cols() + scroll.1 - pos.1 - 6;
+        print!("{}", shown.chars().take(room).collect::<String>());
+    }
+    surf.reset_colors();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(key: char, line: &str, col: usize, count: usize, repeat: bool) -> Option<usize> {
+        let line: String32 = line.chars().collect();
+        CharFind::new(key, ',').target(&line, col, count, repeat)
+    }
+
+    #[test]
+    fn find_forwards_and_backwards() {
+        let line = "a,b,c,d";
+        assert_eq!(target('f', line, 1, 1, false), Some(2));
+        assert_eq!(target('f', line, 2, 1, false), Some(4));
+        assert_eq!(target('f', line, 1, 3, false), Some(6));
+        assert_eq!(target('f', line, 1, 4, false), None);
+        assert_eq!(target('F', line, 7, 1, false), Some(6));
+        assert_eq!(target('F', line, 7, 2, false), Some(4));
+        assert_eq!(target('F', line, 1, 1, false), None);
+    }
+
+    #[test]
+    fn till_stops_next_to_the_character() {
+        let line = "a,b,c,d";
+        assert_eq!(target('t', line, 1, 1, false), Some(1));
+        assert_eq!(target('t', line, 1, 2, false), Some(3));
+        assert_eq!(target('T', line, 7, 1, false), Some(7));
+        assert_eq!(target('T', line, 6, 1, false), Some(5));
+    }
+
+    #[test]
+    fn repeated_till_skips_the_character_next_to_the_cursor() {
+        let line = "a,b,c,d";
+        // Without the skip these would stay where they are
+        assert_eq!(target('t', line, 1, 1, true), Some(3));
+        assert_eq!(target('t', line, 3, 1, true), Some(5));
+        assert_eq!(target('T', line, 7, 1, true), Some(5));
+        assert_eq!(target('T', line, 5, 1, true), Some(3));
+        assert_eq!(target('T', line, 1, 1, true), None);
+        // Repeating f is not affected
+        assert_eq!(target('f', line, 2, 1, true), Some(4));
+    }
+
+    #[test]
+    fn reversed_keeps_till() {
+        let find = CharFind::new('t', ',').reversed();
+        assert!(!find.forward && find.till);
+        let find = CharFind::new('F', ',').reversed();
+        assert!(find.forward && !find.till);
+    }
+
+    #[test]
+    fn labels_take_two_letters() {
+        let mut labels = LabelJump::new(vec![(1, 1), (1, 5), (2, 3)]);
+        assert_eq!(labels.targets[1].1, ['a', 's']);
+        assert_eq!(labels.type_letter('a'), (true, None));
+        assert_eq!(labels.type_letter('s'), (false, Some((1, 5))));
+    }
+
+    #[test]
+    fn unknown_labels_end_the_jump() {
+        let mut labels = LabelJump::new(vec![(1, 1), (1, 5)]);
+        assert_eq!(labels.type_letter('z'), (false, None));
+        let mut labels = LabelJump::new(vec![(1, 1), (1, 5)]);
+        labels.type_letter('a');
+        assert_eq!(labels.type_letter('m'), (false, None));
+    }
+
+    #[test]
+    fn labels_move_on_to_the_next_first_letter() {
+        let positions: Vec<Coord> = (1..=30).map(|row| (row, 1)).collect();
+        let mut labels = LabelJump::new(positions);
+        assert_eq!(labels.targets[26].1, ['s', 'a']);
+        labels.type_letter('s');
+        assert_eq!(labels.type_letter('d'), (false, Some((29, 1))));
+    }
+}
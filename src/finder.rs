@@ -0,0 +1,349 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::terminal::*;
+use crate::walk::*;
+use crate::{draw_popup, Signal};
+
+pub struct Finder {
+    pub query: String32,
+    files: Arc<Mutex<Vec<String>>>,
+    done: Arc<AtomicBool>,
+    cancel: Arc<AtomicBool>,
+    matches: Vec<(i64, usize)>, // Score and index into files, best first
+    scanned: usize,             // Files already scored for the current query
+    pub selected: usize,
+}
+
+impl Finder {
+    // Starts walking the working directory in the background
+    pub fn new(signals: Sender<Signal>) -> Self {
+        let files = Arc::new(Mutex::new(Vec::new()));
+        let done = Arc::new(AtomicBool::new(false));
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let (thread_files, thread_done, thread_cancel) =
+            (files.clone(), done.clone(), cancel.clone());
+        thread::spawn(move || {
+            let mut found = Vec::new();
+            let mut last_flush = Instant::now();
+            walk_files(Path::new("."), &mut |path| {
+                if thread_cancel.load(Ordering::Relaxed) {
+                    return false;
+                }
+                let path = path.strip_prefix(".").unwrap_or(path);
+                found.push(path.to_string_lossy().into_owned());
+                if last_flush.elapsed() > Duration::from_millis(50) {
+                    thread_files.lock().unwrap().append(&mut found);
+                    last_flush = Instant::now();
+                    signals.send(Signal::Refresh).is_ok()
+                } else {
+                    true
+                }
+            });
+            thread_files.lock().unwrap().append(&mut found);
+            thread_done.store(true, Ordering::Relaxed);
+            let _ = signals.send(Signal::Refresh);
+        });
+
+        Self {
+            query: String32::new(),
+            files,
+            done,
+            cancel,
+            matches: Vec::new(),
+            scanned: 0,
+            selected: 0,
+        }
+    }
+
+    // Scores the files found since the last update
+    pub fn update(&mut self) {
+        let files = self.files.lock().unwrap();
+        if self.scanned == files.len() {
+            return;
+        }
+        for (i, file) in files.iter().enumerate().skip(self.scanned) {
+            if let Some(score) = fuzzy_score(&self.query, &file.chars().collect::<String32>()) {
+                self.matches.push((score, i));
+            }
+        }
+        self.scanned = files.len();
+        self.matches
+            .sort_by(|a, b| b.0.cmp(&a.0).then(files[a.1].len().cmp(&files[b.1].len())));
+        self.selected = self.selected.min(self.matches.len().saturating_sub(1));
+    }
+
+    pub fn push(&mut self, cha: char) {
+        self.query.push(cha);
+        // A longer query only matches a subset, so only the current matches are rescored
+        let files = self.files.lock().unwrap();
+        let query = &self.query;
+        self.matches = self
+            .matches
+            .iter()
+            .filter_map(|(_, i)| {
+                Some((
+                    fuzzy_score(query, &files[*i].chars().collect::<String32>())?,
+                    *i,
+                ))
+            })
+            .collect();
+        self.matches
+            .sort_by(|a, b| b.0.cmp(&a.0).then(files[a.1].len().cmp(&files[b.1].len())));
+        self.selected = 0;
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.matches.clear();
+        self.scanned = 0;
+        self.selected = 0;
+        self.update();
+    }
+
+    pub fn move_selection(&mut self, down: bool) {
+        if down {
+            if self.selected + 1 < self.matches.len() {
+                self.selected += 1;
+            }
+        } else if self.selected > 0 {
+            self.selected -= 1;
+        }
+    }
+
+    pub fn selected_path(&self) -> Option<String> {
+        let (_, index) = self.matches.get(self.selected)?;
+        Some(self.files.lock().unwrap()[*index].clone())
+    }
+}
+
+impl Drop for Finder {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+fn is_separator(cha: char) -> bool {
+    matches!(cha, '/' | '_' | '-' | '.' | ' ')
+}
+
+// Scores how well the query matches as a subsequence of the candidate. Matches that are
+// consecutive, start a word or lie in the file name count more.
+pub fn fuzzy_score(query: &[char], candidate: &[char]) -> Option<i64> {
+    if query.is_empty() {
+        return Some(0);
+    }
+    let smart_case = query.iter().any(|c| c.is_uppercase());
+    let same = |a: char, b: char| {
+        if smart_case {
+            a == b
+        } else {
+            a.to_lowercase().eq(b.to_lowercase())
+        }
+    };
+    let name_start = candidate
+        .iter()
+        .rposition(|c| *c == '/')
+        .map_or(0, |i| i + 1);
+
+    let mut best = None;
+    for start in 0..candidate.len() {
+        if !same(candidate[start], query[0]) {
+            continue;
+        }
+        let mut score = 0;
+        let mut matched = 0;
+        let mut prev: Option<usize> = None;
+        for (i, cha) in candidate.iter().enumerate().skip(start) {
+            if matched == query.len() {
+                break;
+            }
+            if !same(*cha, query[matched]) {
+                continue;
+            }
+            score += 1;
+            if prev.is_some_and(|p| p + 1 == i) {
+                score += 5;
+            }
+            if i == 0 || is_separator(candidate[i - 1]) {
+                score += 8;
+            }
+            if i >= name_start {
+                score += 2;
+            }
+            prev = Some(i);
+            matched += 1;
+        }
+        if matched < query.len() {
+            break; // Later starts cannot match either
+        }
+        best = best.max(Some(score));
+    }
+    best.map(|score| score * 16 - candidate.len() as i64)
+}
+
+fn fit(text: &str, width: usize) -> String {
+    let len = text.chars().count();
+    if len > width {
+        // Cut paths from the left, where the least interesting part is
+        let mut cut: String = text.chars().skip(len - width + 1).collect();
+        cut.insert(0, '…');
+        cut
+    } else {
+        format!("{}{}", text, " ".repeat(width - len))
+    }
+}
+
+// Reads only the lines that fit in the preview pane, so large files stay cheap to show
+fn preview(path: &str, rows: usize, width: usize) -> Vec<String> {
+    let Ok(file) = File::open(path) else {
+        return vec!["Could not read file".to_owned()];
+    };
+    let mut reader = BufReader::new(file);
+    let mut lines = Vec::new();
+    let mut bytes = Vec::new();
+    while lines.len() < rows {
+        bytes.clear();
+        match reader.read_until(b'\n', &mut bytes) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(_) => return vec!["Could not read file".to_owned()],
+        }
+        if bytes.contains(&0) {
+            return vec!["Binary file".to_owned()];
+        }
+        let line = String::from_utf8_lossy(&bytes);
+        lines.push(
+            line.trim_end_matches(['\n', '\r'])
+                .replace('\t', "    ")
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(width)
+                .collect(),
+        );
+    }
+    lines
+}
+
+pub fn draw_finder(finder: &Finder, term: &mut Terminal) {
+    // The popup needs room for its border around at least one row
+    if term.rows() < 8 || term.cols() < 10 {
+        return;
+    }
+    let rect = Rect {
+        top: 3,
+        left: term.cols() / 10 + 1,
+        bottom: term.rows() - 3,
+        right: term.cols() - term.cols() / 10,
+    };
+    let inner_height = rect.height().saturating_sub(2);
+    let inner_width = rect.width().saturating_sub(2);
+    let list_width = inner_width / 2;
+    let preview_width = (inner_width - list_width).saturating_sub(1);
+
+    let files = finder.files.lock().unwrap();
+    let status = if finder.done.load(Ordering::Relaxed) {
+        format!("{}/{}", finder.matches.len(), files.len())
+    } else {
+        format!("{}/{}…", finder.matches.len(), files.len())
+    };
+    let query: String = finder.query.iter().collect();
+    let prompt_width = list_width.saturating_sub(status.chars().count() + 1);
+    let prompt = format!("{} {}", fit(&format!("> {}", query), prompt_width), status);
+
+    // Keep the selected file in view
+    let list_rows = inner_height.saturating_sub(1).max(1);
+    let scroll = (finder.selected + 1).saturating_sub(list_rows);
+    let items: Vec<String> = finder
+        .matches
+        .iter()
+        .skip(scroll)
+        .take(list_rows)
+        .map(|(_, i)| fit(&files[*i], list_width))
+        .collect();
+    let preview_lines = match finder.matches.get(finder.selected) {
+        Some((_, i)) => preview(&files[*i], inner_height, preview_width),
+        None => Vec::new(),
+    };
+
+    let mut text = Vec::new();
+    for row in 0..inner_height {
+        let left = if row == 0 {
+            fit(&prompt, list_width)
+        } else {
+            items
+                .get(row - 1)
+                .cloned()
+                .unwrap_or_else(|| " ".repeat(list_width))
+        };
+        let right = preview_lines.get(row).map_or("", |line| line.as_str());
+        text.push(format!("{}│{}", left, right));
+    }
+    draw_popup(rect.clone(), text.join("\n"), term);
+
+    if let Some(item) = items.get(finder.selected - scroll) {
+        term.goto(rect.top + 2 + finder.selected - scroll, rect.left + 1);
+        term.set_color(Color::Black, Color::Cyan);
+        print!("{}", item);
+        term.reset_colors();
+    }
+    term.goto(
+        rect.top + 1,
+        rect.left + 3 + query.chars().count().min(prompt_width.saturating_sub(2)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(query: &str, candidate: &str) -> Option<i64> {
+        let query: String32 = query.chars().collect();
+        let candidate: String32 = candidate.chars().collect();
+        fuzzy_score(&query, &candidate)
+    }
+
+    #[test]
+    fn query_must_be_a_subsequence() {
+        assert!(score("mn", "src/main.rs").is_some());
+        assert!(score("nm", "src/main.rs").is_none());
+        assert_eq!(score("", "anything"), Some(0));
+    }
+
+    #[test]
+    fn smart_case() {
+        assert!(score("main", "src/Main.rs").is_some());
+        assert!(score("Main", "src/main.rs").is_none());
+        assert!(score("Main", "src/Main.rs").is_some());
+    }
+
+    #[test]
+    fn consecutive_and_word_start_matches_score_higher() {
+        assert!(score("main", "xmainxxx") > score("main", "xmxaxixn"));
+        assert!(score("fb", "foo_bar") > score("fb", "xfoxbar"));
+    }
+
+    #[test]
+    fn file_name_matches_score_higher() {
+        assert!(score("view", "src/view.rs") > score("view", "view/src.rs"));
+    }
+
+    #[test]
+    fn shorter_candidates_win_ties() {
+        assert!(score("main", "main.rs") > score("main", "main.rs.orig"));
+    }
+
+    #[test]
+    fn fit_pads_and_cuts_from_the_left() {
+        assert_eq!(fit("abc", 5), "abc  ");
+        assert_eq!(fit("src/main.rs", 6), "…in.rs");
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+use regex::Regex;
+
+use crate::terminal::*;
+use crate::walk::*;
+
+pub struct QuickfixEntry {
+    pub path: String,
+    pub line: usize,
+    pub col: usize,
+    pub text: String,
+}
+
+#[derive(Default)]
+pub struct Quickfix {
+    pub entries: Vec<QuickfixEntry>,
+    pub current: usize,
+    pub scroll: usize,
+    pub open: bool,
+    pub focused: bool,
+}
+
+pub const QUICKFIX_HEIGHT: usize = 10;
+
+impl Quickfix {
+    pub fn select(&mut self, index: usize) -> Option<&QuickfixEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        self.current = index;
+        if self.current < self.scroll {
+            self.scroll = self.current;
+        } else if self.current >= self.scroll + QUICKFIX_HEIGHT {
+            self.scroll = self.current + 1 - QUICKFIX_HEIGHT;
+        }
+        self.entries.get(index)
+    }
+}
+
+fn search_file(regex: &Regex, path: &Path) -> Vec<QuickfixEntry> {
+    let Ok(bytes) = fs::read(path) else {
+        return Vec::new();
+    };
+    if bytes.contains(&0) {
+        return Vec::new();
+    }
+    let contents = String::from_utf8_lossy(&bytes);
+    let name = path.strip_prefix(".").unwrap_or(path).to_string_lossy();
+    let mut entries = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if let Some(found) = regex.find(line) {
+            entries.push(QuickfixEntry {
+                path: name.to_string(),
+                line: i + 1,
+                col: line[..found.start()].chars().count() + 1,
+                text: line.trim().to_owned(),
+            });
+        }
+    }
+    entries
+}
+
+// Searches the given paths, or the working directory, for lines matching the pattern
+pub fn grep(pattern: &str, paths: &[String]) -> Result<Vec<QuickfixEntry>, String> {
+    let regex = Regex::new(pattern).map_err(|err| err.to_string())?;
+
+    let mut files = Vec::new();
+    let roots = if paths.is_empty() {
+        vec![".".to_owned()]
+    } else {
+        paths.to_vec()
+    };
+    for root in &roots {
+        let root = Path::new(root);
+        if root.is_file() {
+            files.push(root.to_owned());
+        } else if root.is_dir() {
+            walk_files(root, &mut |path| {
+                files.push(path.to_owned());
+                true
+            });
+        } else {
+            return Err(format!("{}: No such file or directory", root.display()));
+        }
+    }
+
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let chunk_size = files.len().div_ceil(threads).max(1);
+    let results: Vec<Vec<QuickfixEntry>> = thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let regex = &regex;
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .flat_map(|path| search_file(regex, path))
+                        .collect()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    Ok(results.into_iter().flatten().collect())
+}
+
+pub fn draw_quickfix(quickfix: &Quickfix, surf: &impl Surface) {
+    for row in 1..=surf.rows() {
+        let index = quickfix.scroll + row - 1;
+        let Some(entry) = quickfix.entries.get(index) else {
+            break;
+        };
+        surf.goto(row, 1);
+        if index == quickfix.current {
+            if quickfix.focused {
+                surf.set_color(Color::Black, Color::Cyan);
+            } else {
+                surf.set_fg_color(Color::Cyan);
+            }
+        }
+        let line = format!(
+            "{}:{}:{}: {}",
+            entry.path, entry.line, entry.col, entry.text
+        );
+        let line: String = line
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .take(surf.cols())
+            .collect();
+        print!("{}", line);
+        surf.reset_colors();
+    }
+}
+
+pub fn quickfix_title(quickfix: &Quickfix) -> String {
+    if quickfix.entries.is_empty() {
+        " Quickfix: no matches ".to_owned()
+    } else {
+        format!(
+            " Quickfix: {} of {} ",
+            quickfix.current + 1,
+            quickfix.entries.len()
+        )
+    }
+}
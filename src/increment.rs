@@ -0,0 +1,209 @@
+use time::{Date, Duration, Month};
+
+use crate::common::*;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Decimal,
+    Hex,
+    Binary,
+    Date, // YYYY-MM-DD
+}
+
+fn is_date(line: &[char], i: usize) -> bool {
+    line.len() >= i + 10
+        && line[i..i + 10].iter().enumerate().all(|(k, c)| {
+            if k == 4 || k == 7 {
+                *c == '-'
+            } else {
+                c.is_ascii_digit()
+            }
+        })
+        && (i == 0 || !line[i - 1].is_ascii_digit())
+        && line.get(i + 10).is_none_or(|c| !c.is_ascii_digit())
+}
+
+// The number starting at i, as its kind and where it ends
+fn number_at(line: &[char], i: usize) -> Option<(Kind, usize)> {
+    if is_date(line, i) {
+        return Some((Kind::Date, i + 10));
+    }
+    let digits_from = |start: usize, valid: fn(&char) -> bool| {
+        start + line[start..].iter().take_while(|c| valid(c)).count()
+    };
+    let marker = line.get(i + 1).map(|c| c.to_ascii_lowercase());
+    let after_marker = line.get(i + 2);
+    match line[i] {
+        '0' if marker == Some('x') && after_marker.is_some_and(char::is_ascii_hexdigit) => {
+            Some((Kind::Hex, digits_from(i + 2, char::is_ascii_hexdigit)))
+        }
+        '0' if marker == Some('b') && after_marker.is_some_and(|c| matches!(c, '0' | '1')) => {
+            Some((Kind::Binary, digits_from(i + 2, |c| matches!(c, '0' | '1'))))
+        }
+        c if c.is_ascii_digit() => Some((Kind::Decimal, digits_from(i, char::is_ascii_digit))),
+        // A minus sign counts unless it joins two words
+        '-' if line.get(i + 1).is_some_and(char::is_ascii_digit)
+            && (i == 0 || !line[i - 1].is_alphanumeric()) =>
+        {
+            Some((Kind::Decimal, digits_from(i + 1, char::is_ascii_digit)))
+        }
+        _ => None,
+    }
+}
+
+fn shift_date(text: &str, part: usize, delta: i64) -> Option<String> {
+    let year: i32 = text[0..4].parse().ok()?;
+    let month: u8 = text[5..7].parse().ok()?;
+    let day: u8 = text[8..10].parse().ok()?;
+    let date = Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()?;
+    // Changing the year or month keeps the day if the new month is long enough
+    let with_month = |months: i64| {
+        let total = year as i64 * 12 + month as i64 - 1 + months;
+        let year = i32::try_from(total.div_euclid(12)).ok()?;
+        let month = Month::try_from(total.rem_euclid(12) as u8 + 1).ok()?;
+        let day = day.min(month.length(year));
+        Date::from_calendar_date(year, month, day).ok()
+    };
+    let new = match part {
+        0..=3 => with_month(delta.checked_mul(12)?)?,
+        4..=6 => with_month(delta)?,
+        _ => date.checked_add(Duration::days(delta))?,
+    };
+    Some(format!(
+        "{:04}-{:02}-{:02}",
+        new.year(),
+        new.month() as u8,
+        new.day()
+    ))
+}
+
+// The number after adding to it, in the same format. Dates change in the part the column is
+// on, or by days if the column is before them.
+fn add_to(text: &str, kind: Kind, col: Option<usize>, delta: i64) -> Option<String> {
+    match kind {
+        Kind::Decimal => {
+            let value: i64 = text.parse().ok()?;
+            let new = value.saturating_add(delta);
+            let digits = text.trim_start_matches('-');
+            // Leading zeros keep the width
+            Some(if digits.len() > 1 && digits.starts_with('0') {
+                let sign = if new < 0 { "-" } else { "" };
+                format!("{sign}{:0width$}", new.unsigned_abs(), width = digits.len())
+            } else {
+                new.to_string()
+            })
+        }
+        Kind::Hex | Kind::Binary => {
+            let (prefix, digits) = text.split_at(2);
+            let radix = if kind == Kind::Hex { 16 } else { 2 };
+            let value = u64::from_str_radix(digits, radix).ok()?;
+            let new = value.wrapping_add(delta as u64);
+            let width = digits.len();
+            Some(match kind {
+                Kind::Hex if digits.chars().any(|c| c.is_ascii_uppercase()) => {
+                    format!("{prefix}{new:0width$X}")
+                }
+                Kind::Hex => format!("{prefix}{new:0width$x}"),
+                _ => format!("{prefix}{new:0width$b}"),
+            })
+        }
+        Kind::Date => shift_date(text, col.unwrap_or(usize::MAX), delta),
+    }
+}
+
+// Finds the first number that ends after the column, like Ctrl-A in Vim. Returns where it
+// starts and ends, and the text to replace it with.
+pub fn increment(line: &[char], col: usize, delta: i64) -> Option<(usize, usize, String32)> {
+    let mut i = 0;
+    while i < line.len() {
+        match number_at(line, i) {
+            Some((kind, end)) if end > col => {
+                let text: String = line[i..end].iter().collect();
+                let offset = col.checked_sub(i);
+                let new = add_to(&text, kind, offset, delta)?;
+                return Some((i, end, new.chars().collect()));
+            }
+            Some((_, end)) => i = end,
+            None => i += 1,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The line after incrementing at the zero based column
+    fn apply(line: &str, col: usize, delta: i64) -> Option<String> {
+        let mut line: String32 = line.chars().collect();
+        let (start, end, new) = increment(&line, col, delta)?;
+        line.splice(start..end, new);
+        Some(line.into_iter().collect())
+    }
+
+    #[test]
+    fn first_number_under_or_after_the_cursor() {
+        assert_eq!(apply("x = 41;", 0, 1).unwrap(), "x = 42;");
+        assert_eq!(apply("123", 1, 1).unwrap(), "124");
+        assert_eq!(apply("1 and 2", 1, 5).unwrap(), "1 and 7");
+        assert_eq!(apply("a 1 b", 4, 1), None);
+    }
+
+    #[test]
+    fn negative_numbers() {
+        assert_eq!(apply("-5", 0, 10).unwrap(), "5");
+        assert_eq!(apply("x -1", 0, 1).unwrap(), "x 0");
+        assert_eq!(apply("(-3)", 0, 1).unwrap(), "(-2)");
+        assert_eq!(apply("3", 0, -5).unwrap(), "-2");
+    }
+
+    #[test]
+    fn minus_between_words_is_not_a_sign() {
+        assert_eq!(apply("a-1", 0, 1).unwrap(), "a-2");
+        assert_eq!(apply("item-9", 2, 1).unwrap(), "item-10");
+        assert_eq!(apply("x1-2", 0, 1).unwrap(), "x2-2");
+    }
+
+    #[test]
+    fn leading_zeros_keep_the_width() {
+        assert_eq!(apply("007", 0, 1).unwrap(), "008");
+        assert_eq!(apply("099", 0, 1).unwrap(), "100");
+        assert_eq!(apply("-010", 0, 20).unwrap(), "010");
+    }
+
+    #[test]
+    fn hex_and_binary() {
+        assert_eq!(apply("0xff", 0, 1).unwrap(), "0x100");
+        assert_eq!(apply("0x00fe", 0, 1).unwrap(), "0x00ff");
+        assert_eq!(apply("0X0F", 0, 1).unwrap(), "0X10");
+        assert_eq!(apply("0b0111", 0, 1).unwrap(), "0b1000");
+        // Without digits after the marker it is a plain zero
+        assert_eq!(apply("0x", 0, 1).unwrap(), "1x");
+    }
+
+    #[test]
+    fn dates_change_in_the_part_under_the_cursor() {
+        assert_eq!(apply("2024-01-31", 0, 1).unwrap(), "2025-01-31");
+        assert_eq!(apply("2024-01-31", 5, 1).unwrap(), "2024-02-29");
+        assert_eq!(apply("2024-01-31", 9, 1).unwrap(), "2024-02-01");
+        assert_eq!(apply("due 2024-12-31", 0, 1).unwrap(), "due 2025-01-01");
+    }
+
+    #[test]
+    fn dates_clamp_to_the_end_of_the_month() {
+        assert_eq!(apply("2024-03-31", 6, -1).unwrap(), "2024-02-29");
+        assert_eq!(apply("2023-03-31", 6, -1).unwrap(), "2023-02-28");
+        assert_eq!(apply("2024-02-29", 2, 1).unwrap(), "2025-02-28");
+        assert_eq!(apply("2024-10-31", 5, 2).unwrap(), "2024-12-31");
+        assert_eq!(apply("2024-12-15", 5, 1).unwrap(), "2025-01-15");
+    }
+
+    #[test]
+    fn not_quite_dates() {
+        // With another digit in front it is a number
+        assert_eq!(apply("12024-01-01", 0, 1).unwrap(), "12025-01-01");
+        // A date that cannot exist is left alone
+        assert_eq!(apply("2024-13-01", 0, 1), None);
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::Path;
+
+use crate::common::*;
+
+const PAIRS: [(char, char); 6] = [
+    ('(', ')'),
+    ('[', ']'),
+    ('{', '}'),
+    ('"', '"'),
+    ('\'', '\''),
+    ('`', '`'),
+];
+
+pub fn closer_of(opener: char) -> Option<char> {
+    PAIRS
+        .iter()
+        .find_map(|(open, close)| (*open == opener).then_some(*close))
+}
+
+pub fn is_closer(cha: char) -> bool {
+    PAIRS.iter().any(|(_, close)| *close == cha)
+}
+
+pub fn is_quote(cha: char) -> bool {
+    matches!(cha, '"' | '\'' | '`')
+}
+
+pub fn leading_whitespace(line: &[char]) -> String32 {
+    line.iter()
+        .take_while(|c| c.is_whitespace())
+        .copied()
+        .collect()
+}
+
+// One level of indentation, with tabs if the line is indented with them
+pub fn indent_unit(line: &[char], tab_width: usize) -> String32 {
+    if line.first() == Some(&'\t') {
+        vec!['\t']
+    } else {
+        vec![' '; tab_width]
+    }
+}
+
+// Whether the line after this one goes one level deeper, judged by how this one ends
+pub fn opens_block(line: &[char], path: &str) -> bool {
+    let python = Path::new(path).extension().is_some_and(|ext| ext == "py");
+    match line.iter().rev().find(|c| !c.is_whitespace()) {
+        Some('{' | '(' | '[') => true,
+        Some(':') => python,
+        _ => false,
+    }
+}
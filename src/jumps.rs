@@ -0,0 +1,143 @@
+use crate::common::*;
+use crate::undo::*;
+
+const MAX_JUMPS: usize = 100;
+
+// Buffers and positions jumped away from, oldest first
+pub struct JumpList {
+    pub jumps: Vec<(usize, Coord)>,
+    pub index: usize, // Position in the list while moving with Ctrl-O and Ctrl-I
+}
+
+impl JumpList {
+    pub fn new() -> Self {
+        JumpList {
+            jumps: Vec::new(),
+            index: 0,
+        }
+    }
+
+    // Only the latest jump from each line is kept
+    pub fn push(&mut self, jump: (usize, Coord)) {
+        self.jumps
+            .retain(|(buffer, pos)| !(*buffer == jump.0 && pos.0 == jump.1 .0));
+        self.jumps.push(jump);
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.index = self.jumps.len();
+    }
+
+    // The place count jumps back from the current one
+    pub fn older(
+        &mut self,
+        current: (usize, Coord),
+        count: usize,
+    ) -> Result<(usize, Coord), String> {
+        if self.index == self.jumps.len() {
+            // Going back from the newest place saves it, so Ctrl-I can return to it
+            self.push(current);
+            self.index = self.jumps.len() - 1;
+        }
+        if count > self.index {
+            return Err("At start of jump list".to_owned());
+        }
+        self.index -= count;
+        Ok(self.jumps[self.index])
+    }
+
+    pub fn newer(&mut self, count: usize) -> Result<(usize, Coord), String> {
+        if self.index + count >= self.jumps.len() {
+            return Err("At end of jump list".to_owned());
+        }
+        self.index += count;
+        Ok(self.jumps[self.index])
+    }
+
+    // Forgets the jumps into a closed buffer, and renumbers the ones after it
+    pub fn remove_buffer(&mut self, index: usize) {
+        self.jumps.retain(|(i, _)| *i != index);
+        for (i, _) in &mut self.jumps {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        self.index = self.jumps.len();
+    }
+
+    // Moves the jumps into a buffer along with an edit to it
+    pub fn shift(&mut self, buffer: usize, action: &TextAction) {
+        for (_, pos) in self.jumps.iter_mut().filter(|(b, _)| *b == buffer) {
+            *pos = action.shift_coord(*pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(jumps: &[(usize, Coord)]) -> JumpList {
+        let mut list = JumpList::new();
+        for jump in jumps {
+            list.push(*jump);
+        }
+        list
+    }
+
+    #[test]
+    fn push_keeps_the_latest_jump_from_a_line() {
+        let list = list(&[(0, (1, 1)), (0, (5, 1)), (1, (1, 3)), (0, (1, 4))]);
+        assert_eq!(list.jumps, [(0, (5, 1)), (1, (1, 3)), (0, (1, 4))]);
+        assert_eq!(list.index, 3);
+    }
+
+    #[test]
+    fn push_forgets_the_oldest_jumps() {
+        let jumps: Vec<(usize, Coord)> = (1..=MAX_JUMPS + 5).map(|row| (0, (row, 1))).collect();
+        let list = list(&jumps);
+        assert_eq!(list.jumps.len(), MAX_JUMPS);
+        assert_eq!(list.jumps[0], (0, (6, 1)));
+    }
+
+    #[test]
+    fn going_back_saves_the_current_place() {
+        let mut list = list(&[(0, (1, 1)), (0, (2, 1))]);
+        assert_eq!(list.older((0, (3, 1)), 1), Ok((0, (2, 1))));
+        assert_eq!(list.jumps.len(), 3);
+        assert_eq!(list.older((0, (2, 1)), 1), Ok((0, (1, 1))));
+        assert_eq!(
+            list.older((0, (1, 1)), 1),
+            Err("At start of jump list".to_owned())
+        );
+        assert_eq!(list.newer(2), Ok((0, (3, 1))));
+        assert_eq!(list.newer(1), Err("At end of jump list".to_owned()));
+        // A new jump goes after all the others
+        list.older((0, (3, 1)), 2).unwrap();
+        list.push((0, (9, 1)));
+        assert_eq!(list.index, 4);
+        assert_eq!(list.jumps[3], (0, (9, 1)));
+    }
+
+    #[test]
+    fn closing_a_buffer_renumbers_the_ones_after_it() {
+        let mut list = list(&[(0, (1, 1)), (1, (2, 1)), (2, (3, 1))]);
+        list.remove_buffer(1);
+        assert_eq!(list.jumps, [(0, (1, 1)), (1, (3, 1))]);
+        assert_eq!(list.index, 2);
+    }
+
+    #[test]
+    fn jumps_move_with_edits_to_their_buffer() {
+        let mut list = list(&[(0, (3, 2)), (1, (3, 2))]);
+        list.shift(
+            0,
+            &TextAction::InsertLines {
+                start: 1,
+                stop: 1,
+                lines: vec![Vec::new()],
+            },
+        );
+        assert_eq!(list.jumps, [(0, (4, 2)), (1, (3, 2))]);
+    }
+}
@@ -0,0 +1,756 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::buffer::*;
+use crate::common::*;
+use crate::config::*;
+use crate::terminal::*;
+use crate::undo::*;
+use crate::Signal;
+
+// Stands in for "the end of the line" when the length of a line is not known
+const LINE_END: usize = i32::MAX as usize;
+
+// A zero based position as sent by a server. The character counts UTF-16 code units
+// unless the server agreed to count characters.
+#[derive(Debug, Clone, Copy)]
+pub struct Position {
+    line: usize,
+    character: usize,
+    utf32: bool,
+}
+
+impl Position {
+    fn from_json(value: &Value, utf32: bool) -> Self {
+        Self {
+            line: value["line"].as_u64().unwrap_or(0) as usize,
+            character: value["character"].as_u64().unwrap_or(0) as usize,
+            utf32,
+        }
+    }
+
+    pub fn to_coord(self, contents: &[String32]) -> Coord {
+        let Some(line) = contents.get(self.line) else {
+            return (self.line + 1, 1);
+        };
+        if self.utf32 {
+            return (self.line + 1, self.character.min(line.len()) + 1);
+        }
+        let mut units = 0;
+        for (i, cha) in line.iter().enumerate() {
+            if units >= self.character {
+                return (self.line + 1, i + 1);
+            }
+            units += cha.len_utf16();
+        }
+        (self.line + 1, line.len() + 1)
+    }
+}
+
+fn position_json(contents: &[String32], pos: Coord, utf32: bool) -> Value {
+    let line = contents.get(pos.0 - 1).map_or(&[][..], |line| &line[..]);
+    let before = &line[..min(pos.1 - 1, line.len())];
+    let character = if utf32 {
+        before.len()
+    } else {
+        before.iter().map(|c| c.len_utf16()).sum()
+    };
+    json!({"line": pos.0 - 1, "character": character})
+}
+
+pub struct Diagnostic {
+    pub start: Coord,
+    pub end: Coord,
+    pub severity: u64, // 1 is an error, 4 a hint
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn sign(&self) -> (char, Color) {
+        match self.severity {
+            1 => ('E', Color::Red),
+            2 => ('W', Color::Yellow),
+            3 => ('I', Color::Blue),
+            _ => ('H', Color::Cyan),
+        }
+    }
+}
+
+pub struct TextEdit {
+    pub start: Position,
+    pub end: Position,
+    pub text: String,
+}
+
+pub enum LspEvent {
+    Hover(String),
+    Definition(String, Position),
+    Edits(Vec<(String, Vec<TextEdit>)>, String), // Edits per file and the name of the undo step
+    Diagnostics(String, Vec<(Position, Position, u64, String)>),
+    Message(String),
+}
+
+enum Request {
+    Initialize,
+    Hover,
+    Definition,
+    Rename(String),
+    Formatting(String),
+}
+
+struct Document {
+    version: i64,
+    lines: usize, // Line count as the server knows it
+}
+
+struct Server {
+    command: String,
+    child: Child,
+    stdin: ChildStdin,
+    incoming: Receiver<Value>,
+    next_id: i64,
+    requests: HashMap<i64, Request>,
+    ready: bool,        // Whether the initialize handshake is done
+    queued: Vec<Value>, // Messages held back until then
+    incremental: bool,
+    utf32: bool,
+    documents: HashMap<String, Document>, // By uri
+}
+
+impl Server {
+    fn start(command: &str, signals: Sender<Signal>) -> Result<Self, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| format!("Could not start {command}: {err}"))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Some(message) = read_message(&mut reader) {
+                if sender.send(message).is_err() || signals.send(Signal::Refresh).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut server = Self {
+            command: command.to_owned(),
+            child,
+            stdin,
+            incoming,
+            next_id: 1,
+            requests: HashMap::new(),
+            ready: false,
+            queued: Vec::new(),
+            incremental: false,
+            utf32: false,
+            documents: HashMap::new(),
+        };
+        let root = std::env::current_dir().unwrap_or_default();
+        server.request(
+            "initialize",
+            json!({
+                "processId": std::process::id(),
+                "rootUri": path_to_uri(&root.to_string_lossy()),
+                "capabilities": {
+                    "general": {"positionEncodings": ["utf-32", "utf-16"]},
+                    "textDocument": {
+                        "synchronization": {"didSave": true},
+                        "hover": {"contentFormat": ["plaintext", "markdown"]},
+                        "definition": {"linkSupport": true},
+                        "rename": {},
+                        "formatting": {},
+                        "publishDiagnostics": {},
+                    },
+                    "workspace": {"workspaceEdit": {"documentChanges": true}},
+                },
+            }),
+            Request::Initialize,
+        );
+        Ok(server)
+    }
+
+    fn write(&mut self, message: Value) {
+        if !self.ready && message["method"] != "initialize" {
+            self.queued.push(message);
+            return;
+        }
+        let body = message.to_string();
+        let _ = write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.stdin.flush();
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.write(json!({"jsonrpc": "2.0", "method": method, "params": params}));
+    }
+
+    fn request(&mut self, method: &str, params: Value, kind: Request) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.insert(id, kind);
+        self.write(json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}));
+    }
+
+    fn handle(&mut self, message: Value, events: &mut Vec<LspEvent>) {
+        let method = message["method"].as_str();
+        let id = message.get("id").cloned();
+        match (method, id) {
+            // Requests from the server, which all get a plain answer
+            (Some(method), Some(id)) => {
+                let result = match method {
+                    "workspace/configuration" => {
+                        let items = message["params"]["items"].as_array().map_or(0, |a| a.len());
+                        Value::Array(vec![Value::Null; items])
+                    }
+                    _ => Value::Null,
+                };
+                self.write(json!({"jsonrpc": "2.0", "id": id, "result": result}));
+            }
+            (Some(method), None) => self.handle_notification(method, &message["params"], events),
+            (None, Some(id)) => {
+                let Some(kind) = id.as_i64().and_then(|id| self.requests.remove(&id)) else {
+                    return;
+                };
+                if let Some(error) = message.get("error") {
+                    let text = error["message"].as_str().unwrap_or("Request failed");
+                    events.push(LspEvent::Message(format!("{}: {text}", self.command)));
+                    return;
+                }
+                self.handle_response(kind, &message["result"], events);
+            }
+            (None, None) => (),
+        }
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Value, events: &mut Vec<LspEvent>) {
+        match method {
+            "textDocument/publishDiagnostics" => {
+                let Some(path) = params["uri"].as_str().and_then(uri_to_path) else {
+                    return;
+                };
+                let diagnostics = params["diagnostics"]
+                    .as_array()
+                    .map_or(&[][..], |a| a)
+                    .iter()
+                    .map(|diag| {
+                        (
+                            Position::from_json(&diag["range"]["start"], self.utf32),
+                            Position::from_json(&diag["range"]["end"], self.utf32),
+                            diag["severity"].as_u64().unwrap_or(1),
+                            diag["message"].as_str().unwrap_or("").to_owned(),
+                        )
+                    })
+                    .collect();
+                events.push(LspEvent::Diagnostics(path, diagnostics));
+            }
+            "window/showMessage" => {
+                if let Some(text) = params["message"].as_str() {
+                    events.push(LspEvent::Message(text.to_owned()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn handle_response(&mut self, kind: Request, result: &Value, events: &mut Vec<LspEvent>) {
+        match kind {
+            Request::Initialize => {
+                let capabilities = &result["capabilities"];
+                self.utf32 = capabilities["positionEncoding"] == "utf-32";
+                let sync = &capabilities["textDocumentSync"];
+                let change = sync.get("change").unwrap_or(sync);
+                self.incremental = change.as_u64() == Some(2);
+                self.ready = true;
+                self.notify("initialized", json!({}));
+                for message in std::mem::take(&mut self.queued) {
+                    self.write(message);
+                }
+            }
+            Request::Hover => {
+                let text = hover_text(&result["contents"]);
+                if text.trim().is_empty() {
+                    events.push(LspEvent::Message("No information available".to_owned()));
+                } else {
+                    events.push(LspEvent::Hover(text.trim().to_owned()));
+                }
+            }
+            Request::Definition => {
+                let location = match result {
+                    Value::Array(locations) => locations.first().unwrap_or(&Value::Null),
+                    location => location,
+                };
+                let uri = location.get("targetUri").or(location.get("uri"));
+                let range = location
+                    .get("targetSelectionRange")
+                    .or(location.get("range"));
+                match (
+                    uri.and_then(|uri| uri.as_str()).and_then(uri_to_path),
+                    range,
+                ) {
+                    (Some(path), Some(range)) => events.push(LspEvent::Definition(
+                        path,
+                        Position::from_json(&range["start"], self.utf32),
+                    )),
+                    _ => events.push(LspEvent::Message("No definition found".to_owned())),
+                }
+            }
+            Request::Rename(name) => {
+                let edits = self.workspace_edits(result);
+                if edits.is_empty() {
+                    events.push(LspEvent::Message("Nothing to rename".to_owned()));
+                } else {
+                    events.push(LspEvent::Edits(edits, format!("Rename to {name}")));
+                }
+            }
+            Request::Formatting(path) => {
+                let edits = self.text_edits(result);
+                if !edits.is_empty() {
+                    events.push(LspEvent::Edits(
+                        vec![(path, edits)],
+                        "Format document".to_owned(),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn text_edits(&self, edits: &Value) -> Vec<TextEdit> {
+        edits
+            .as_array()
+            .map_or(&[][..], |a| a)
+            .iter()
+            .map(|edit| TextEdit {
+                start: Position::from_json(&edit["range"]["start"], self.utf32),
+                end: Position::from_json(&edit["range"]["end"], self.utf32),
+                text: edit["newText"].as_str().unwrap_or("").to_owned(),
+            })
+            .collect()
+    }
+
+    fn workspace_edits(&self, edit: &Value) -> Vec<(String, Vec<TextEdit>)> {
+        let mut files = Vec::new();
+        if let Some(changes) = edit["documentChanges"].as_array() {
+            for change in changes {
+                if let Some(path) = change["textDocument"]["uri"].as_str().and_then(uri_to_path) {
+                    files.push((path, self.text_edits(&change["edits"])));
+                }
+            }
+        } else if let Some(changes) = edit["changes"].as_object() {
+            for (uri, edits) in changes {
+                if let Some(path) = uri_to_path(uri) {
+                    files.push((path, self.text_edits(edits)));
+                }
+            }
+        }
+        files
+    }
+
+    // Describes the actions as content changes, keeping track of the line count
+    fn content_changes(action: &TextAction, lines: &mut usize, changes: &mut Vec<Value>) {
+        use TextAction::*;
+        let pos = |line: usize, character: usize| json!({"line": line, "character": character});
+        let mut change = |start: Value, end: Value, text: String| {
+            changes.push(json!({"range": {"start": start, "end": end}, "text": text}));
+        };
+        match action {
+            None => (),
+            Insert { start, text, .. } => {
+                let at = pos(start.0 - 1, start.1 - 1);
+                change(at.clone(), at, concat_lines(text));
+                *lines += text.len() - 1;
+            }
+            Remove { start, text, .. } => {
+                let end = if text.len() == 1 {
+                    pos(start.0 - 1, start.1 - 1 + text[0].len())
+                } else {
+                    pos(start.0 + text.len() - 2, text[text.len() - 1].len())
+                };
+                change(pos(start.0 - 1, start.1 - 1), end, String::new());
+                *lines = lines.saturating_sub(text.len() - 1).max(1);
+            }
+            InsertChar { pos: at, cha } => {
+                let at = pos(at.0 - 1, at.1 - 1);
+                change(at.clone(), at, cha.to_string());
+                if *cha == '\n' {
+                    *lines += 1;
+                }
+            }
+            RemoveChar { pos: at, cha } => {
+                let end = if *cha == '\n' {
+                    *lines = lines.saturating_sub(1).max(1);
+                    pos(at.0, 0)
+                } else {
+                    pos(at.0 - 1, at.1)
+                };
+                change(pos(at.0 - 1, at.1 - 1), end, String::new());
+            }
+            InsertLines {
+                start, lines: new, ..
+            } => {
+                let text = concat_lines(new);
+                if *start <= *lines {
+                    let at = pos(start - 1, 0);
+                    change(at.clone(), at, text + "\n");
+                } else {
+                    let at = pos(*lines - 1, LINE_END);
+                    change(at.clone(), at, "\n".to_owned() + &text);
+                }
+                *lines += new.len();
+            }
+            RemoveLines { start, stop, .. } => {
+                if *stop < *lines {
+                    change(pos(start - 1, 0), pos(*stop, 0), String::new());
+                } else if *start > 1 {
+                    change(
+                        pos(start - 2, LINE_END),
+                        pos(stop - 1, LINE_END),
+                        String::new(),
+                    );
+                } else {
+                    change(pos(0, 0), pos(stop - 1, LINE_END), String::new());
+                }
+                *lines = lines.saturating_sub(stop - start + 1).max(1);
+            }
+            Composite { actions, .. } => {
+                for action in actions {
+                    Self::content_changes(action, lines, changes);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if self.ready {
+            self.write(json!({"jsonrpc": "2.0", "id": 0, "method": "shutdown"}));
+            self.notify("exit", Value::Null);
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Reads one message with its Content-Length header, or None when the server is gone
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    Some(serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn hover_text(contents: &Value) -> String {
+    match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .map(hover_text)
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        Value::Object(object) => object
+            .get("value")
+            .and_then(|value| value.as_str())
+            .unwrap_or("")
+            .to_owned(),
+        _ => String::new(),
+    }
+}
+
+pub fn path_to_uri(path: &str) -> String {
+    let absolute = std::fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_owned());
+    let mut uri = "file://".to_owned();
+    for byte in absolute.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    uri
+}
+
+pub fn uri_to_path(uri: &str) -> Option<String> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok());
+        match (
+            encoded[i],
+            hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+        ) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn server_command(path: &str) -> Option<String> {
+    let extension = Path::new(path).extension()?.to_str()?;
+    CONFIG
+        .read()
+        .unwrap()
+        .language_servers
+        .get(extension)
+        .cloned()
+}
+
+// Runs one language server per configured command, shared by all buffers using it
+pub struct Lsp {
+    servers: HashMap<String, Server>,
+    failed: HashSet<String>, // Commands that could not be started, so they are not retried
+    signals: Sender<Signal>,
+}
+
+impl Lsp {
+    pub fn new(signals: Sender<Signal>) -> Self {
+        Self {
+            servers: HashMap::new(),
+            failed: HashSet::new(),
+            signals,
+        }
+    }
+
+    fn server_for(&mut self, buf: &Buffer) -> Option<&mut Server> {
+        let command = server_command(&buf.path)?;
+        self.servers.get_mut(&command)
+    }
+
+    // Starts the server for the buffer if needed, and tells it about the buffer
+    pub fn open(&mut self, buf: &Buffer) -> Result<(), String> {
+        let Some(command) = server_command(&buf.path) else {
+            return Ok(());
+        };
+        if self.failed.contains(&command) {
+            return Ok(());
+        }
+        if !self.servers.contains_key(&command) {
+            match Server::start(&command, self.signals.clone()) {
+                Ok(server) => self.servers.insert(command.clone(), server),
+                Err(err) => {
+                    self.failed.insert(command);
+                    return Err(err);
+                }
+            };
+        }
+        let server = self.servers.get_mut(&command).unwrap();
+        let uri = path_to_uri(&buf.path);
+        if server.documents.contains_key(&uri) {
+            return Ok(());
+        }
+        let language = Path::new(&buf.path)
+            .extension()
+            .map_or("", |ext| ext.to_str().unwrap_or(""));
+        let language = match language {
+            "rs" => "rust",
+            "py" => "python",
+            "js" => "javascript",
+            "ts" => "typescript",
+            "h" => "c",
+            "hpp" | "cc" => "cpp",
+            other => other,
+        };
+        server.notify(
+            "textDocument/didOpen",
+            json!({"textDocument": {
+                "uri": uri,
+                "languageId": language,
+                "version": 0,
+                "text": concat_lines(&buf.contents) + "\n",
+            }}),
+        );
+        server.documents.insert(
+            uri,
+            Document {
+                version: 0,
+                lines: buf.contents.len(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn change(&mut self, buf: &Buffer, actions: &[TextAction]) {
+        if actions.is_empty() {
+            return;
+        }
+        let uri = path_to_uri(&buf.path);
+        let Some(server) = self.server_for(buf) else {
+            return;
+        };
+        let Some(doc) = server.documents.get_mut(&uri) else {
+            return;
+        };
+        doc.version += 1;
+        // Columns are sent as characters, which is only right for UTF-16 without surrogates
+        let simple = server.utf32 || buf.contents.iter().flatten().all(|c| c.len_utf16() == 1);
+        let changes = if server.incremental && simple {
+            let mut changes = Vec::new();
+            for action in actions {
+                Server::content_changes(action, &mut doc.lines, &mut changes);
+            }
+            changes
+        } else {
+            vec![json!({"text": concat_lines(&buf.contents) + "\n"})]
+        };
+        doc.lines = buf.contents.len();
+        let version = doc.version;
+        server.notify(
+            "textDocument/didChange",
+            json!({
+                "textDocument": {"uri": uri, "version": version},
+                "contentChanges": changes,
+            }),
+        );
+    }
+
+    pub fn save(&mut self, buf: &Buffer) {
+        let uri = path_to_uri(&buf.path);
+        if let Some(server) = self.server_for(buf) {
+            server.notify(
+                "textDocument/didSave",
+                json!({"textDocument": {"uri": uri}}),
+            );
+        }
+    }
+
+    pub fn close(&mut self, buf: &Buffer) {
+        let uri = path_to_uri(&buf.path);
+        if let Some(server) = self.server_for(buf) {
+            if server.documents.remove(&uri).is_some() {
+                server.notify(
+                    "textDocument/didClose",
+                    json!({"textDocument": {"uri": uri}}),
+                );
+            }
+        }
+    }
+
+    fn position_request(
+        &mut self,
+        buf: &Buffer,
+        pos: Coord,
+        method: &str,
+        extra: Value,
+        kind: Request,
+    ) -> Result<(), String> {
+        let uri = path_to_uri(&buf.path);
+        let Some(server) = self.server_for(buf) else {
+            return Err(format!("No language server for {}", buf.name()));
+        };
+        let mut params = json!({
+            "textDocument": {"uri": uri},
+            "position": position_json(&buf.contents, pos, server.utf32),
+        });
+        if let (Value::Object(params), Value::Object(extra)) = (&mut params, extra) {
+            params.extend(extra);
+        }
+        server.request(method, params, kind);
+        Ok(())
+    }
+
+    pub fn hover(&mut self, buf: &Buffer, pos: Coord) -> Result<(), String> {
+        self.position_request(buf, pos, "textDocument/hover", Value::Null, Request::Hover)
+    }
+
+    pub fn definition(&mut self, buf: &Buffer, pos: Coord) -> Result<(), String> {
+        self.position_request(
+            buf,
+            pos,
+            "textDocument/definition",
+            Value::Null,
+            Request::Definition,
+        )
+    }
+
+    pub fn rename(&mut self, buf: &Buffer, pos: Coord, name: &str) -> Result<(), String> {
+        self.position_request(
+            buf,
+            pos,
+            "textDocument/rename",
+            json!({"newName": name}),
+            Request::Rename(name.to_owned()),
+        )
+    }
+
+    pub fn format(&mut self, buf: &Buffer) -> Result<(), String> {
+        let uri = path_to_uri(&buf.path);
+        let path = buf.path.clone();
+        let Some(server) = self.server_for(buf) else {
+            return Err(format!("No language server for {}", buf.name()));
+        };
+        let tab_width = CONFIG.read().unwrap().tab_width;
+        server.request(
+            "textDocument/formatting",
+            json!({
+                "textDocument": {"uri": uri},
+                "options": {"tabSize": tab_width, "insertSpaces": true},
+            }),
+            Request::Formatting(path),
+        );
+        Ok(())
+    }
+
+    // Handles everything the servers sent since the last call
+    pub fn poll(&mut self) -> Vec<LspEvent> {
+        let mut events = Vec::new();
+        let mut exited = Vec::new();
+        for (command, server) in &mut self.servers {
+            loop {
+                match server.incoming.try_recv() {
+                    Ok(message) => server.handle(message, &mut events),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        exited.push(command.clone());
+                        break;
+                    }
+                }
+            }
+        }
+        for command in exited {
+            self.servers.remove(&command);
+            self.failed.insert(command.clone());
+            events.push(LspEvent::Message(format!(
+                "{command}: language server exited"
+            )));
+        }
+        events
+    }
+}
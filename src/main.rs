@@ -1,10 +1,14 @@
 use std::cmp::{max, min};
+use std::collections::HashSet;
 use std::env;
 use std::io::prelude::*;
 use std::io::{stdin, stdout};
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use notify::{RecursiveMode, Watcher};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 
 use wl_clipboard_rs::copy::{self, Options, Source};
 use wl_clipboard_rs::paste::{self, get_contents, ClipboardType, Seat};
@@ -24,96 +28,1221 @@ mod buffer;
 use buffer::*;
 mod undo;
 use undo::*;
+mod view;
+use view::*;
+mod command;
+use command::*;
+mod finder;
+mod walk;
+use finder::*;
+mod grep;
+use grep::*;
+mod filter;
+use filter::*;
+mod eval;
+use eval::*;
+mod lsp;
+use lsp::*;
+mod complete;
+use complete::*;
+mod diff;
+use diff::*;
+mod swap;
+use swap::*;
+mod session;
+use session::*;
+mod jumps;
+use jumps::*;
+mod indent;
+use indent::*;
+mod comment;
+use comment::*;
+mod increment;
+use increment::*;
+mod matching;
+use matching::*;
+mod find;
+use find::*;
+mod sort;
 
 struct Process {
     buffers: Vec<Buffer>,
-    active_buffer: usize,
+    views: Vec<View>,
+    layout: Layout,
+    separators: Vec<Rect>,
+    active_view: usize,
+    pending: Vec<Key>,
+    count: Option<usize>,
+    command_line: String,
+    message: Option<String>,
+    finder: Option<Finder>,
+    quickfix: Quickfix,
+    lsp: Lsp,
+    popup: Option<String>, // Hover text and diagnostics shown next to the cursor
+    completion: Option<Completion>,
+    history_selection: Option<HistorySelection>, // Set while the history panel has focus
+    recovery: Vec<(usize, Swap)>,                // Buffers with a swap file newer than their file
+    file_watcher: Option<RecommendedWatcher>,
+    watched_dirs: HashSet<PathBuf>,
+    changed_files: Vec<(PathBuf, Instant)>, // Written to by other programs, and when
+    disk_changes: Vec<(usize, Vec<String32>)>, // Modified buffers whose file changed on disk
+    shada: Shada,
+    command_history_index: Option<usize>, // Set while going through old commands with Up and Down
+    jumps: JumpList,
+    last_find: Option<CharFind>, // Repeated by ; and ,
+    labels: Option<LabelJump>,   // Set while choosing where to jump with gs
+    completion_sources: Vec<Box<dyn CompletionSource>>,
+    signals: Sender<Signal>,
 }
 
+// The node picked in the history panel, with what jumping to it would do
+struct HistorySelection {
+    location: Vec<usize>,
+    summary: Vec<String>,
+    diff: Vec<String>, // From the current text to the node's text
+}
+
+// What wakes up the main loop
+enum Signal {
+    Input(std::io::Result<Event>),
+    Refresh, // Sent by background work that has something new to show
+    Tick,    // Time to write the swap files
+    FileChanged(PathBuf),
+}
+
+// How long a file must be left alone before it is read again, as programs often write in parts
+const FILE_SETTLE_TIME: Duration = Duration::from_millis(100);
+
 impl Process {
     fn get_active_buffer(&mut self) -> &mut Buffer {
-        &mut self.buffers[self.active_buffer]
+        &mut self.buffers[self.views[self.active_view].buffer]
+    }
+
+    fn get_active(&mut self) -> (&mut Buffer, &mut View) {
+        let view = &mut self.views[self.active_view];
+        (&mut self.buffers[view.buffer], view)
+    }
+
+    fn layout_area(&self, term: &Terminal) -> Rect {
+        Rect {
+            top: 2,
+            left: if self.buffers[self.views[self.active_view].buffer].show_history {
+                40
+            } else {
+                1
+            },
+            bottom: if self.quickfix_shown(term) {
+                term.rows() - 3 - QUICKFIX_HEIGHT
+            } else {
+                term.rows().saturating_sub(2).max(2)
+            },
+            right: term.cols(),
+        }
+    }
+
+    // The quickfix panel is left out when the terminal is too short to fit it under the views
+    fn quickfix_shown(&self, term: &Terminal) -> bool {
+        self.quickfix.open && term.rows() >= QUICKFIX_HEIGHT + 5
+    }
+
+    fn quickfix_area(&self, term: &Terminal) -> Rect {
+        Rect {
+            top: term.rows().saturating_sub(1 + QUICKFIX_HEIGHT),
+            left: 1,
+            bottom: term.rows().saturating_sub(2),
+            right: term.cols(),
+        }
+    }
+
+    fn goto_quickfix(&mut self, index: usize) -> Result<(), String> {
+        let Some(entry) = self.quickfix.select(index) else {
+            return Err("No more items".to_owned());
+        };
+        let (path, pos) = (entry.path.clone(), (entry.line, entry.col));
+        self.push_jump();
+        self.open_buffer(&path)?;
+        let (buf, view) = self.get_active();
+        view.cursor = pos;
+        view.cursor_col_goal = pos.1;
+        update_cursor(buf, view);
+        self.message = Some(format!(
+            "({} of {}) {}",
+            index + 1,
+            self.quickfix.entries.len(),
+            self.quickfix.entries[index].text
+        ));
+        Ok(())
+    }
+
+    fn update_layout(&mut self, term: &Terminal) {
+        let mut rects = Vec::new();
+        self.separators.clear();
+        self.layout
+            .arrange(self.layout_area(term), &mut rects, &mut self.separators);
+        for (index, rect) in rects {
+            self.views[index].rect = rect;
+            update_scroll(&mut self.views[index]);
+        }
+    }
+
+    fn view_at(&self, pos: Coord) -> Option<usize> {
+        self.views.iter().position(|view| view.rect.contains(pos))
+    }
+
+    fn focus_view(&mut self, index: usize) {
+        if index == self.active_view {
+            return;
+        }
+        let (buf, view) = self.get_active();
+        buf.set_mode(view, Mode::Normal);
+        self.active_view = index;
+    }
+
+    fn switch_buffer(&mut self, index: usize) {
+        if self.views[self.active_view].buffer != index {
+            self.push_jump();
+        }
+        self.show_buffer(index);
+    }
+
+    // Like switch_buffer, without adding to the jump list
+    fn show_buffer(&mut self, index: usize) {
+        let view = &mut self.views[self.active_view];
+        if view.buffer == index {
+            return;
+        }
+        let buf = &mut self.buffers[view.buffer];
+        buf.set_mode(view, Mode::Normal);
+        buf.last_position = (view.cursor, view.scroll);
+        view.buffer = index;
+        (view.cursor, view.scroll) = self.buffers[index].last_position;
+        view.cursor_col_goal = view.cursor.1;
+        view.clamp(&self.buffers[index].contents);
+    }
+
+    // Shows a file in the active view, reusing its buffer if it is already open
+    fn open_buffer(&mut self, path: &str) -> Result<(), String> {
+        let index = match self.buffers.iter().position(|buf| buf.is_file(path)) {
+            Some(index) => index,
+            None => {
+                let undofile = CONFIG.read().unwrap().undofile;
+                let buffer =
+                    Buffer::open(path, undofile).map_err(|err| format!("{path}: {err}"))?;
+                if let Err(msg) = self.lsp.open(&buffer) {
+                    self.message = Some(msg);
+                }
+                self.buffers.push(buffer);
+                self.check_swap(self.buffers.len() - 1);
+                self.watch_buffer(self.buffers.len() - 1);
+                self.restore_position(self.buffers.len() - 1);
+                self.buffers.len() - 1
+            }
+        };
+        self.switch_buffer(index);
+        Ok(())
+    }
+
+    // Asks what to do with a swap file left behind by an earlier session
+    fn check_swap(&mut self, index: usize) {
+        if let Some(swap) = find_swap(&self.buffers[index].path) {
+            self.recovery.push((index, swap));
+        }
+    }
+
+    // Writes the swap file of every buffer edited since its last one
+    fn write_swaps(&mut self) {
+        if !CONFIG.read().unwrap().swapfile {
+            return;
+        }
+        for (index, buf) in self.buffers.iter_mut().enumerate() {
+            // A swap file waiting to be recovered is not overwritten
+            if !buf.swap_pending || self.recovery.iter().any(|(i, _)| *i == index) {
+                continue;
+            }
+            match write_swap(buf) {
+                Ok(()) => {
+                    buf.swap_pending = false;
+                    buf.swap_written = true;
+                }
+                Err(err) => {
+                    self.message = Some(format!("{}: {err}", swap_path(&buf.path).display()))
+                }
+            }
+        }
+    }
+
+    fn remove_swaps(&mut self) {
+        for buf in &mut self.buffers {
+            if buf.swap_written {
+                remove_swap(&buf.path);
+                buf.swap_written = false;
+            }
+        }
+    }
+
+    // Handles a key while asking about the first swap file found
+    fn answer_recovery(&mut self, key: Key) {
+        let (index, swap) = &self.recovery[0];
+        let buf = &self.buffers[*index];
+        match key {
+            Key::Char('r') => {
+                let (index, swap) = self.recovery.remove(0);
+                let buf = &mut self.buffers[index];
+                buf.contents = swap.contents;
+                buf.history = swap.history;
+                // Whatever was being typed when the swap file was written becomes a change
+                buf.history.locked = false;
+                if buf.history.is_recording() {
+                    buf.history.stop_record();
+                }
+                buf.modified = true;
+                buf.swap_pending = true;
+                buf.diagnostics.clear();
+                self.lsp.close(buf);
+                if let Err(msg) = self.lsp.open(buf) {
+                    self.message = Some(msg);
+                }
+                for view in self.views.iter_mut().filter(|view| view.buffer == index) {
+                    view.clamp(&buf.contents);
+                }
+            }
+            Key::Char('d') => {
+                let diff = unified_diff(&buf.contents, &swap.contents, 3);
+                self.message = Some(if diff.is_empty() {
+                    "The swap file has the same text as the file".to_owned()
+                } else {
+                    diff.join("\n")
+                });
+            }
+            Key::Char('D') => {
+                remove_swap(&buf.path);
+                self.recovery.remove(0);
+            }
+            Key::Char('i') | Key::Esc => {
+                self.recovery.remove(0);
+            }
+            _ => (),
+        }
+    }
+
+    // Puts the cursor of a newly opened buffer where it was when the file was last closed
+    fn restore_position(&mut self, index: usize) {
+        let buf = &mut self.buffers[index];
+        if let Some(pos) = self.shada.position(&buf.path) {
+            buf.last_position = (pos, (1, 1));
+        }
+    }
+
+    fn remember_positions(&mut self) {
+        for (index, buf) in self.buffers.iter().enumerate() {
+            let cursor = match self.views.iter().find(|view| view.buffer == index) {
+                Some(view) => view.cursor,
+                None => buf.last_position.0,
+            };
+            self.shada.set_position(&buf.path, cursor);
+        }
+    }
+
+    fn session(&self) -> Session {
+        let buffers = self
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buf)| {
+                let (cursor, scroll) = match self.views.iter().find(|view| view.buffer == index) {
+                    Some(view) => (view.cursor, view.scroll),
+                    None => buf.last_position,
+                };
+                BufferState {
+                    path: std::fs::canonicalize(&buf.path)
+                        .map_or(buf.path.clone(), |path| path.to_string_lossy().into_owned()),
+                    cursor,
+                    scroll,
+                    clip: buf.clip.iter().map(|line| line.iter().collect()).collect(),
+                    clip_lines: buf.clip_lines,
+                }
+            })
+            .collect();
+        let views = self
+            .views
+            .iter()
+            .map(|view| ViewState {
+                buffer: view.buffer,
+                cursor: view.cursor,
+                scroll: view.scroll,
+            })
+            .collect();
+        Session {
+            buffers,
+            views,
+            active_view: self.active_view,
+            layout: self.layout.clone(),
+        }
+    }
+
+    // Brings back the windows of a session, whose files are the first buffers
+    fn restore_session(&mut self, session: Session) {
+        for (state, buf) in session.buffers.into_iter().zip(&mut self.buffers) {
+            buf.last_position = (state.cursor, state.scroll);
+            if !state.clip.is_empty() {
+                buf.clip = state
+                    .clip
+                    .iter()
+                    .map(|line| line.chars().collect())
+                    .collect();
+                buf.clip_lines = state.clip_lines;
+            }
+        }
+        self.views = session
+            .views
+            .iter()
+            .map(|state| {
+                let mut view = View::new(state.buffer);
+                view.cursor = state.cursor;
+                view.cursor_col_goal = state.cursor.1;
+                view.scroll = state.scroll;
+                view.clamp(&self.buffers[state.buffer].contents);
+                view
+            })
+            .collect();
+        self.layout = session.layout;
+        self.active_view = session.active_view;
+    }
+
+    // Watches the directory of a buffer's file, which also notices the file being replaced
+    fn watch_buffer(&mut self, index: usize) {
+        let Some(watcher) = &mut self.file_watcher else {
+            return;
+        };
+        let path = Path::new(&self.buffers[index].path);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Ok(dir) = std::fs::canonicalize(dir) else {
+            return;
+        };
+        if !self.watched_dirs.contains(&dir)
+            && watcher.watch(&dir, RecursiveMode::NonRecursive).is_ok()
+        {
+            self.watched_dirs.insert(dir);
+        }
+    }
+
+    fn file_event(&mut self, path: PathBuf) {
+        match self.changed_files.iter_mut().find(|(p, _)| *p == path) {
+            Some((_, time)) => *time = Instant::now(),
+            None => self.changed_files.push((path, Instant::now())),
+        }
+        let signals = self.signals.clone();
+        thread::spawn(move || {
+            thread::sleep(FILE_SETTLE_TIME);
+            signals.send(Signal::Refresh).unwrap_or(());
+        });
+    }
+
+    // Handles the files that have not been written to for a moment
+    fn check_changed_files(&mut self) {
+        let now = Instant::now();
+        let (settled, waiting) = std::mem::take(&mut self.changed_files)
+            .into_iter()
+            .partition(|(_, time)| now - *time >= FILE_SETTLE_TIME);
+        self.changed_files = waiting;
+        for (path, _) in settled {
+            let path = path.to_string_lossy();
+            for index in 0..self.buffers.len() {
+                if self.buffers[index].is_file(&path) {
+                    self.file_changed(index);
+                }
+            }
+        }
+    }
+
+    // Reloads a buffer whose file was changed by another program, or asks first if it has
+    // changes of its own
+    fn file_changed(&mut self, index: usize) {
+        let buf = &mut self.buffers[index];
+        let Ok(disk) = buf.read_disk() else {
+            return;
+        };
+        // Our own writes end up here too
+        if disk == buf.disk_contents {
+            return;
+        }
+        buf.reopen().unwrap_or(());
+        if !buf.modified {
+            self.reload_buffer(index, disk);
+            self.message = Some(format!(
+                "{} was changed on disk and reloaded",
+                self.buffers[index].path
+            ));
+        } else if let Some((_, lines)) = self.disk_changes.iter_mut().find(|(i, _)| *i == index) {
+            *lines = disk;
+        } else {
+            self.disk_changes.push((index, disk));
+        }
+    }
+
+    fn reload_buffer(&mut self, index: usize, disk: Vec<String32>) {
+        self.replace_contents(index, &disk, "Reload from disk");
+        let buf = &mut self.buffers[index];
+        buf.disk_contents = disk;
+        buf.history.mark_saved();
+        buf.modified = false;
+        buf.swap_pending = false;
+    }
+
+    // Changes the text of a buffer as one undo step, touching only the lines that differ
+    fn replace_contents(&mut self, index: usize, lines: &[String32], name: &str) {
+        let view = match self.views.iter().position(|view| view.buffer == index) {
+            Some(v) => &mut self.views[v],
+            None => &mut View::new(index),
+        };
+        let buf = &mut self.buffers[index];
+        buf.set_mode(view, Mode::Normal);
+        let blocks = changed_blocks(&buf.contents, lines);
+        if blocks.is_empty() {
+            return;
+        }
+
+        let first_change = buf.changes.len();
+        buf.history.start_record();
+        // Going backwards keeps the line numbers of the remaining blocks valid
+        for (start, stop, mut new) in blocks.into_iter().rev() {
+            let count = new.len();
+            if count > 0 {
+                insert_lines(buf, &mut new, start + 1);
+            }
+            if stop > start {
+                remove_lines(buf, start + count + 1, stop + count);
+            }
+        }
+        buf.history.stop_record_named(name.to_owned());
+
+        self.follow_changes(index, first_change);
+    }
+
+    // Moves the cursor of the active view along with the changes made to the buffer since the
+    // given one. The other views follow in sync_views.
+    fn follow_changes(&mut self, index: usize, first_change: usize) {
+        let buf = &self.buffers[index];
+        let view = &mut self.views[self.active_view];
+        if view.buffer == index {
+            for action in &buf.changes[first_change..] {
+                view.cursor = action.shift_coord(view.cursor);
+            }
+            view.clamp(&buf.contents);
+        }
+    }
+
+    // Handles a key while asking about the first buffer changed both here and on disk
+    fn answer_disk_change(&mut self, key: Key) {
+        let (index, disk) = &self.disk_changes[0];
+        let buf = &self.buffers[*index];
+        match key {
+            Key::Char('r') => {
+                let (index, disk) = self.disk_changes.remove(0);
+                self.reload_buffer(index, disk);
+            }
+            Key::Char('k') | Key::Esc => {
+                // The next write overwrites the file without asking
+                let (index, disk) = self.disk_changes.remove(0);
+                self.buffers[index].disk_contents = disk;
+            }
+            Key::Char('d') => {
+                self.message = Some(unified_diff(&buf.contents, disk, 3).join("\n"));
+            }
+            Key::Char('m') => {
+                let (index, disk) = self.disk_changes.remove(0);
+                let buf = &self.buffers[index];
+                let (merged, conflicts) = merge3(&buf.disk_contents, &buf.contents, &disk);
+                self.replace_contents(index, &merged, "Merge changes from disk");
+                self.buffers[index].disk_contents = disk;
+                if conflicts > 0 {
+                    self.message = Some(format!("Merged with {conflicts} conflicts"));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn delete_buffer(&mut self, index: usize, force: bool) -> Result<(), String> {
+        if self.buffers[index].modified && !force {
+            return Err(format!(
+                "No write since last change for buffer {} (add ! to override)",
+                index + 1
+            ));
+        }
+        if self.buffers.len() == 1 {
+            return Err("Cannot delete the last buffer".to_owned());
+        }
+        let replacement = if index == 0 { 1 } else { index - 1 };
+        let active = self.active_view;
+        for v in 0..self.views.len() {
+            if self.views[v].buffer == index {
+                self.active_view = v;
+                self.show_buffer(replacement);
+            }
+        }
+        self.active_view = active;
+        self.lsp.close(&self.buffers[index]);
+        let buf = self.buffers.remove(index);
+        self.shada.set_position(&buf.path, buf.last_position.0);
+        if buf.swap_written {
+            remove_swap(&buf.path);
+        }
+        self.recovery.retain(|(i, _)| *i != index);
+        for (i, _) in &mut self.recovery {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        self.disk_changes.retain(|(i, _)| *i != index);
+        for (i, _) in &mut self.disk_changes {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        self.jumps.remove_buffer(index);
+        for view in &mut self.views {
+            if view.buffer > index {
+                view.buffer -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn split_view(&mut self, direction: Direction, term: &Terminal) -> Result<(), String> {
+        let new_index = self.views.len();
+        let view = self.views[self.active_view].clone();
+        if !self.layout.split(
+            self.active_view,
+            new_index,
+            direction,
+            &self.layout_area(term),
+        ) {
+            return Err("Not enough room".to_owned());
+        }
+        self.views.push(view);
+        self.active_view = new_index;
+        Ok(())
+    }
+
+    fn close_view(&mut self, index: usize) -> bool {
+        if self.views.len() == 1 {
+            return false;
+        }
+        let order = self.layout.views();
+        let position = order.iter().position(|v| *v == index).unwrap();
+        let neighbour = if position > 0 {
+            order[position - 1]
+        } else {
+            order[1]
+        };
+        if index == self.active_view {
+            self.focus_view(neighbour);
+        }
+        let view = &self.views[index];
+        self.buffers[view.buffer].last_position = (view.cursor, view.scroll);
+        self.layout.remove(index);
+        self.views.remove(index);
+        if self.active_view > index {
+            self.active_view -= 1;
+        }
+        true
+    }
+
+    fn only_view(&mut self) {
+        while self.views.len() > 1 {
+            let index = if self.active_view == 0 { 1 } else { 0 };
+            self.close_view(index);
+        }
+    }
+
+    fn window_command(&mut self, key: Key, term: &Terminal) {
+        let direction_key = match key {
+            Key::Char(c) | Key::Ctrl(c) if "hjkl".contains(c) => Some(c),
+            Key::Left => Some('h'),
+            Key::Down => Some('j'),
+            Key::Up => Some('k'),
+            Key::Right => Some('l'),
+            _ => None,
+        };
+        if let Some(dir) = direction_key {
+            let rects: Vec<(usize, Rect)> = self
+                .views
+                .iter()
+                .enumerate()
+                .map(|(i, view)| (i, view.rect.clone()))
+                .collect();
+            let cursor = self.views[self.active_view].screen_cursor();
+            if let Some(index) = view_in_direction(&rects, self.active_view, cursor, dir) {
+                self.focus_view(index);
+            } else if dir == 'j' && self.quickfix_shown(term) {
+                self.quickfix.focused = true;
+            }
+            return;
+        }
+
+        let area = self.layout_area(term);
+        let result = match key {
+            Key::Char('s') | Key::Char('S') | Key::Ctrl('s') => {
+                self.split_view(Direction::Horizontal, term)
+            }
+            Key::Char('v') | Key::Ctrl('v') => self.split_view(Direction::Vertical, term),
+            Key::Char('w') | Key::Ctrl('w') | Key::Char('W') => {
+                let order = self.layout.views();
+                let position = order.iter().position(|v| *v == self.active_view).unwrap();
+                let next = if key == Key::Char('W') {
+                    (position + order.len() - 1) % order.len()
+                } else {
+                    (position + 1) % order.len()
+                };
+                self.focus_view(order[next]);
+                Ok(())
+            }
+            Key::Char('c') | Key::Char('q') => {
+                if self.close_view(self.active_view) {
+                    Ok(())
+                } else {
+                    Err("Cannot close last window".to_owned())
+                }
+            }
+            Key::Char('o') | Key::Ctrl('o') => {
+                self.only_view();
+                Ok(())
+            }
+            Key::Char('+') | Key::Char('-') | Key::Char('>') | Key::Char('<') => {
+                let (direction, delta) = match key {
+                    Key::Char('+') => (Direction::Horizontal, 1),
+                    Key::Char('-') => (Direction::Horizontal, -1),
+                    Key::Char('>') => (Direction::Vertical, 1),
+                    _ => (Direction::Vertical, -1),
+                };
+                self.layout
+                    .resize(self.active_view, direction, delta, &area);
+                Ok(())
+            }
+            Key::Char('=') => {
+                self.layout.equalize();
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(msg) = result {
+            self.message = Some(msg);
+        }
+    }
+
+    // Moves the views that were not used for editing along with the changes to their buffer
+    fn sync_views(&mut self) {
+        for (index, buf) in self.buffers.iter_mut().enumerate() {
+            let changes = std::mem::take(&mut buf.changes);
+            self.lsp.change(buf, &changes);
+            for diagnostic in &mut buf.diagnostics {
+                for action in &changes {
+                    diagnostic.start = action.shift_coord(diagnostic.start);
+                    diagnostic.end = action.shift_coord(diagnostic.end);
+                }
+            }
+            for action in &changes {
+                buf.shift_marks(action);
+                self.jumps.shift(index, action);
+                buf.record_change(action);
+            }
+            for (v, view) in self.views.iter_mut().enumerate() {
+                if view.buffer != index || v == self.active_view {
+                    continue;
+                }
+                for action in &changes {
+                    view.cursor = action.shift_coord(view.cursor);
+                    view.scroll.0 = action.shift_coord((view.scroll.0, 1)).0;
+                }
+                view.cursor_col_goal = view.cursor.1;
+                view.clamp(&buf.contents);
+            }
+        }
+    }
+
+    // Moves the cursor of the active view, to the first non-blank of the line if asked
+    fn move_cursor(&mut self, pos: Coord, line_start: bool) {
+        let (buf, view) = self.get_active();
+        view.cursor = pos;
+        view.clamp(&buf.contents);
+        if line_start {
+            let line = &buf.contents[view.cursor.0 - 1];
+            view.cursor.1 = line
+                .iter()
+                .position(|c| !c.is_whitespace())
+                .map_or(1, |i| i + 1);
+        }
+        view.cursor_col_goal = view.cursor.1;
+        update_cursor(buf, view);
+    }
+
+    fn set_mark(&mut self, name: char) {
+        if name.is_ascii_uppercase() {
+            for buf in &mut self.buffers {
+                buf.marks.remove(&name);
+            }
+        }
+        let view = &self.views[self.active_view];
+        self.buffers[view.buffer].marks.insert(name, view.cursor);
+    }
+
+    // Goes to a mark with ` or to the start of its line with '
+    fn goto_mark(&mut self, name: char, exact: bool) -> Result<(), String> {
+        let active = self.views[self.active_view].buffer;
+        let index = if name.is_ascii_uppercase() {
+            self.buffers
+                .iter()
+                .position(|buf| buf.marks.contains_key(&name))
+        } else {
+            Some(active).filter(|index| self.buffers[*index].marks.contains_key(&name))
+        };
+        let Some(index) = index else {
+            return Err(format!("Mark not set: {name}"));
+        };
+        self.push_jump();
+        self.show_buffer(index);
+        self.move_cursor(self.buffers[index].marks[&name], !exact);
+        Ok(())
+    }
+
+    // Runs an f, F, t or T motion, or repeats the last one with ; or , the other way. Returns
+    // where the cursor lands without moving it.
+    fn find_in_line(&mut self, keys: &[Key], count: usize) -> Option<Coord> {
+        let (find, repeat) = match keys {
+            [Key::Char(key @ ('f' | 'F' | 't' | 'T')), Key::Char(cha)] => {
+                let find = CharFind::new(*key, *cha);
+                self.last_find = Some(find);
+                (find, false)
+            }
+            [Key::Char(';')] => (self.last_find?, true),
+            [Key::Char(',')] => (self.last_find?.reversed(), true),
+            _ => return None,
+        };
+        let view = &self.views[self.active_view];
+        let line = &self.buffers[view.buffer].contents[view.cursor.0 - 1];
+        let col = find.target(line, view.cursor.1, count, repeat)?;
+        Some((view.cursor.0, col))
+    }
+
+    // Labels every occurrence of the character on screen to jump to. A single one is jumped
+    // to right away.
+    fn start_label_jump(&mut self, cha: char) {
+        let view = &self.views[self.active_view];
+        let buf = &self.buffers[view.buffer];
+        let last_row = min(buf.contents.len(), view.scroll.0 + view.rect.height() - 1);
+        let mut positions = Vec::new();
+        for row in view.scroll.0..=last_row {
+            let visible = buf.contents[row - 1]
+                .iter()
+                .enumerate()
+                .take((view.scroll.1 + view.rect.width()).saturating_sub(7))
+                .skip(view.scroll.1 - 1);
+            for (x, c) in visible {
+                if *c == cha && (row, x + 1) != view.cursor {
+                    positions.push((row, x + 1));
+                }
+            }
+        }
+        match positions[..] {
+            [] => self.message = Some(format!("No {cha} on screen")),
+            [pos] => {
+                self.push_jump();
+                self.move_cursor(pos, false);
+            }
+            _ => self.labels = Some(LabelJump::new(positions)),
+        }
+    }
+
+    // Remembers where the cursor is before it moves far away
+    fn push_jump(&mut self) {
+        let view = &self.views[self.active_view];
+        self.jumps.push((view.buffer, view.cursor));
+    }
+
+    fn jump_older(&mut self, count: usize) -> Result<(), String> {
+        let view = &self.views[self.active_view];
+        let jump = self.jumps.older((view.buffer, view.cursor), count)?;
+        self.goto_jump(jump);
+        Ok(())
+    }
+
+    fn jump_newer(&mut self, count: usize) -> Result<(), String> {
+        let jump = self.jumps.newer(count)?;
+        self.goto_jump(jump);
+        Ok(())
+    }
+
+    fn goto_jump(&mut self, (buffer, pos): (usize, Coord)) {
+        self.show_buffer(buffer);
+        self.move_cursor(pos, false);
+    }
+
+    // Moves through the change list with g; and g,
+    fn goto_change(&mut self, older: bool, count: usize) -> Result<(), String> {
+        let buf = self.get_active_buffer();
+        if buf.change_list.is_empty() {
+            return Err("Change list is empty".to_owned());
+        }
+        let index = if older {
+            buf.change_index
+                .checked_sub(count)
+                .ok_or("At start of change list")?
+        } else {
+            Some(buf.change_index + count)
+                .filter(|index| *index < buf.change_list.len())
+                .ok_or("At end of change list")?
+        };
+        buf.change_index = index;
+        let pos = buf.change_list[index];
+        self.move_cursor(pos, false);
+        Ok(())
+    }
+
+    // Moves the active buffer to another state in its undo tree
+    fn time_travel(&mut self, travel: impl FnOnce(&mut History) -> Vec<TextAction>) {
+        let (buf, view) = self.get_active();
+        // Leaving Insert mode first stores the ongoing edit as a node of its own
+        buf.set_mode(view, Mode::Normal);
+        for action in travel(&mut buf.history) {
+            do_text_action(buf, view, action);
+        }
+        buf.modified = !buf.history.is_saved();
+        restore_cursor(buf, view);
+    }
+
+    fn select_history_node(&mut self, location: Vec<usize>) {
+        let buf = self.get_active_buffer();
+        let state = buf.history.state_at(&location, &buf.contents);
+        self.history_selection = Some(HistorySelection {
+            summary: buf.history.describe_node(&location),
+            diff: unified_diff(&buf.contents, &state, 3),
+            location,
+        });
+    }
+
+    // Selects the node above or below the selected one in the history panel
+    fn history_step(&mut self, down: bool, term: &Terminal) {
+        let Some(selection) = &self.history_selection else {
+            return;
+        };
+        let history = &self.buffers[self.views[self.active_view].buffer].history;
+        let rows = history.node_rows();
+        let Some(index) = rows.iter().position(|(_, loc)| *loc == selection.location) else {
+            return;
+        };
+        let target = if down {
+            rows.get(index + 1)
+        } else {
+            index.checked_sub(1).and_then(|i| rows.get(i))
+        };
+        let Some((row, location)) = target.cloned() else {
+            return;
+        };
+        self.select_history_node(location);
+        let history = &mut self.get_active_buffer().history;
+        let height = term.rows() - 3;
+        if row < history.scroll {
+            history.scroll = row;
+        } else if row >= history.scroll + height {
+            history.scroll = row + 1 - height;
+        }
+    }
+
+    // Replaces the word before the cursor with the next or previous completion candidate
+    fn complete(&mut self, forward: bool) {
+        let view = &self.views[self.active_view];
+        if self.completion.is_none() {
+            let line = &self.buffers[view.buffer].contents[view.cursor.0 - 1];
+            let start = completion_start(line, view.cursor.1);
+            let prefix: String = line[start - 1..view.cursor.1 - 1].iter().collect();
+            let context = CompletionContext {
+                prefix: &prefix,
+                buffers: &self.buffers,
+                active: view.buffer,
+                cursor: view.cursor,
+            };
+            let candidates: Vec<Candidate> = self
+                .completion_sources
+                .iter()
+                .flat_map(|source| source.candidates(&context))
+                .collect();
+            if candidates.is_empty() {
+                self.message = Some("No completions".to_owned());
+                return;
+            }
+            self.completion = Some(Completion {
+                start: (view.cursor.0, start),
+                prefix,
+                candidates,
+                selected: None,
+            });
+        }
+
+        let completion = self.completion.as_mut().unwrap();
+        let old_len = completion.current_text().chars().count();
+        completion.cycle(forward);
+        let text = completion.current_text().to_owned();
+        let start = completion.start;
+        // The edits land in the recording of the current insert
+        let (buf, view) = self.get_active();
+        if old_len > 0 {
+            remove_text(buf, start, (start.0, start.1 + old_len - 1));
+        }
+        view.cursor = start;
+        if !text.is_empty() {
+            insert_text(buf, view, &mut split_text(&text), start);
+        }
+        view.cursor_col_goal = view.cursor.1;
+        update_scroll(view);
+    }
+
+    fn write_active(&mut self, force: bool) -> Result<(), String> {
+        let buf = &mut self.buffers[self.views[self.active_view].buffer];
+        if !force && buf.read_disk().is_ok_and(|disk| disk != buf.disk_contents) {
+            return Err(
+                "The file was changed on disk since it was read (add ! to override)".to_owned(),
+            );
+        }
+        write_buffer(buf).map_err(|err| format!("{}: {err}", buf.path))?;
+        self.lsp.save(buf);
+        if CONFIG.read().unwrap().undofile {
+            buf.history
+                .save(&buf.path)
+                .map_err(|err| format!("Could not write the undo file: {err}"))?;
+        }
+        Ok(())
+    }
+
+    fn handle_lsp_events(&mut self) {
+        for event in self.lsp.poll() {
+            match event {
+                LspEvent::Hover(text) => {
+                    self.popup = Some(match self.popup.take() {
+                        Some(diagnostics) => format!("{diagnostics}\n\n{text}"),
+                        None => text,
+                    });
+                }
+                LspEvent::Definition(path, pos) => {
+                    self.push_jump();
+                    if let Err(msg) = self.open_buffer(&path) {
+                        self.message = Some(msg);
+                        continue;
+                    }
+                    let (buf, view) = self.get_active();
+                    view.cursor = pos.to_coord(&buf.contents);
+                    view.cursor_col_goal = view.cursor.1;
+                    view.clamp(&buf.contents);
+                    update_cursor(buf, view);
+                }
+                LspEvent::Edits(files, name) => {
+                    let count = files.len();
+                    for (path, edits) in files {
+                        if let Err(msg) = self.apply_edits(&path, &edits, &name) {
+                            self.message = Some(msg);
+                        }
+                    }
+                    if count > 1 {
+                        self.message = Some(format!("Changed {count} files"));
+                    }
+                }
+                LspEvent::Diagnostics(path, diagnostics) => {
+                    let Some(buf) = self.buffers.iter_mut().find(|buf| buf.is_file(&path)) else {
+                        continue;
+                    };
+                    buf.diagnostics = diagnostics
+                        .into_iter()
+                        .map(|(start, end, severity, message)| Diagnostic {
+                            start: start.to_coord(&buf.contents),
+                            end: end.to_coord(&buf.contents),
+                            severity,
+                            message,
+                        })
+                        .collect();
+                    buf.diagnostics.sort_by_key(|diag| diag.start);
+                }
+                LspEvent::Message(msg) => self.message = Some(msg),
+            }
+        }
+    }
+
+    // Applies edits from a language server to a file, opening it in the background if needed
+    fn apply_edits(&mut self, path: &str, edits: &[TextEdit], name: &str) -> Result<(), String> {
+        let index = match self.buffers.iter().position(|buf| buf.is_file(path)) {
+            Some(index) => index,
+            None => {
+                let undofile = CONFIG.read().unwrap().undofile;
+                let buffer =
+                    Buffer::open(path, undofile).map_err(|err| format!("{path}: {err}"))?;
+                let _ = self.lsp.open(&buffer);
+                self.buffers.push(buffer);
+                self.buffers.len() - 1
+            }
+        };
+        let buf = &mut self.buffers[index];
+        let mut edits: Vec<(Coord, Coord, &str)> = edits
+            .iter()
+            .rev()
+            .map(|edit| {
+                (
+                    edit.start.to_coord(&buf.contents),
+                    edit.end.to_coord(&buf.contents),
+                    edit.text.as_str(),
+                )
+            })
+            .collect();
+        // Going backwards keeps the positions of the remaining edits valid. Edits at the same
+        // position were reversed above, so they end up in their original order.
+        edits.sort_by_key(|edit| std::cmp::Reverse(edit.0));
+
+        let first_change = buf.changes.len();
+        let recording = buf.history.is_recording();
+        if !recording {
+            buf.history.start_record();
+        }
+        let mut scratch = View::new(index);
+        for (start, end, text) in edits {
+            let last = buf.contents.len();
+            let start = (
+                start.0.min(last),
+                start.1.min(buf.contents[start.0.min(last) - 1].len() + 1),
+            );
+            let end = if end.0 > last {
+                (last, buf.contents[last - 1].len() + 1)
+            } else {
+                end
+            };
+            if end > start {
+                let stop = if end.1 > 1 {
+                    (end.0, end.1 - 1)
+                } else {
+                    (end.0 - 1, buf.contents[end.0 - 2].len() + 1)
+                };
+                remove_text(buf, start, stop);
+            }
+            if !text.is_empty() {
+                scratch.cursor = start;
+                insert_text(buf, &mut scratch, &mut split_text(text), start);
+            }
+        }
+        if !recording {
+            buf.history.stop_record_named(name.to_owned());
+        }
+
+        self.follow_changes(index, first_change);
+        Ok(())
     }
 }
 
-fn up(buf: &mut Buffer, term: &Terminal, n: usize) {
-    if buf.cursor.0 > 1 {
-        buf.cursor.0 -= min(n, buf.cursor.0 - 1);
+fn up(buf: &mut Buffer, view: &mut View, n: usize) {
+    if view.cursor.0 > 1 {
+        view.cursor.0 -= min(n, view.cursor.0 - 1);
 
-        update_cursor(buf, term);
+        update_cursor(buf, view);
         buf.history.snip_record();
     }
 }
 
-fn down(buf: &mut Buffer, term: &Terminal, n: usize) {
-    if buf.cursor.0 < buf.contents.len() {
-        buf.cursor.0 = min(buf.contents.len(), buf.cursor.0 + n);
+fn down(buf: &mut Buffer, view: &mut View, n: usize) {
+    if view.cursor.0 < buf.contents.len() {
+        view.cursor.0 = min(buf.contents.len(), view.cursor.0 + n);
 
-        update_cursor(buf, term);
+        update_cursor(buf, view);
         buf.history.snip_record();
     }
 }
-fn left(buf: &mut Buffer, term: &Terminal, n: usize) {
-    if buf.cursor.1 > 1 {
-        buf.cursor.1 -= min(n, buf.cursor.1 - 1);
-        buf.cursor_col_goal = buf.cursor.1;
-        update_scroll(buf, term);
+fn left(buf: &mut Buffer, view: &mut View, n: usize) {
+    if view.cursor.1 > 1 {
+        view.cursor.1 -= min(n, view.cursor.1 - 1);
+        view.cursor_col_goal = view.cursor.1;
+        update_scroll(view);
         buf.history.snip_record();
     }
 }
-fn right(buf: &mut Buffer, term: &Terminal, n: usize) {
-    let mx = buf.contents[buf.cursor.0 - 1].len() + if buf.mode == Mode::Normal { 0 } else { 1 };
-    if buf.cursor.1 < mx {
-        buf.cursor.1 = min(buf.cursor.1 + n, mx);
-        buf.cursor_col_goal = buf.cursor.1;
-        update_scroll(buf, term);
+fn right(buf: &mut Buffer, view: &mut View, n: usize) {
+    let mx = buf.contents[view.cursor.0 - 1].len() + if buf.mode == Mode::Normal { 0 } else { 1 };
+    if view.cursor.1 < mx {
+        view.cursor.1 = min(view.cursor.1 + n, mx);
+        view.cursor_col_goal = view.cursor.1;
+        update_scroll(view);
         buf.history.snip_record();
     }
 }
 
-fn update_cursor(buf: &mut Buffer, term: &Terminal) {
-    if buf.cursor.0 > buf.contents.len() {
-        buf.cursor.0 = buf.contents.len();
+fn update_cursor(buf: &Buffer, view: &mut View) {
+    if view.cursor.0 > buf.contents.len() {
+        view.cursor.0 = buf.contents.len();
     }
 
     let mx = max(
-        buf.contents[buf.cursor.0 - 1].len() + if buf.mode == Mode::Normal { 0 } else { 1 },
+        buf.contents[view.cursor.0 - 1].len() + if buf.mode == Mode::Normal { 0 } else { 1 },
         1,
     );
-    buf.cursor.1 = min(buf.cursor_col_goal, mx);
-    update_scroll(buf, term)
+    view.cursor.1 = min(view.cursor_col_goal, mx);
+    update_scroll(view)
 }
-fn update_scroll(buf: &mut Buffer, term: &Terminal) {
-    if buf.cursor.0 > 10 && buf.cursor.0 < 10 + buf.scroll.0 {
-        buf.scroll.0 = max(buf.cursor.0 - 10, 1);
-    } else if buf.cursor.0 > buf.scroll.0 + term.rows() - 12 {
-        buf.scroll.0 = buf.cursor.0 + 12 - term.rows();
+fn cursor_state(buf: &Buffer, view: &View) -> CursorState {
+    CursorState {
+        cursor: view.cursor,
+        selection: matches!(buf.mode, Mode::Visual | Mode::VisualLine)
+            .then(|| selected_bounds(view)),
     }
+}
 
-    if buf.scroll.1 >= buf.cursor.1 {
-        buf.scroll.1 = max(buf.cursor.1 - 1, 1);
-    } else if buf.scroll.1 + term.cols() - 7 <= buf.cursor.1 {
-        buf.scroll.1 = buf.cursor.1 + 8 - term.cols();
+// Puts the cursor back where it was around the change that was undone or redone
+fn restore_cursor(buf: &mut Buffer, view: &mut View) {
+    if let Some(state) = buf.history.take_restored_cursor() {
+        view.cursor = state.cursor;
+        view.cursor_col_goal = state.cursor.1;
+        if let Some((start, stop)) = state.selection {
+            view.selection_start = start;
+            buf.visual_marks = (start, stop);
+        }
     }
+    update_cursor(buf, view);
 }
 
-fn redraw(buffer: &Buffer, term: &mut Terminal) {
+fn update_scroll(view: &mut View) {
+    let margin = view.margin();
+    if view.cursor.0 < view.scroll.0 + margin {
+        view.scroll.0 = max(view.cursor.0.saturating_sub(margin), 1);
+    } else if view.cursor.0 + margin > view.scroll.0 + view.rect.height() {
+        view.scroll.0 = view.cursor.0 + margin - view.rect.height();
+    }
+
+    if view.scroll.1 >= view.cursor.1 {
+        view.scroll.1 = max(view.cursor.1 - 1, 1);
+    } else if view.scroll.1 + view.rect.width() - 7 <= view.cursor.1 {
+        view.scroll.1 = view.cursor.1 + 8 - view.rect.width();
+    }
+}
+
+fn redraw(process: &Process, term: &mut Terminal) {
     term.reset_colors();
     term.clear();
 
+    let active = &process.views[process.active_view];
+    let buffer = &process.buffers[active.buffer];
     if buffer.show_history {
         let hist_surface = Window {
             parent: &term,
             rect: Rect {
-                top: 1,
+                top: 2,
                 left: 1,
                 bottom: term.rows() - 2,
                 right: 38,
             },
         };
-        draw_history(&buffer.history, &hist_surface);
+        let selected = process
+            .history_selection
+            .as_ref()
+            .map(|selection| &selection.location[..]);
+        draw_history(&buffer.history, selected, &hist_surface);
         let sep = Rect {
-            top: 1,
+            top: 2,
             left: 39,
             bottom: term.rows() - 2,
             right: 39,
@@ -121,33 +1250,87 @@ fn redraw(buffer: &Buffer, term: &mut Terminal) {
         draw_fill(sep, Color::Gray, term);
     }
 
-    let buf_surface = Window {
-        parent: &term,
-        rect: Rect {
-            top: 1,
-            left: if buffer.show_history { 40 } else { 1 },
-            bottom: term.rows() - 2,
-            right: term.cols(),
-        },
-    };
-
-    draw_contents(buffer, &buf_surface);
-
-    let rect = Rect {
-        top: buffer.cursor.0 + 2,
-        left: buffer.cursor.1 + 8,
-        bottom: buffer.cursor.0 + 4,
-        right: buffer.cursor.1 + 30,
-    };
-    let text = "Hi! This is a popup.".to_owned();
-    //draw_popup(rect, text, term);
+    for (index, view) in process.views.iter().enumerate() {
+        let surface = Window {
+            parent: term,
+            rect: view.rect.clone(),
+        };
+        draw_contents(
+            &process.buffers[view.buffer],
+            view,
+            index == process.active_view,
+            &surface,
+        );
+    }
+    if let Some(labels) = &process.labels {
+        let surface = Window {
+            parent: term,
+            rect: active.rect.clone(),
+        };
+        draw_labels(labels, active.scroll, &surface);
+    }
+    for sep in &process.separators {
+        draw_fill(sep.clone(), Color::Gray, term);
+    }
+    if process.quickfix_shown(term) {
+        let area = process.quickfix_area(term);
+        term.goto(area.top - 1, 1);
+        term.set_color(Color::Black, Color::Gray);
+        let title = quickfix_title(&process.quickfix);
+        print!("{:<width$}", title, width = term.cols());
+        term.reset_colors();
+        let surface = Window {
+            parent: term,
+            rect: area,
+        };
+        draw_quickfix(&process.quickfix, &surface);
+    }
 
-    draw_status(buffer, term);
+    draw_tabline(process, term);
+    draw_status(buffer, active, term);
+    draw_message(process, term);
+    if let Some(text) = &process.popup {
+        draw_popup_at(active.screen_cursor(), text, term);
+    }
+    if let Some(completion) = &process.completion {
+        draw_completion(completion, active, term);
+    }
+    if let Some(selection) = &process.history_selection {
+        if term.cols() > 60 {
+            let rect = Rect {
+                top: 2,
+                left: 40,
+                bottom: term.rows() - 2,
+                right: term.cols(),
+            };
+            draw_history_details(selection, rect, term);
+        }
+    }
 
     //term.goto(term.rows(), 1);
     //term.reset_colors();
     //print!("{}", preview_lines(&buffer.clip, 50));
-    draw_cursor(buffer, &buf_surface);
+    if let Some(finder) = &process.finder {
+        draw_finder(finder, term);
+    } else if let Some(selection) = &process.history_selection {
+        let row = buffer.history.row_of(&selection.location).unwrap_or(1);
+        term.goto(row + 2 - buffer.history.scroll.min(row), 1);
+    } else if process.quickfix.focused && process.quickfix_shown(term) {
+        let area = process.quickfix_area(term);
+        term.goto(
+            area.top + process.quickfix.current - process.quickfix.scroll,
+            1,
+        );
+    } else if buffer.mode == Mode::Command {
+        term.goto(term.rows(), process.command_line.chars().count() + 2);
+        term.bar(true);
+    } else {
+        let buf_surface = Window {
+            parent: term,
+            rect: active.rect.clone(),
+        };
+        draw_cursor(buffer, active, &buf_surface);
+    }
     term.reset_colors();
     term.flush();
 }
@@ -204,27 +1387,146 @@ fn draw_popup(rect: Rect, text: String, term: &mut Terminal) {
     draw_text_box(inner_rect, text, term);
 }
 
-fn draw_contents<S>(buffer: &Buffer, surf: &S)
+// Draws a popup just below or above a position on the screen, sized to fit the text
+fn draw_popup_at(anchor: Coord, text: &str, term: &mut Terminal) -> Rect {
+    let max_width = term.cols().saturating_sub(4).max(1);
+    let max_height = (term.rows() / 2).max(1);
+    let lines: Vec<String> = text
+        .lines()
+        .take(max_height)
+        .map(|line| {
+            line.replace('\t', "    ")
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(max_width)
+                .collect()
+        })
+        .collect();
+    let width = lines
+        .iter()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let height = lines.len() + 2;
+    let top = if anchor.0 + height < term.rows() - 1 || anchor.0 <= height {
+        anchor.0 + 1
+    } else {
+        anchor.0 - height
+    };
+    let left = anchor.1.min(term.cols() + 1 - width).max(1);
+    let rect = Rect {
+        top,
+        left,
+        bottom: top + height - 1,
+        right: left + width - 1,
+    };
+    draw_popup(rect.clone(), lines.join("\n"), term);
+    rect
+}
+
+// The selected undo node's age and actions, followed by the diff jumping to it would make
+fn draw_history_details(selection: &HistorySelection, rect: Rect, term: &mut Terminal) {
+    draw_popup(rect.clone(), String::new(), term);
+    let width = rect.width() - 2;
+    let diff: &[String] = if selection.diff.is_empty() {
+        &["No changes".to_owned()]
+    } else {
+        &selection.diff
+    };
+    let lines = selection
+        .summary
+        .iter()
+        .map(|line| (line, Color::White))
+        .chain(diff.iter().map(|line| {
+            let color = match line.chars().next() {
+                Some('+') => Color::Green,
+                Some('-') => Color::Red,
+                Some('@') => Color::Cyan,
+                _ => Color::White,
+            };
+            (line, color)
+        }));
+    for (row, (line, color)) in (rect.top + 1..rect.bottom).zip(lines) {
+        term.goto(row, rect.left + 1);
+        term.set_fg_color(color);
+        let shown: String = line
+            .replace('\t', "    ")
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(width)
+            .collect();
+        print!("{shown}");
+        term.reset_colors();
+    }
+}
+
+fn draw_completion(completion: &Completion, view: &View, term: &mut Terminal) {
+    const MENU_ROWS: usize = 10;
+    let selected = completion.selected.unwrap_or(0);
+    let scroll = (selected + 1).saturating_sub(MENU_ROWS);
+    let shown =
+        &completion.candidates[scroll..min(scroll + MENU_ROWS, completion.candidates.len())];
+    let width = shown
+        .iter()
+        .map(|candidate| candidate.text.chars().count())
+        .max()
+        .unwrap_or(0);
+    let lines: Vec<String> = shown
+        .iter()
+        .map(|candidate| format!("{:<width$} {}", candidate.text, candidate.source))
+        .collect();
+
+    let (row, col) = view.screen_cursor();
+    let anchor = (
+        row,
+        (col + completion.start.1).saturating_sub(view.cursor.1 + 1),
+    );
+    let rect = draw_popup_at(anchor, &lines.join("\n"), term);
+    if let Some(index) = completion.selected {
+        if let Some(line) = lines.get(index - scroll) {
+            term.goto(rect.top + 1 + index - scroll, rect.left + 1);
+            term.set_color(Color::Black, Color::Cyan);
+            print!(
+                "{}",
+                line.chars().take(rect.width() - 2).collect::<String>()
+            );
+            term.reset_colors();
+        }
+    }
+}
+
+fn draw_contents<S>(buffer: &Buffer, view: &View, active: bool, surf: &S)
 where
     S: Surface,
 {
-    let mut cur_content_line = buffer.scroll.0;
+    let mut cur_content_line = view.scroll.0;
     let mut cur_screen_line = 1;
     let mut in_selection = false;
-    let (sel_start, sel_stop) = selected_bounds(buffer);
+    let (sel_start, sel_stop) = selected_bounds(view);
+    // Only the view being edited shows the selection
+    let mode = if active { buffer.mode } else { Mode::Normal };
 
     if cur_content_line > sel_start.0 && cur_content_line <= sel_stop.0 {
         in_selection = true;
     }
+    // The partner of the bracket under the cursor stands out if it is on screen
+    let matched = if mode == Mode::Normal && active {
+        let pairs = parse_match_pairs(&CONFIG.read().unwrap().match_pairs);
+        let rows = (view.scroll.0, view.scroll.0 + surf.rows() - 1);
+        matching_item(&buffer.contents, view.cursor, &pairs, rows)
+    } else {
+        None
+    };
 
     loop {
         let number = if CONFIG.read().unwrap().relative_number {
-            if cur_content_line < buffer.cursor.0 {
-                buffer.cursor.0 - cur_content_line
-            } else if cur_content_line == buffer.cursor.0 {
+            if cur_content_line < view.cursor.0 {
+                view.cursor.0 - cur_content_line
+            } else if cur_content_line == view.cursor.0 {
                 cur_content_line
             } else {
-                cur_content_line - buffer.cursor.0
+                cur_content_line - view.cursor.0
             }
         } else {
             cur_content_line
@@ -232,14 +1534,29 @@ where
 
         surf.goto(cur_screen_line, 1);
         surf.reset_colors();
-        if cur_content_line != buffer.cursor.0 {
+        // The most severe diagnostic on the line takes the first column of the gutter
+        let sign = buffer
+            .diagnostics
+            .iter()
+            .filter(|diag| diag.start.0 == cur_content_line)
+            .min_by_key(|diag| diag.severity);
+        let width = if let Some(diag) = sign {
+            let (cha, color) = diag.sign();
+            surf.set_fg_color(color);
+            print!("{}", cha);
+            surf.reset_colors();
+            4
+        } else {
+            5
+        };
+        if cur_content_line != view.cursor.0 {
             surf.set_fg_color(Color::Gray);
         }
-        print!("{:>5} ", number);
+        print!("{:>width$} ", number);
         surf.reset_colors();
         let line = &buffer.contents[cur_content_line as usize - 1];
-        if buffer.mode == Mode::Visual {
-            if line.len() < buffer.scroll.1 {
+        if mode == Mode::Visual {
+            if line.len() < view.scroll.1 {
                 if (
                     cur_content_line,
                     buffer.contents[cur_content_line - 1].len() + 1,
@@ -263,8 +1580,7 @@ where
                 if in_selection {
                     surf.set_bg_color(Color::Gray);
                 }
-                for x in buffer.scroll.1 - 1..min(line.len() + 1, buffer.scroll.1 + surf.cols() - 7)
-                {
+                for x in view.scroll.1 - 1..min(line.len() + 1, view.scroll.1 + surf.cols() - 7) {
                     if (cur_content_line, x + 1) == sel_start {
                         in_selection = true;
                         surf.set_bg_color(Color::Gray);
@@ -279,7 +1595,7 @@ where
                 }
             }
             surf.reset_colors();
-        } else if buffer.mode == Mode::VisualLine {
+        } else if mode == Mode::VisualLine {
             if cur_content_line == sel_start.0 {
                 in_selection = true;
             }
@@ -287,8 +1603,8 @@ where
                 surf.set_bg_color(Color::Gray);
             }
 
-            if line.len() >= buffer.scroll.1 {
-                for x in buffer.scroll.1 - 1..min(line.len(), buffer.scroll.1 + surf.cols() - 7) {
+            if line.len() >= view.scroll.1 {
+                for x in view.scroll.1 - 1..min(line.len(), view.scroll.1 + surf.cols() - 7) {
                     print!("{}", &line[x]);
                 }
             } else {
@@ -298,9 +1614,18 @@ where
             if cur_content_line == sel_stop.0 {
                 in_selection = false;
             }
-        } else if line.len() >= buffer.scroll.1 {
-            for x in buffer.scroll.1 - 1..min(line.len(), buffer.scroll.1 + surf.cols() - 7) {
+        } else if line.len() >= view.scroll.1 {
+            for x in view.scroll.1 - 1..min(line.len(), view.scroll.1 + surf.cols() - 7) {
+                let highlight = matched.is_some_and(|((row, col), len)| {
+                    row == cur_content_line && x + 1 >= col && x + 1 < col + len
+                });
+                if highlight {
+                    surf.set_bg_color(Color::Cyan);
+                }
                 print!("{}", &line[x]);
+                if highlight {
+                    surf.reset_colors();
+                }
             }
         }
         cur_content_line += 1;
@@ -311,7 +1636,7 @@ where
     }
 }
 
-fn draw_status(buffer: &Buffer, term: &impl Surface) {
+fn draw_status(buffer: &Buffer, view: &View, term: &impl Surface) {
     term.goto(term.rows() - 1, 1);
     term.set_bg_color(Color::Gray);
     for _ in 0..term.cols() {
@@ -326,77 +1651,335 @@ fn draw_status(buffer: &Buffer, term: &impl Surface) {
     term.set_color(sec, Color::Gray);
     print!("");
     term.set_color(Color::Black, pri);
-    let pos_str = format!(" {}:{} ", buffer.cursor.0, buffer.cursor.1);
+    let pos_str = format!(" {}:{} ", view.cursor.0, view.cursor.1);
     term.goto(term.rows() - 1, term.cols() - pos_str.len() + 1);
     print!("{}", pos_str);
 }
 
-fn draw_cursor(buffer: &Buffer, surf: &impl Surface) {
+fn draw_tabline(process: &Process, term: &impl Surface) {
+    let active = process.views[process.active_view].buffer;
+    let labels: Vec<String> = process
+        .buffers
+        .iter()
+        .enumerate()
+        .map(|(i, buf)| {
+            let flag = if buf.modified { " +" } else { "" };
+            format!(" {}:{}{} ", i + 1, buf.name(), flag)
+        })
+        .collect();
+
+    // Leave out buffers from the left until the active one fits
+    let mut first = 0;
+    let width = |range: &[String]| range.iter().map(|l| l.chars().count()).sum::<usize>();
+    while first < active && width(&labels[first..=active]) > term.cols() {
+        first += 1;
+    }
+
+    term.goto(1, 1);
+    term.set_bg_color(Color::Gray);
+    for _ in 0..term.cols() {
+        print!(" ");
+    }
+    term.goto(1, 1);
+    let mut used = 0;
+    for (i, label) in labels.iter().enumerate().skip(first) {
+        let len = label.chars().count();
+        if used + len > term.cols() {
+            break;
+        }
+        if i == active {
+            term.set_color(Color::Black, Color::White);
+        } else {
+            term.set_color(Color::White, Color::Gray);
+        }
+        print!("{}", label);
+        used += len;
+    }
+    term.reset_colors();
+}
+
+fn draw_message(process: &Process, term: &impl Surface) {
+    term.reset_colors();
+    let prompt = if let Some((index, _)) = process.recovery.first() {
+        Some(format!(
+            "Found a swap file newer than {}. (r)ecover, (d)iff, (D)elete or (i)gnore?",
+            process.buffers[*index].path
+        ))
+    } else {
+        process.disk_changes.first().map(|(index, _)| {
+            format!(
+                "{} was changed on disk. (r)eload, (k)eep, (d)iff or (m)erge?",
+                process.buffers[*index].path
+            )
+        })
+    };
+    let message = match &prompt {
+        Some(prompt) => Some(format!(
+            "{}{prompt}",
+            process
+                .message
+                .as_ref()
+                .map_or(String::new(), |msg| msg.to_owned() + "\n"),
+        )),
+        None => process.message.clone(),
+    };
+    if prompt.is_none()
+        && process.buffers[process.views[process.active_view].buffer].mode == Mode::Command
+    {
+        term.goto(term.rows(), 1);
+        print!(":{}", process.command_line);
+    } else if let Some(message) = &message {
+        // Longer messages grow upwards over the windows
+        let lines: Vec<&str> = message.lines().collect();
+        let top = (term.rows() + 1).saturating_sub(lines.len()).max(1);
+        for (i, line) in lines.iter().enumerate().take(term.rows()) {
+            term.goto(top + i, 1);
+            print!(
+                "\x1b[2K{}",
+                line.chars().take(term.cols()).collect::<String>()
+            );
+        }
+    }
+}
+
+fn draw_cursor(buffer: &Buffer, view: &View, surf: &impl Surface) {
     surf.goto(
-        buffer.cursor.0 - buffer.scroll.0 + 1,
-        buffer.cursor.1 + 7 - buffer.scroll.1,
+        view.cursor.0 - view.scroll.0 + 1,
+        view.cursor.1 + 7 - view.scroll.1,
     );
     surf.bar(buffer.mode == Mode::Insert);
 }
 
-fn insert_key(buf: &mut Buffer, key: Key) {
+fn insert_key(buf: &mut Buffer, view: &mut View, key: Key) {
     match key {
-        Key::Char(cha) => {
-            if cha == '\n' {
-                buf.history.snip_record();
-            }
-            insert_char(buf, cha, buf.cursor);
+        Key::Char('\n') => {
+            buf.history.snip_record();
+            insert_newline(buf, view);
         }
+        Key::Char(cha) => type_char(buf, view, cha),
         Key::Backspace => {
-            if buf.cursor.0 > 1 || buf.cursor.1 > 1 {
-                let pos = if buf.cursor.1 == 1 {
-                    (buf.cursor.0 - 1, buf.contents[buf.cursor.0 - 2].len() + 1)
+            if view.cursor.0 > 1 || view.cursor.1 > 1 {
+                let pos = if view.cursor.1 == 1 {
+                    (view.cursor.0 - 1, buf.contents[view.cursor.0 - 2].len() + 1)
                 } else {
-                    (buf.cursor.0, buf.cursor.1 - 1)
+                    (view.cursor.0, view.cursor.1 - 1)
                 };
-                remove_char(buf, pos)
-            }
-            // if buf.cursor.1 > 1 {
-            //     buf.contents[buf.cursor.0 - 1].remove(buf.cursor.1 - 2);
-            //     buf.cursor.1 -= 1;
-            // } else if buf.cursor.0 > 1 {
-            //     buf.cursor.1 = buf.contents[buf.cursor.0 - 2].len() + 1;
-            //     let mut tail = buf.contents.remove(buf.cursor.0 - 1);
-            //     buf.contents[buf.cursor.0 - 2].append(&mut tail);
-            //     buf.cursor.0 -= 1;
+                remove_char(buf, view, pos)
+            }
+            // if view.cursor.1 > 1 {
+            //     buf.contents[view.cursor.0 - 1].remove(view.cursor.1 - 2);
+            //     view.cursor.1 -= 1;
+            // } else if view.cursor.0 > 1 {
+            //     view.cursor.1 = buf.contents[view.cursor.0 - 2].len() + 1;
+            //     let mut tail = buf.contents.remove(view.cursor.0 - 1);
+            //     buf.contents[view.cursor.0 - 2].append(&mut tail);
+            //     view.cursor.0 -= 1;
             // }
         }
         Key::Delete => {
-            if buf.cursor.0 < buf.contents.len()
-                || buf.cursor.1 <= buf.contents[buf.cursor.0 - 1].len()
+            if view.cursor.0 < buf.contents.len()
+                || view.cursor.1 <= buf.contents[view.cursor.0 - 1].len()
             {
-                remove_char(buf, buf.cursor)
+                remove_char(buf, view, view.cursor)
             }
 
-            // if buf.cursor.1 <= buf.contents[buf.cursor.0 - 1].len() {
-            //     buf.contents[buf.cursor.0 - 1].remove(buf.cursor.1 - 1);
-            // } else if buf.cursor.0 < buf.contents.len() - 1 {
-            //     let mut tail = buf.contents.remove(buf.cursor.0);
-            //     buf.contents[buf.cursor.0 - 1].append(&mut tail);
+            // if view.cursor.1 <= buf.contents[view.cursor.0 - 1].len() {
+            //     buf.contents[view.cursor.0 - 1].remove(view.cursor.1 - 1);
+            // } else if view.cursor.0 < buf.contents.len() - 1 {
+            //     let mut tail = buf.contents.remove(view.cursor.0);
+            //     buf.contents[view.cursor.0 - 1].append(&mut tail);
             // }
         }
         _ => (),
     }
-    buf.cursor_col_goal = buf.cursor.1;
+    view.cursor_col_goal = view.cursor.1;
 }
 
-fn selected_bounds(buf: &Buffer) -> (Coord, Coord) {
-    if buf.selection_start.0 < buf.cursor.0
-        || (buf.selection_start.0 == buf.cursor.0 && buf.selection_start.1 <= buf.cursor.1)
+// Replace mode types over the text, but still inserts line breaks and adds to the line end
+fn replace_key(buf: &mut Buffer, view: &mut View, key: Key) {
+    match key {
+        Key::Char('\n') => insert_newline(buf, view),
+        Key::Char(cha) => {
+            let pos = view.cursor;
+            if pos.1 <= buf.contents[pos.0 - 1].len() {
+                remove_char(buf, view, pos);
+            }
+            insert_char(buf, view, cha, pos);
+        }
+        Key::Backspace => restore_replaced(buf, view),
+        Key::Delete => insert_key(buf, view, key),
+        _ => (),
+    }
+    view.cursor_col_goal = view.cursor.1;
+}
+
+// Backspace in Replace mode takes the last typed character back out of the recording and
+// puts back the one it replaced. Before the typed text it only moves left.
+fn restore_replaced(buf: &mut Buffer, view: &mut View) {
+    let (row, col) = view.cursor;
+    let target = if col > 1 {
+        (row, col - 1)
+    } else if row > 1 {
+        (row - 1, buf.contents[row - 2].len() + 1)
+    } else {
+        return;
+    };
+    let Some(recording) = &mut buf.history.recording else {
+        return;
+    };
+    if !matches!(recording.last(), Some(TextAction::InsertChar { pos, .. }) if *pos == target) {
+        if col > 1 {
+            view.cursor.1 -= 1;
+        }
+        return;
+    }
+    recording.pop();
+    let original = match recording.last() {
+        Some(TextAction::RemoveChar { pos, cha }) if *pos == target && *cha != '\n' => {
+            let cha = *cha;
+            recording.pop();
+            Some(cha)
+        }
+        _ => None,
+    };
+    buf.history.locked = true;
+    remove_char(buf, view, target);
+    if let Some(cha) = original {
+        insert_char(buf, view, cha, target);
+    }
+    buf.history.locked = false;
+    view.cursor = target;
+}
+
+// Replaces the characters from start to stop with the given one, keeping line breaks
+fn replace_chars(buf: &mut Buffer, start: Coord, stop: Coord, cha: char) {
+    buf.history.start_record();
+    for row in start.0..=stop.0 {
+        let len = buf.contents[row - 1].len();
+        let first = if row == start.0 { start.1 } else { 1 };
+        let last = if row == stop.0 { stop.1.min(len) } else { len };
+        if first <= last {
+            replace_in_line(buf, (row, first), last, vec![cha; last - first + 1]);
+        }
+    }
+    buf.history.stop_record_named(format!(
+        "Replace with {cha} on {}",
+        describe_lines(start.0, stop.0)
+    ));
+}
+
+// Splits the line at the cursor, indenting the new line
+fn insert_newline(buf: &mut Buffer, view: &mut View) {
+    let config = CONFIG.read().unwrap();
+    let (smartindent, autopairs) = (config.smartindent, config.autopairs);
+    let line = &buf.contents[view.cursor.0 - 1];
+    let before = &line[..view.cursor.1 - 1];
+    let indent = if config.autoindent || smartindent {
+        leading_whitespace(before)
+    } else {
+        Vec::new()
+    };
+    let mut inner = indent.clone();
+    if smartindent && opens_block(before, &buf.path) {
+        inner.extend(indent_unit(&indent, config.tab_width as usize));
+    }
+    // Enter between a pair of brackets moves the closing one down a line too
+    let between = (smartindent || autopairs)
+        && before
+            .last()
+            .and_then(|c| closer_of(*c))
+            .is_some_and(|closer| {
+                !is_quote(closer) && line.get(view.cursor.1 - 1) == Some(&closer)
+            });
+    drop(config);
+
+    insert_char(buf, view, '\n', view.cursor);
+    for cha in inner {
+        insert_char(buf, view, cha, view.cursor);
+    }
+    if between {
+        let pos = view.cursor;
+        insert_char(buf, view, '\n', view.cursor);
+        for cha in indent {
+            insert_char(buf, view, cha, view.cursor);
+        }
+        view.cursor = pos;
+    }
+}
+
+// Inserts a typed character, pairing brackets and quotes if enabled
+fn type_char(buf: &mut Buffer, view: &mut View, cha: char) {
+    let config = CONFIG.read().unwrap();
+    let (smartindent, autopairs) = (config.smartindent, config.autopairs);
+    let tab_width = config.tab_width as usize;
+    drop(config);
+    let line = &buf.contents[view.cursor.0 - 1];
+    let prev = (view.cursor.1 > 1).then(|| line[view.cursor.1 - 2]);
+    let next = line.get(view.cursor.1 - 1).copied();
+
+    // Typing the closing character that is already there steps over it
+    if autopairs && is_closer(cha) && next == Some(cha) {
+        view.cursor.1 += 1;
+        return;
+    }
+    if smartindent
+        && matches!(cha, '}' | ')' | ']')
+        && view.cursor.1 > 1
+        && line[..view.cursor.1 - 1].iter().all(|c| c.is_whitespace())
+    {
+        // A closing bracket at the start of a line goes back one level
+        let count = if prev == Some('\t') {
+            1
+        } else {
+            line[..view.cursor.1 - 1]
+                .iter()
+                .rev()
+                .take(tab_width)
+                .take_while(|c| **c == ' ')
+                .count()
+        };
+        for _ in 0..count {
+            view.cursor.1 -= 1;
+            remove_char(buf, view, view.cursor);
+        }
+    }
+    insert_char(buf, view, cha, view.cursor);
+    // Quotes after a letter are more likely apostrophes or closing quotes
+    let after_word = prev.is_some_and(|c| c.is_alphanumeric());
+    if let Some(closer) = closer_of(cha).filter(|_| autopairs) {
+        if !(is_quote(cha) && after_word) {
+            insert_char(buf, view, closer, view.cursor);
+            view.cursor.1 -= 1;
+        }
+    }
+}
+
+// The indentation of a line opened above or below the given one with O or o
+fn new_line_indent(buf: &Buffer, row: usize, below: bool) -> String32 {
+    let config = CONFIG.read().unwrap();
+    let line = &buf.contents[row - 1];
+    if !config.autoindent && !config.smartindent {
+        return Vec::new();
+    }
+    let mut indent = leading_whitespace(line);
+    if below && config.smartindent && opens_block(line, &buf.path) {
+        indent.extend(indent_unit(line, config.tab_width as usize));
+    }
+    indent
+}
+
+fn selected_bounds(view: &View) -> (Coord, Coord) {
+    if view.selection_start.0 < view.cursor.0
+        || (view.selection_start.0 == view.cursor.0 && view.selection_start.1 <= view.cursor.1)
     {
-        (buf.selection_start, buf.cursor)
+        (view.selection_start, view.cursor)
     } else {
-        (buf.cursor, buf.selection_start)
+        (view.cursor, view.selection_start)
     }
 }
 
-fn get_selected_text(buf: &Buffer) -> Vec<String32> {
-    let (start, stop) = selected_bounds(buf);
+fn get_selected_text(buf: &Buffer, view: &View) -> Vec<String32> {
+    let (start, stop) = selected_bounds(view);
     if buf.mode == Mode::VisualLine {
         get_lines(buf, start.0, stop.0)
     } else {
@@ -440,8 +2023,8 @@ fn get_lines(buf: &Buffer, start: usize, stop: usize) -> Vec<String32> {
     return lines;
 }
 
-fn yank_selected(buf: &mut Buffer) {
-    buf.clip = get_selected_text(buf);
+fn yank_selected(buf: &mut Buffer, view: &View) {
+    buf.clip = get_selected_text(buf, view);
     buf.clip_lines = buf.mode == Mode::VisualLine;
     if CONFIG.read().unwrap().clipboard {
         let opts = Options::new();
@@ -459,7 +2042,7 @@ fn remove_text(buf: &mut Buffer, start: Coord, stop: Coord) {
         stop,
         text: get_text(buf, start, stop),
     };
-    buf.history.add_node(action);
+    buf.add_action(action);
     let last_line = &buf.contents[stop.0 - 1];
     let mut extra = 0;
     let mut tail = if stop.1 > last_line.len() {
@@ -485,7 +2068,7 @@ fn remove_lines(buf: &mut Buffer, start: usize, stop: usize) {
         stop,
         lines: get_lines(buf, start, stop),
     };
-    buf.history.add_node(action);
+    buf.add_action(action);
 
     for _ in start..=stop {
         buf.contents.remove(start - 1);
@@ -495,51 +2078,385 @@ fn remove_lines(buf: &mut Buffer, start: usize, stop: usize) {
     }
 }
 
-fn remove_char(buf: &mut Buffer, pos: Coord) {
-    let action;
-    if pos.1 <= buf.contents[pos.0 - 1].len() {
-        action = TextAction::RemoveChar {
-            pos,
-            cha: buf.contents[pos.0 - 1][pos.1 - 1],
+fn remove_char(buf: &mut Buffer, view: &mut View, pos: Coord) {
+    let action;
+    if pos.1 <= buf.contents[pos.0 - 1].len() {
+        action = TextAction::RemoveChar {
+            pos,
+            cha: buf.contents[pos.0 - 1][pos.1 - 1],
+        };
+
+        buf.contents[pos.0 - 1].remove(pos.1 - 1);
+    } else {
+        action = TextAction::RemoveChar { pos, cha: '\n' };
+
+        let mut tail = buf.contents.remove(pos.0);
+        buf.contents[pos.0 - 1].append(&mut tail);
+    }
+    view.cursor = pos;
+    buf.add_action(action);
+}
+
+fn remove_selected(buf: &mut Buffer, view: &mut View) {
+    let (start, stop) = selected_bounds(view);
+    if buf.mode == Mode::VisualLine {
+        remove_lines(buf, start.0, stop.0);
+        view.cursor.0 = start.0;
+    } else {
+        remove_text(buf, start, stop);
+        view.cursor = start;
+    }
+    view.cursor_col_goal = view.cursor.1;
+}
+
+fn replace_selected(buf: &mut Buffer, view: &mut View, text: &mut Vec<String32>) {
+    let (start, _) = selected_bounds(view);
+    let text_len = text.len();
+    remove_selected(buf, view);
+    if buf.mode == Mode::Visual {
+        insert_text(buf, view, text, start);
+    } else {
+        insert_lines(buf, text, start.0)
+    }
+    view.cursor.0 = start.0 + text_len - 1;
+    view.cursor.1 = max(1, buf.contents[view.cursor.0 - 1].len());
+}
+
+// Replaces whole lines as a single undo step
+fn replace_lines(
+    buf: &mut Buffer,
+    start: usize,
+    stop: usize,
+    mut lines: Vec<String32>,
+    name: String,
+) {
+    buf.history.start_record();
+    // Inserting first keeps the buffer from ever being empty in between
+    let count = lines.len();
+    if count > 0 {
+        insert_lines(buf, &mut lines, start);
+    }
+    remove_lines(buf, start + count, stop + count);
+    buf.history.stop_record_named(name);
+}
+
+// The lines an operator applies to, for the motion typed after it. Repeating the key of the
+// operator means the current line.
+fn motion_lines(
+    motion: &[Key],
+    operator: Key,
+    count: usize,
+    buf: &Buffer,
+    cursor: Coord,
+) -> Option<(usize, usize)> {
+    let (row, last) = (cursor.0, buf.contents.len());
+    let (start, stop) = match motion {
+        [key] | [Key::Char('g'), key] if *key == operator => (row, row + count - 1),
+        [Key::Char('j')] | [Key::Down] => (row, row + count),
+        [Key::Char('k')] | [Key::Up] => (row.saturating_sub(count).max(1), row),
+        [Key::Char('G')] => (row, last),
+        [Key::Char('g'), Key::Char('g')] => (1, row),
+        [Key::Char('%')] => {
+            let target = match_of(buf, cursor)?.0;
+            (min(row, target), max(row, target))
+        }
+        _ => return None,
+    };
+    Some((start, stop.min(last)))
+}
+
+// Where % goes from the position, with the pairs from the config
+fn match_of(buf: &Buffer, pos: Coord) -> Option<Coord> {
+    let pairs = parse_match_pairs(&CONFIG.read().unwrap().match_pairs);
+    match_target(&buf.contents, pos, &pairs)
+}
+
+fn describe_lines(start: usize, stop: usize) -> String {
+    if start == stop {
+        format!("line {start}")
+    } else {
+        format!("lines {start} to {stop}")
+    }
+}
+
+// Replaces the characters of a line from the start to the stop column
+fn replace_in_line(buf: &mut Buffer, start: Coord, stop: usize, text: String32) {
+    if stop >= start.1 {
+        remove_text(buf, start, (start.0, stop));
+    }
+    if !text.is_empty() {
+        let mut scratch = View::new(0);
+        scratch.cursor = start;
+        insert_text(buf, &mut scratch, &mut vec![text], start);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Case {
+    Upper,
+    Lower,
+    Toggle,
+}
+
+fn convert_case(text: &[char], case: Case) -> String32 {
+    let mut converted = Vec::new();
+    for c in text {
+        match case {
+            Case::Upper => converted.extend(c.to_uppercase()),
+            Case::Lower => converted.extend(c.to_lowercase()),
+            Case::Toggle if c.is_uppercase() => converted.extend(c.to_lowercase()),
+            Case::Toggle => converted.extend(c.to_uppercase()),
+        }
+    }
+    converted
+}
+
+// Changes the case of the text from start to stop as one undo step
+fn change_case(buf: &mut Buffer, start: Coord, stop: Coord, case: Case) {
+    buf.history.start_record();
+    for row in start.0..=stop.0 {
+        let line = &buf.contents[row - 1];
+        let first = if row == start.0 { start.1 } else { 1 };
+        let last = if row == stop.0 {
+            stop.1.min(line.len())
+        } else {
+            line.len()
+        };
+        if first > last {
+            continue;
+        }
+        let new = convert_case(&line[first - 1..last], case);
+        if new[..] != line[first - 1..last] {
+            replace_in_line(buf, (row, first), last, new);
+        }
+    }
+    let name = match case {
+        Case::Upper => "Uppercase",
+        Case::Lower => "Lowercase",
+        Case::Toggle => "Toggle case of",
+    };
+    buf.history
+        .stop_record_named(format!("{name} {}", describe_lines(start.0, stop.0)));
+}
+
+// Adds to the first number at or after the column (from 0) of a line. Returns the column of
+// its last character.
+fn increment_number(buf: &mut Buffer, row: usize, col: usize, delta: i64) -> Option<usize> {
+    let (start, end, text) = increment(&buf.contents[row - 1], col, delta)?;
+    let len = text.len();
+    replace_in_line(buf, (row, start + 1), end, text);
+    Some(start + len)
+}
+
+// Ctrl-A and Ctrl-X on the selected lines. With g, each line gets one step more than the last.
+fn increment_selected(buf: &mut Buffer, view: &mut View, delta: i64, progressive: bool) {
+    let (start, stop) = selected_bounds(view);
+    let linewise = buf.mode == Mode::VisualLine;
+    buf.set_mode(view, Mode::Normal);
+    buf.history.start_record();
+    let mut step = delta;
+    for row in start.0..=stop.0 {
+        let col = if row == start.0 && !linewise {
+            start.1 - 1
+        } else {
+            0
+        };
+        let line = &buf.contents[row - 1];
+        // In a character selection, the number must start before its end
+        let inside = increment(line, col, delta)
+            .is_some_and(|(first, _, _)| linewise || row < stop.0 || first < stop.1);
+        if inside && increment_number(buf, row, col, step).is_some() && progressive {
+            step += delta;
+        }
+    }
+    let name = if delta < 0 { "Decrement" } else { "Increment" };
+    buf.history.stop_record_named(format!(
+        "{name} numbers on {}",
+        describe_lines(start.0, stop.0)
+    ));
+    view.cursor = start;
+    view.cursor_col_goal = start.1;
+    update_cursor(buf, view);
+}
+
+// Joins count lines from the cursor. J puts a space between them where one is needed, gJ
+// leaves the whitespace as it is.
+fn join_lines(buf: &mut Buffer, view: &mut View, count: usize, spaces: bool) {
+    let row = view.cursor.0;
+    let stop = min(row + count.max(2) - 1, buf.contents.len());
+    if stop == row {
+        return;
+    }
+    buf.history.start_record();
+    for _ in row..stop {
+        let line = &buf.contents[row - 1];
+        let end = line.len() + 1;
+        if !spaces {
+            remove_text(buf, (row, end), (row, end));
+            view.cursor.1 = end;
+            continue;
+        }
+        let next = &buf.contents[row];
+        let indent = next.iter().take_while(|c| c.is_whitespace()).count();
+        let space = !line.is_empty()
+            && !line.last().is_some_and(|c| c.is_whitespace())
+            && indent < next.len()
+            && next[indent] != ')';
+        let stop = if indent == 0 {
+            (row, end)
+        } else {
+            (row + 1, indent)
+        };
+        remove_text(buf, (row, end), stop);
+        if space {
+            replace_in_line(buf, (row, end), end - 1, vec![' ']);
+        }
+        view.cursor.1 = end;
+    }
+    buf.history
+        .stop_record_named(format!("Join {}", describe_lines(row, stop)));
+    view.cursor_col_goal = view.cursor.1;
+    update_cursor(buf, view);
+}
+
+// Comments out whole lines, or uncomments them if they all are commented
+fn comment_lines(buf: &mut Buffer, view: &mut View, start: usize, stop: usize) {
+    let extension = std::path::Path::new(&buf.path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let commentstring = CONFIG
+        .read()
+        .unwrap()
+        .comments
+        .get(&extension)
+        .cloned()
+        .unwrap_or("# %s".to_owned());
+    let lines = get_lines(buf, start, stop);
+    let toggled = toggle_comments(&lines, &commentstring);
+    if toggled != lines {
+        let name = if start == stop {
+            format!("Toggle comment on line {start}")
+        } else {
+            format!("Toggle comments on lines {start} to {stop}")
         };
+        replace_lines(buf, start, stop, toggled, name);
+    }
+    update_cursor(buf, view);
+}
 
-        buf.contents[pos.0 - 1].remove(pos.1 - 1);
+fn filter_timeout() -> Duration {
+    Duration::from_secs(CONFIG.read().unwrap().filter_timeout)
+}
+
+// Pipes lines through a shell command and replaces them with its output. Returns what
+// the command wrote to stderr.
+fn filter_lines(
+    buf: &mut Buffer,
+    view: &mut View,
+    start: usize,
+    stop: usize,
+    command: &str,
+) -> Result<Option<String>, String> {
+    let mut input = concat_lines(&get_lines(buf, start, stop));
+    input.push('\n');
+    let output = run_filter(command, &input, filter_timeout())?;
+    if !output.success {
+        return Err(if output.stderr.is_empty() {
+            format!("{command}: command failed")
+        } else {
+            output.stderr
+        });
+    }
+    let mut lines = split_text(&output.stdout);
+    if output.stdout.ends_with('\n') {
+        lines.pop();
+    }
+    let name = if start == stop {
+        format!("Filter line {start} through {command}")
     } else {
-        action = TextAction::RemoveChar { pos, cha: '\n' };
+        format!("Filter lines {start} to {stop} through {command}")
+    };
+    replace_lines(buf, start, stop, lines, name);
+    view.cursor = (min(start, buf.contents.len()), 1);
+    view.cursor_col_goal = 1;
+    update_cursor(buf, view);
+    Ok(Some(output.stderr).filter(|err| !err.is_empty()))
+}
 
-        let mut tail = buf.contents.remove(pos.0);
-        buf.contents[pos.0 - 1].append(&mut tail);
+// Replaces a range of lines with a rearranged copy, as one undo step named like "Sort lines
+// 10–40". Nothing is recorded if the lines stay the same.
+fn rearrange_lines(
+    buf: &mut Buffer,
+    view: &mut View,
+    start: usize,
+    stop: usize,
+    action: &str,
+    rearrange: impl FnOnce(&[String32]) -> Vec<String32>,
+) {
+    let lines = get_lines(buf, start, stop);
+    let new_lines = rearrange(&lines);
+    if new_lines != lines {
+        let name = if start == stop {
+            format!("{action} line {start}")
+        } else {
+            format!("{action} lines {start}–{stop}")
+        };
+        replace_lines(buf, start, stop, new_lines, name);
     }
-    buf.cursor = pos;
-    buf.history.add_node(action);
+    view.cursor = (start, 1);
+    view.cursor_col_goal = 1;
+    update_cursor(buf, view);
 }
 
-fn remove_selected(buf: &mut Buffer) {
-    let (start, stop) = selected_bounds(buf);
+fn filter_selected(
+    buf: &mut Buffer,
+    view: &mut View,
+    command: &str,
+) -> Result<Option<String>, String> {
+    let mut input = concat_lines(&get_selected_text(buf, view));
     if buf.mode == Mode::VisualLine {
-        remove_lines(buf, start.0, stop.0);
-        buf.cursor.0 = start.0;
-    } else {
-        remove_text(buf, start, stop);
-        buf.cursor = start;
+        input.push('\n');
+    }
+    let output = run_filter(command, &input, filter_timeout())?;
+    if !output.success {
+        return Err(if output.stderr.is_empty() {
+            format!("{command}: command failed")
+        } else {
+            output.stderr
+        });
     }
-    buf.cursor_col_goal = buf.cursor.1;
+    let stdout = output.stdout.strip_suffix('\n').unwrap_or(&output.stdout);
+    let (start, _) = selected_bounds(view);
+    buf.history.start_record();
+    replace_selected(buf, view, &mut split_text(stdout));
+    buf.history.stop_record_named(format!(
+        "Filter selection at line {} through {command}",
+        start.0
+    ));
+    Ok(Some(output.stderr).filter(|err| !err.is_empty()))
 }
 
-fn replace_selected(buf: &mut Buffer, text: &mut Vec<String32>) {
-    let (start, _) = selected_bounds(buf);
-    let text_len = text.len();
-    remove_selected(buf);
-    if buf.mode == Mode::Visual {
-        insert_text(buf, text, start);
-    } else {
-        insert_lines(buf, text, start.0)
+// Evaluates the selected line with the buffer's evaluator, replacing it with the result
+fn evaluate_selected(buf: &mut Buffer, view: &mut View) -> Result<Option<String>, String> {
+    let text = concat_lines(&get_selected_text(buf, view));
+    if buf.evaluator.is_none() {
+        buf.evaluator = Some(new_evaluator()?);
+    }
+    match buf.evaluator.as_mut().unwrap().evaluate(&text)? {
+        Evaluation::Value(value) => {
+            let (start, _) = selected_bounds(view);
+            buf.history.start_record();
+            replace_selected(buf, view, &mut split_text(&value));
+            buf.history
+                .stop_record_named(format!("Evaluate selection at line {}", start.0));
+            Ok(None)
+        }
+        Evaluation::Binding(binding) => Ok(Some(binding)),
     }
-    buf.cursor.0 = start.0 + text_len - 1;
-    buf.cursor.1 = max(1, buf.contents[buf.cursor.0 - 1].len());
 }
 
-fn paste_clip(buf: &mut Buffer, after: bool) {
+fn paste_clip(buf: &mut Buffer, view: &mut View, after: bool) {
     if CONFIG.read().unwrap().clipboard {
         let result = get_contents(
             ClipboardType::Regular,
@@ -561,23 +2478,23 @@ fn paste_clip(buf: &mut Buffer, after: bool) {
 
     let mut text = buf.clip.clone();
     if buf.clip_lines {
-        let row = buf.cursor.0 + if after { 1 } else { 0 };
-        buf.cursor.0 += text.len();
+        let row = view.cursor.0 + if after { 1 } else { 0 };
+        view.cursor.0 += text.len();
         insert_lines(buf, &mut text, row);
     } else {
-        let col = buf.cursor.1
-            + if after && buf.contents[buf.cursor.0 - 1].len() != 0 {
+        let col = view.cursor.1
+            + if after && buf.contents[view.cursor.0 - 1].len() != 0 {
                 1
             } else {
                 0
             };
-        insert_text(buf, &mut text, (buf.cursor.0, col));
+        insert_text(buf, view, &mut text, (view.cursor.0, col));
     }
-    buf.cursor_col_goal = buf.cursor.1;
+    view.cursor_col_goal = view.cursor.1;
 }
 
-fn insert_text(buf: &mut Buffer, text: &mut Vec<String32>, pos: Coord) {
-    buf.cursor.0 += text.len() - 1;
+fn insert_text(buf: &mut Buffer, view: &mut View, text: &mut Vec<String32>, pos: Coord) {
+    view.cursor.0 += text.len() - 1;
     let history_text = text.clone();
     let first = buf.contents[pos.0 - 1][..pos.1 - 1].to_owned();
     let mut last = buf.contents[pos.0 - 1][pos.1 - 1..].to_owned();
@@ -585,12 +2502,12 @@ fn insert_text(buf: &mut Buffer, text: &mut Vec<String32>, pos: Coord) {
 
     if text.len() == 1 {
         let mut line = first;
-        buf.cursor.1 += text[0].len();
+        view.cursor.1 += text[0].len();
         line.append(&mut text[0]);
         line.append(&mut last);
         buf.contents[pos.0 - 1] = line;
     } else {
-        buf.cursor.1 = max(1, text[text.len() - 1].len());
+        view.cursor.1 = max(1, text[text.len() - 1].len());
         buf.contents[pos.0 - 1] = first;
         buf.contents[pos.0 - 1].append(&mut text.remove(0));
         for i in 1..text.len() + 1 {
@@ -598,12 +2515,13 @@ fn insert_text(buf: &mut Buffer, text: &mut Vec<String32>, pos: Coord) {
         }
         buf.contents[last_row].append(&mut last);
     }
+    // Where the cursor ends up depends on the caller, so the end comes from the text
     let action = TextAction::Insert {
         start: pos,
-        stop: buf.cursor,
+        stop: text_stop(pos, &history_text),
         text: history_text,
     };
-    buf.history.add_node(action);
+    buf.add_action(action);
 }
 
 fn insert_lines(buf: &mut Buffer, lines: &mut Vec<String32>, row: usize) {
@@ -612,28 +2530,28 @@ fn insert_lines(buf: &mut Buffer, lines: &mut Vec<String32>, row: usize) {
         stop: row + lines.len() - 1,
         lines: lines.clone(),
     };
-    buf.history.add_node(action);
+    buf.add_action(action);
     for _ in 0..lines.len() {
         buf.contents.insert(row - 1, lines.pop().unwrap());
     }
 }
 
-fn insert_char(buf: &mut Buffer, cha: char, pos: Coord) {
+fn insert_char(buf: &mut Buffer, view: &mut View, cha: char, pos: Coord) {
     let action = TextAction::InsertChar { pos, cha };
-    buf.history.add_node(action);
+    buf.add_action(action);
     if cha == '\n' {
         let line = &buf.contents[pos.0 - 1];
         let tail = (&line[pos.1 - 1..]).to_owned();
         buf.contents[pos.0 - 1].truncate(pos.1 - 1);
         buf.contents.insert(pos.0, tail);
-        buf.cursor = (buf.cursor.0 + 1, 1);
+        view.cursor = (view.cursor.0 + 1, 1);
     } else {
         buf.contents[pos.0 - 1].insert(pos.1 - 1, cha);
-        buf.cursor.1 += 1;
+        view.cursor.1 += 1;
     }
 }
 
-fn do_text_action(buf: &mut Buffer, action: TextAction) {
+fn do_text_action(buf: &mut Buffer, view: &mut View, action: TextAction) {
     use TextAction::*;
     buf.history.locked = true;
     match action {
@@ -642,7 +2560,7 @@ fn do_text_action(buf: &mut Buffer, action: TextAction) {
             start,
             stop: _,
             text,
-        } => insert_text(buf, &mut text.clone(), start),
+        } => insert_text(buf, view, &mut text.clone(), start),
 
         Remove {
             start,
@@ -659,89 +2577,80 @@ fn do_text_action(buf: &mut Buffer, action: TextAction) {
             stop,
             lines: _,
         } => remove_lines(buf, start, stop),
-        InsertChar { pos, cha } => insert_char(buf, cha, pos),
-        RemoveChar { pos, cha: _ } => remove_char(buf, pos),
+        InsertChar { pos, cha } => insert_char(buf, view, cha, pos),
+        RemoveChar { pos, cha: _ } => remove_char(buf, view, pos),
         Composite { actions, name: _ } => {
             for action in actions {
-                do_text_action(buf, action)
+                do_text_action(buf, view, action)
             }
         }
     }
     buf.history.locked = false;
 }
 
-fn handle_event(buf: &mut Buffer, term: &Terminal, evt: Event) -> bool {
-    if let Event::Mouse(mevt) = evt {
-        handle_mouse_event(buf, term, mevt);
-        return false;
-    }
+fn handle_event(buf: &mut Buffer, view: &mut View, evt: Event, count: usize) -> bool {
     if evt == Event::Key(Key::Esc) {
-        buf.set_mode(Mode::Normal);
+        buf.set_mode(view, Mode::Normal);
         return false;
     }
-    if buf.mode == Mode::Insert {
+    if matches!(buf.mode, Mode::Insert | Mode::Replace) {
         if let Event::Key(key) = evt {
             match key {
-                Key::Up => up(buf, term, 1),
-                Key::Down => down(buf, term, 1),
-                Key::Left => left(buf, term, 1),
-                Key::Right => right(buf, term, 1),
+                Key::Up => up(buf, view, 1),
+                Key::Down => down(buf, view, 1),
+                Key::Left => left(buf, view, 1),
+                Key::Right => right(buf, view, 1),
+                _ if buf.mode == Mode::Replace => {
+                    replace_key(buf, view, key);
+                    update_scroll(view);
+                }
                 _ => {
-                    insert_key(buf, key);
-                    update_scroll(buf, term);
+                    insert_key(buf, view, key);
+                    update_scroll(view);
                 }
             }
         }
         return false;
     } else if buf.mode == Mode::Visual || buf.mode == Mode::VisualLine {
         match evt {
-            Event::Key(Key::Char('h')) => left(buf, term, 1),
-            Event::Key(Key::Char('l')) => right(buf, term, 1),
-            Event::Key(Key::Char('k')) => up(buf, term, 1),
-            Event::Key(Key::Char('j')) => down(buf, term, 1),
+            Event::Key(Key::Char('h')) => left(buf, view, 1),
+            Event::Key(Key::Char('l')) => right(buf, view, 1),
+            Event::Key(Key::Char('k')) => up(buf, view, 1),
+            Event::Key(Key::Char('j')) => down(buf, view, 1),
+            Event::Key(Key::Char('%')) => {
+                if let Some(pos) = match_of(buf, view.cursor) {
+                    view.cursor = pos;
+                    view.cursor_col_goal = pos.1;
+                    update_cursor(buf, view);
+                }
+            }
             Event::Key(Key::Char('y')) => {
-                yank_selected(buf);
-                buf.set_mode(Mode::Normal);
+                yank_selected(buf, view);
+                buf.set_mode(view, Mode::Normal);
+            }
+            Event::Key(Key::Char(c @ ('~' | 'u' | 'U'))) => {
+                let (start, stop) = selected_bounds(view);
+                let (start, stop) = if buf.mode == Mode::VisualLine {
+                    ((start.0, 1), (stop.0, usize::MAX))
+                } else {
+                    (start, stop)
+                };
+                let case = match c {
+                    'u' => Case::Lower,
+                    'U' => Case::Upper,
+                    _ => Case::Toggle,
+                };
+                buf.set_mode(view, Mode::Normal);
+                change_case(buf, start, stop, case);
+                view.cursor = start;
+                view.cursor_col_goal = start.1;
+                update_cursor(buf, view);
             }
             Event::Key(Key::Char('d')) => {
-                yank_selected(buf);
-                remove_selected(buf);
-                buf.set_mode(Mode::Normal);
-                update_cursor(buf, term);
-            }
-            #[cfg(feature = "talculia")]
-            Event::Key(Key::Char('c')) => {
-                if buf.cursor.0 == buf.selection_start.0 {
-                    let text = concat_lines(&get_selected_text(buf));
-                    let context = talculia::Context::default();
-                    let parsed = talculia::parse(&talculia::preparse(text)).unwrap();
-                    let result = parsed.evaluate(&context);
-                    let new_text = format!("{}", result);
-                    let mut splitted = split_text(&new_text);
-                    replace_selected(buf, &mut splitted);
-                    buf.set_mode(Mode::Normal);
-                    update_scroll(buf, term);
-                }
-            }
-            Event::Key(Key::Char('e')) => {
-                if buf.cursor.0 == buf.selection_start.0 {
-                    let text = concat_lines(&get_selected_text(buf));
-                    let python = Command::new("python")
-                        .arg("-c")
-                        .arg(format!("print({})", text))
-                        .stdout(Stdio::piped())
-                        .output()
-                        .unwrap();
-                    if !python.status.success() {
-                        return false;
-                    }
-                    let mut new_text = std::str::from_utf8(&python.stdout).unwrap();
-                    new_text = &new_text[..new_text.len() - 1];
-                    let mut splitted = split_text(&new_text);
-                    replace_selected(buf, &mut splitted);
-                    buf.set_mode(Mode::Normal);
-                    update_scroll(buf, term);
-                }
+                yank_selected(buf, view);
+                remove_selected(buf, view);
+                buf.set_mode(view, Mode::Normal);
+                update_cursor(buf, view);
             }
             _ => (),
         }
@@ -749,67 +2658,69 @@ fn handle_event(buf: &mut Buffer, term: &Terminal, evt: Event) -> bool {
     }
     match evt {
         Event::Key(Key::Char('q')) => return true,
-        Event::Key(Key::Char('i')) => buf.set_mode(Mode::Insert),
+        Event::Key(Key::Char('i')) => buf.set_mode(view, Mode::Insert),
+        Event::Key(Key::Char('R')) => buf.set_mode(view, Mode::Replace),
         Event::Key(Key::Char('a')) => {
-            buf.set_mode(Mode::Insert);
-            right(buf, term, 1)
+            buf.set_mode(view, Mode::Insert);
+            right(buf, view, 1)
         }
         Event::Key(Key::Char('o')) => {
-            buf.contents.insert(buf.cursor.0, String32::new());
-            buf.cursor = (buf.cursor.0 + 1, 1);
-            buf.set_mode(Mode::Insert);
+            buf.set_mode(view, Mode::Insert);
+            let indent = new_line_indent(buf, view.cursor.0, true);
+            let col = indent.len() + 1;
+            insert_lines(buf, &mut vec![indent], view.cursor.0 + 1);
+            view.cursor = (view.cursor.0 + 1, col);
         }
         Event::Key(Key::Char('O')) => {
-            buf.contents.insert(buf.cursor.0 - 1, String32::new());
-            buf.cursor = (buf.cursor.0, 1);
-            buf.set_mode(Mode::Insert);
-        }
-        Event::Key(Key::Char('v')) => buf.set_mode(Mode::Visual),
-        Event::Key(Key::Char('V')) => buf.set_mode(Mode::VisualLine),
-        Event::Key(Key::Char('H')) => {
-            buf.show_history = !buf.show_history;
+            buf.set_mode(view, Mode::Insert);
+            let indent = new_line_indent(buf, view.cursor.0, false);
+            let col = indent.len() + 1;
+            insert_lines(buf, &mut vec![indent], view.cursor.0);
+            view.cursor = (view.cursor.0, col);
         }
-        Event::Key(Key::Char('h')) => left(buf, term, 1),
-        Event::Key(Key::Char('l')) => right(buf, term, 1),
-        Event::Key(Key::Char('k')) => up(buf, term, 1),
-        Event::Key(Key::Char('j')) => down(buf, term, 1),
+        Event::Key(Key::Char('v')) => buf.set_mode(view, Mode::Visual),
+        Event::Key(Key::Char('V')) => buf.set_mode(view, Mode::VisualLine),
+        Event::Key(Key::Char('h')) => left(buf, view, count),
+        Event::Key(Key::Char('l')) => right(buf, view, count),
+        Event::Key(Key::Char('k')) => up(buf, view, count),
+        Event::Key(Key::Char('j')) => down(buf, view, count),
         Event::Key(Key::Char('x')) => {
-            if buf.contents[buf.cursor.0 - 1].len() != 0 {
-                remove_char(buf, buf.cursor);
+            if buf.contents[view.cursor.0 - 1].len() != 0 {
+                remove_char(buf, view, view.cursor);
             }
         }
         Event::Key(Key::Char('d')) => {
-            buf.set_mode(Mode::VisualLine);
-            yank_selected(buf);
-            remove_selected(buf);
-            buf.set_mode(Mode::Normal);
-            update_cursor(buf, term);
+            buf.set_mode(view, Mode::VisualLine);
+            yank_selected(buf, view);
+            remove_selected(buf, view);
+            buf.set_mode(view, Mode::Normal);
+            update_cursor(buf, view);
         }
         Event::Key(Key::Char('y')) => {
-            buf.set_mode(Mode::VisualLine);
-            yank_selected(buf);
-            buf.set_mode(Mode::Normal);
+            buf.set_mode(view, Mode::VisualLine);
+            yank_selected(buf, view);
+            buf.set_mode(view, Mode::Normal);
         }
         Event::Key(Key::Char('p')) => {
-            paste_clip(buf, true);
-            update_cursor(buf, term);
+            paste_clip(buf, view, true);
+            update_cursor(buf, view);
         }
         Event::Key(Key::Char('P')) => {
-            paste_clip(buf, false);
-            update_cursor(buf, term);
+            paste_clip(buf, view, false);
+            update_cursor(buf, view);
         }
         Event::Key(Key::Char('u')) => {
             let result = buf.history.undo();
             if let Some(action) = result {
-                do_text_action(buf, action);
-                update_cursor(buf, term)
+                do_text_action(buf, view, action);
+                restore_cursor(buf, view)
             }
         }
-        Event::Key(Key::Char('r')) => {
+        Event::Key(Key::Ctrl('r')) | Event::Key(Key::Char('r')) => {
             let result = buf.history.redo();
             if let Some(action) = result {
-                do_text_action(buf, action);
-                update_cursor(buf, term)
+                do_text_action(buf, view, action);
+                restore_cursor(buf, view)
             }
         }
 
@@ -818,90 +2729,697 @@ fn handle_event(buf: &mut Buffer, term: &Terminal, evt: Event) -> bool {
     return false;
 }
 
-fn handle_mouse_event(buf: &mut Buffer, term: &Terminal, mevt: MouseEvent) {
+fn handle_mouse_event(process: &mut Process, term: &Terminal, mevt: MouseEvent) {
     match mevt {
         MouseEvent::Press(MouseButton::Left, mcol, mrow) => {
-            let mut hcol = mcol;
-            if buf.show_history {
-                if mcol <= 39 {
-                    let row = mrow as usize + buf.history.scroll - 1;
-                    let result = buf.history.goto_row(row);
+            let pos = (mrow as usize, mcol as usize);
+            if pos.0 == 1 {
+                return;
+            }
+            if process.quickfix_shown(term) && pos.0 >= process.quickfix_area(term).top {
+                let index = process.quickfix.scroll + pos.0 - process.quickfix_area(term).top;
+                if let Err(msg) = process.goto_quickfix(index) {
+                    process.message = Some(msg);
+                }
+                process.quickfix.focused = false;
+                return;
+            }
+            let (buf, view) = process.get_active();
+            if buf.show_history && pos.1 <= 39 {
+                let row = pos.0 + buf.history.scroll - 2;
+                let result = buf.history.goto_row(row);
 
-                    buf.set_mode(Mode::Normal); // Done after retrieving actions since exiting
-                                                // Insert mode may change undo tree
+                buf.set_mode(view, Mode::Normal); // Done after retrieving actions since exiting
+                                                  // Insert mode may change undo tree
 
-                    if let Some(actions) = result {
-                        for action in actions {
-                            do_text_action(buf, action);
-                        }
+                if let Some(actions) = result {
+                    for action in actions {
+                        do_text_action(buf, view, action);
                     }
-                    update_cursor(buf, term);
-                    return;
                 }
-                hcol -= 39;
+                restore_cursor(buf, view);
+                return;
             }
+            let Some(index) = process.view_at(pos) else {
+                return;
+            };
+            process.push_jump();
+            process.focus_view(index);
+            let (buf, view) = process.get_active();
             if buf.mode == Mode::Visual {
-                buf.set_mode(Mode::Normal);
+                buf.set_mode(view, Mode::Normal);
             }
             buf.history.snip_record();
-            let col = if hcol <= 6 {
+            let (rel_row, rel_col) = view.rect.to_relative(pos);
+            let col = if rel_col < 6 {
                 1
             } else {
-                hcol as usize + buf.scroll.1 - 7
+                rel_col + view.scroll.1 - 6
             };
-            let row = mrow as usize + buf.scroll.0 - 1;
-            buf.cursor = (row, col);
-            buf.cursor_col_goal = col;
-            update_cursor(buf, term)
+            let row = rel_row + view.scroll.0;
+            view.cursor = (row, col);
+            view.cursor_col_goal = col;
+            update_cursor(buf, view)
         }
-        MouseEvent::Press(MouseButton::WheelUp, _, _) => {
-            buf.scroll.0 = max(4, buf.scroll.0) - 3;
-            if buf.cursor.0 > buf.scroll.0 + term.rows() - 12 {
-                buf.cursor.0 = buf.scroll.0 + term.rows() - 12;
+        MouseEvent::Press(MouseButton::WheelUp, mcol, mrow) => {
+            let index = process
+                .view_at((mrow as usize, mcol as usize))
+                .unwrap_or(process.active_view);
+            let view = &mut process.views[index];
+            let buf = &mut process.buffers[view.buffer];
+            view.scroll.0 = max(4, view.scroll.0) - 3;
+            let bottom = view.scroll.0 + view.rect.height() - view.margin();
+            if view.cursor.0 > bottom {
+                view.cursor.0 = bottom;
             }
-            update_cursor(buf, term);
+            update_cursor(buf, view);
             if buf.history.scroll > 1 {
                 buf.history.scroll -= 1;
             }
         }
-        MouseEvent::Press(MouseButton::WheelDown, _, _) => {
-            buf.scroll.0 = min(buf.contents.len(), buf.scroll.0 + 3);
-            if buf.cursor.0 < 10 + buf.scroll.0 {
-                buf.cursor.0 = 10 + buf.scroll.0;
+        MouseEvent::Press(MouseButton::WheelDown, mcol, mrow) => {
+            let index = process
+                .view_at((mrow as usize, mcol as usize))
+                .unwrap_or(process.active_view);
+            let view = &mut process.views[index];
+            let buf = &mut process.buffers[view.buffer];
+            view.scroll.0 = min(buf.contents.len(), view.scroll.0 + 3);
+            if view.cursor.0 < view.margin() + view.scroll.0 {
+                view.cursor.0 = view.margin() + view.scroll.0;
             }
-            update_cursor(buf, term);
+            update_cursor(buf, view);
             buf.history.scroll += 1;
         }
         MouseEvent::Press(MouseButton::WheelLeft, _, _) => {
-            buf.scroll.0 = min(buf.contents.len(), buf.scroll.0 + 3);
-            if buf.cursor.0 < 10 + buf.scroll.0 {
-                buf.cursor.0 = 10 + buf.scroll.0;
+            let (buf, view) = process.get_active();
+            view.scroll.0 = min(buf.contents.len(), view.scroll.0 + 3);
+            if view.cursor.0 < view.margin() + view.scroll.0 {
+                view.cursor.0 = view.margin() + view.scroll.0;
             }
-            update_cursor(buf, term);
+            update_cursor(buf, view);
         }
         MouseEvent::Hold(mcol, mrow) => {
-            buf.set_mode(Mode::Visual);
-
-            let mut col = if mcol <= 6 {
+            let (buf, view) = process.get_active();
+            buf.set_mode(view, Mode::Visual);
+
+            let rect = &view.rect;
+            let pos = (
+                (mrow as usize).clamp(rect.top, rect.bottom),
+                (mcol as usize).clamp(rect.left, rect.right),
+            );
+            let (rel_row, rel_col) = rect.to_relative(pos);
+            let col = if rel_col < 6 {
                 1
             } else {
-                mcol as usize + buf.scroll.1 - 7
+                rel_col + view.scroll.1 - 6
             };
-            if buf.show_history {
-                if col <= 40 {
-                    return;
+            let row = rel_row + view.scroll.0;
+
+            view.cursor = (row, col);
+            view.cursor_col_goal = col;
+            update_cursor(buf, view)
+        }
+
+        _ => (),
+    }
+}
+
+// Handles the keys that concern the whole editor rather than a single buffer
+fn handle_process_event(
+    process: &mut Process,
+    term: &Terminal,
+    evt: Event,
+) -> std::io::Result<bool> {
+    let key = match evt {
+        Event::Mouse(mevt) => {
+            handle_mouse_event(process, term, mevt);
+            return Ok(false);
+        }
+        Event::Key(key) => key,
+        _ => return Ok(false),
+    };
+    process.message = None;
+    process.popup = None;
+
+    if !process.recovery.is_empty() {
+        process.answer_recovery(key);
+        return Ok(false);
+    }
+    if !process.disk_changes.is_empty() {
+        process.answer_disk_change(key);
+        return Ok(false);
+    }
+
+    if let Some(finder) = &mut process.finder {
+        match key {
+            Key::Esc => process.finder = None,
+            Key::Char('\n') => {
+                let path = finder.selected_path();
+                process.finder = None;
+                if let Some(path) = path {
+                    if let Err(msg) = process.open_buffer(&path) {
+                        process.message = Some(msg);
+                    }
+                }
+            }
+            Key::Char(c) => finder.push(c),
+            Key::Backspace => finder.pop(),
+            Key::Down | Key::Ctrl('n') => finder.move_selection(true),
+            Key::Up | Key::Ctrl('p') => finder.move_selection(false),
+            _ => (),
+        }
+        return Ok(false);
+    }
+
+    if process.quickfix.focused {
+        match key {
+            Key::Char('j') | Key::Down => {
+                process.quickfix.select(process.quickfix.current + 1);
+            }
+            Key::Char('k') | Key::Up if process.quickfix.current > 0 => {
+                process.quickfix.select(process.quickfix.current - 1);
+            }
+            Key::Char('\n') => {
+                process.quickfix.focused = false;
+                if let Err(msg) = process.goto_quickfix(process.quickfix.current) {
+                    process.message = Some(msg);
                 }
-                col -= 39;
             }
-            let row = mrow as usize + buf.scroll.0 - 1;
+            Key::Char('q') => {
+                process.quickfix.open = false;
+                process.quickfix.focused = false;
+            }
+            Key::Esc | Key::Ctrl('w') => process.quickfix.focused = false,
+            _ => (),
+        }
+        return Ok(false);
+    }
+
+    if let Some(selection) = &process.history_selection {
+        match key {
+            Key::Char('j') | Key::Down => process.history_step(true, term),
+            Key::Char('k') | Key::Up => process.history_step(false, term),
+            Key::Char('\n') => {
+                let location = selection.location.clone();
+                process.time_travel(|history| history.goto_location(location));
+                process.history_selection = None;
+            }
+            Key::Esc => process.history_selection = None,
+            Key::Char('H') | Key::Char('q') => {
+                process.get_active_buffer().show_history = false;
+                process.history_selection = None;
+            }
+            _ => (),
+        }
+        return Ok(false);
+    }
 
-            buf.cursor = (row, col);
-            buf.cursor_col_goal = col;
-            update_cursor(buf, term)
+    if let Some(labels) = &mut process.labels {
+        let (waiting, target) = match key {
+            Key::Char(c) => labels.type_letter(c),
+            _ => (false, None),
+        };
+        if !waiting {
+            process.labels = None;
+        }
+        if let Some(pos) = target {
+            process.push_jump();
+            process.move_cursor(pos, false);
         }
+        return Ok(false);
+    }
 
-        _ => (),
+    if let Key::F(n) = key {
+        process.switch_buffer(min(n as usize - 1, process.buffers.len() - 1));
+        return Ok(false);
+    }
+
+    match process.get_active_buffer().mode {
+        Mode::Command => {
+            match key {
+                Key::Char('\n') => {
+                    let line = std::mem::take(&mut process.command_line);
+                    if !line.trim().is_empty() {
+                        process.shada.add_command(&line);
+                    }
+                    process.command_history_index = None;
+                    let (buf, view) = process.get_active();
+                    buf.set_mode(view, Mode::Normal);
+                    match run_command(process, term, &line) {
+                        Ok(quit) => return Ok(quit),
+                        Err(msg) => process.message = Some(msg),
+                    }
+                }
+                Key::Char(c) => process.command_line.push(c),
+                Key::Backspace if !process.command_line.is_empty() => {
+                    process.command_line.pop();
+                }
+                Key::Up => {
+                    let commands = &process.shada.commands;
+                    let index = process.command_history_index.unwrap_or(commands.len());
+                    if index > 0 {
+                        process.command_line = commands[index - 1].clone();
+                        process.command_history_index = Some(index - 1);
+                    }
+                }
+                Key::Down => {
+                    if let Some(index) = process.command_history_index {
+                        let commands = &process.shada.commands;
+                        if index + 1 < commands.len() {
+                            process.command_line = commands[index + 1].clone();
+                            process.command_history_index = Some(index + 1);
+                        } else {
+                            process.command_line.clear();
+                            process.command_history_index = None;
+                        }
+                    }
+                }
+                Key::Backspace | Key::Esc => {
+                    process.command_line.clear();
+                    process.command_history_index = None;
+                    let (buf, view) = process.get_active();
+                    buf.set_mode(view, Mode::Normal);
+                }
+                _ => (),
+            }
+            return Ok(false);
+        }
+        Mode::Insert => match key {
+            Key::Ctrl('n') | Key::Ctrl('p') => {
+                process.complete(key == Key::Ctrl('n'));
+                return Ok(false);
+            }
+            _ => process.completion = None,
+        },
+        Mode::Replace => (),
+        Mode::Normal => return handle_normal_keys(process, term, key),
+        Mode::Visual | Mode::VisualLine => {
+            let filter = match key {
+                Key::Char(c) => CONFIG.read().unwrap().filters.get(&c.to_string()).cloned(),
+                _ => None,
+            };
+            if process.pending == [Key::Char('r')] {
+                process.pending.clear();
+                if let Key::Char(c) = key {
+                    let (buf, view) = process.get_active();
+                    let (start, stop) = selected_bounds(view);
+                    let (start, stop) = if buf.mode == Mode::VisualLine {
+                        ((start.0, 1), (stop.0, usize::MAX))
+                    } else {
+                        (start, stop)
+                    };
+                    buf.set_mode(view, Mode::Normal);
+                    if c != '\n' {
+                        replace_chars(buf, start, stop, c);
+                    }
+                    view.cursor = start;
+                    view.cursor_col_goal = start.1;
+                    update_cursor(buf, view);
+                }
+                return Ok(false);
+            }
+            if let [Key::Char('f' | 'F' | 't' | 'T')] = process.pending[..] {
+                let mut keys = std::mem::take(&mut process.pending);
+                keys.push(key);
+                if let Some(pos) = process.find_in_line(&keys, 1) {
+                    process.move_cursor(pos, false);
+                }
+                return Ok(false);
+            }
+            match key {
+                Key::Char('f' | 'F' | 't' | 'T') => {
+                    process.pending.push(key);
+                    return Ok(false);
+                }
+                Key::Char(';' | ',') => {
+                    if let Some(pos) = process.find_in_line(&[key], 1) {
+                        process.move_cursor(pos, false);
+                    }
+                    return Ok(false);
+                }
+                _ => (),
+            }
+            // gc, g Ctrl-A and r{char} are the only key commands in Visual mode
+            let progressive = process.pending == [Key::Char('g')];
+            if progressive {
+                process.pending.clear();
+                if key == Key::Char('c') {
+                    let (buf, view) = process.get_active();
+                    let (start, stop) = selected_bounds(view);
+                    buf.set_mode(view, Mode::Normal);
+                    view.cursor.0 = start.0;
+                    comment_lines(buf, view, start.0, stop.0);
+                    return Ok(false);
+                }
+            } else if key == Key::Char('g') || key == Key::Char('r') {
+                process.pending.push(key);
+                return Ok(false);
+            }
+            let (buf, view) = process.get_active();
+            if let Key::Ctrl(c @ ('a' | 'x')) = key {
+                let delta = if c == 'a' { 1 } else { -1 };
+                increment_selected(buf, view, delta, progressive);
+                return Ok(false);
+            }
+            if let Some(command) = filter {
+                let result = filter_selected(buf, view, &command);
+                buf.set_mode(view, Mode::Normal);
+                update_cursor(buf, view);
+                match result {
+                    Ok(stderr) => process.message = stderr,
+                    Err(msg) => process.message = Some(msg),
+                }
+                return Ok(false);
+            }
+            if key == Key::Char('c') && view.cursor.0 == view.selection_start.0 {
+                let result = evaluate_selected(buf, view);
+                buf.set_mode(view, Mode::Normal);
+                update_cursor(buf, view);
+                match result {
+                    Ok(msg) => process.message = msg,
+                    Err(msg) => process.message = Some(msg),
+                }
+                return Ok(false);
+            }
+            if let Key::Char(c @ (':' | '!')) = key {
+                buf.set_mode(view, Mode::Normal);
+                buf.set_mode(view, Mode::Command);
+                process.command_line = "'<,'>".to_owned();
+                if c == '!' {
+                    process.command_line.push('!');
+                }
+                return Ok(false);
+            }
+        }
+    }
+
+    let (buf, view) = process.get_active();
+    Ok(handle_event(buf, view, evt, 1))
+}
+
+// Collects counts and multi-key commands in Normal mode
+fn handle_normal_keys(process: &mut Process, term: &Terminal, key: Key) -> std::io::Result<bool> {
+    if let Key::Char(c) = key {
+        // A count can also come between an operator and its motion
+        let operator = matches!(
+            process.pending[..],
+            [Key::Char('g'), Key::Char('c' | 'u' | 'U' | '~')]
+        );
+        if (process.pending.is_empty() || operator)
+            && c.is_ascii_digit()
+            && (c != '0' || process.count.is_some())
+        {
+            let digit = c.to_digit(10).unwrap() as usize;
+            process.count = Some(
+                process
+                    .count
+                    .unwrap_or(0)
+                    .saturating_mul(10)
+                    .saturating_add(digit),
+            );
+            return Ok(false);
+        }
+    }
+    if key == Key::Esc {
+        process.pending.clear();
+        process.count = None;
+    }
+
+    process.pending.push(key);
+    let pending = process.pending.clone();
+    let count = process.count;
+    match pending[..] {
+        [Key::Ctrl('w')]
+        | [Key::Char('!' | 'f' | 'F' | 't' | 'T')]
+        | [Key::Char('!'), Key::Char('g' | 'f' | 'F' | 't' | 'T')]
+        | [Key::Char('g'), Key::Char('s')]
+        | [Key::Char('g' | ']' | '[' | 'm' | '\'' | '`')] => return Ok(false),
+        [Key::Ctrl('w'), key] => process.window_command(key, term),
+        [Key::Char('!'), ..] => {
+            // Like in vim, the motion only fills in the range of a filter command
+            let n = count.unwrap_or(1);
+            let found = process.find_in_line(&pending[1..], n).is_some();
+            let (buf, view) = process.get_active();
+            let range = match pending[1..] {
+                [Key::Char('!')] if n == 1 => Some(".".to_owned()),
+                [Key::Char('!')] => Some(format!(".,.+{}", n - 1)),
+                [Key::Char('j')] | [Key::Down] => Some(format!(".,.+{n}")),
+                [Key::Char('k')] | [Key::Up] => Some(format!(".-{n},.")),
+                [Key::Char('G')] => Some(".,$".to_owned()),
+                [Key::Char('g'), Key::Char('g')] => Some("1,.".to_owned()),
+                [Key::Char('%')] => match_of(buf, view.cursor).map(|(row, _)| format!(".,{row}")),
+                _ if found => Some(".".to_owned()),
+                _ => None,
+            };
+            if let Some(range) = range {
+                buf.set_mode(view, Mode::Command);
+                process.command_line = range + "!";
+            }
+        }
+        [Key::Char(':')] => {
+            let (buf, view) = process.get_active();
+            buf.set_mode(view, Mode::Command);
+            if let Some(n) = count {
+                process.command_line = if n == 1 {
+                    ".".to_owned()
+                } else {
+                    format!(".,.+{}", n - 1)
+                };
+            }
+        }
+        [Key::Char('g'), Key::Char('c' | 'u' | 'U' | '~')]
+        | [Key::Char('g'), Key::Char('c' | 'u' | 'U' | '~'), Key::Char('g' | 'f' | 'F' | 't' | 'T')] => {
+            return Ok(false)
+        }
+        [Key::Char('g'), operator @ Key::Char(op @ ('c' | 'u' | 'U' | '~')), ..] => {
+            let case = match op {
+                'u' => Case::Lower,
+                'U' => Case::Upper,
+                _ => Case::Toggle,
+            };
+            // After an in-line find the operator goes from the cursor to where it lands
+            if let Some(target) = process.find_in_line(&pending[2..], count.unwrap_or(1)) {
+                let (buf, view) = process.get_active();
+                let cursor = view.cursor;
+                if op == 'c' {
+                    comment_lines(buf, view, cursor.0, cursor.0);
+                } else {
+                    // Backward finds leave out the character under the cursor, like in vim
+                    let (start, stop) = if target.1 >= cursor.1 {
+                        (cursor, target)
+                    } else {
+                        (target, (cursor.0, cursor.1 - 1))
+                    };
+                    change_case(buf, start, stop, case);
+                    view.cursor = start;
+                    view.cursor_col_goal = start.1;
+                    update_cursor(buf, view);
+                }
+                process.pending.clear();
+                process.count = None;
+                return Ok(false);
+            }
+            let (buf, view) = process.get_active();
+            let lines = motion_lines(
+                &pending[2..],
+                operator,
+                count.unwrap_or(1),
+                buf,
+                view.cursor,
+            );
+            if let Some((start, stop)) = lines {
+                if op == 'c' {
+                    comment_lines(buf, view, start, stop);
+                } else {
+                    change_case(buf, (start, 1), (stop, usize::MAX), case);
+                    view.cursor = (start, view.cursor.1);
+                    update_cursor(buf, view);
+                }
+            }
+        }
+        [Key::Char('~')] => {
+            let (buf, view) = process.get_active();
+            let len = buf.contents[view.cursor.0 - 1].len();
+            if len > 0 {
+                let stop = min(view.cursor.1 + count.unwrap_or(1) - 1, len);
+                change_case(buf, view.cursor, (view.cursor.0, stop), Case::Toggle);
+                view.cursor.1 = min(stop + 1, len);
+                view.cursor_col_goal = view.cursor.1;
+                update_cursor(buf, view);
+            }
+        }
+        [Key::Ctrl(c @ ('a' | 'x'))] => {
+            let delta = count.unwrap_or(1) as i64 * if c == 'a' { 1 } else { -1 };
+            let (buf, view) = process.get_active();
+            let row = view.cursor.0;
+            buf.history.start_record();
+            let end = increment_number(buf, row, view.cursor.1 - 1, delta);
+            let name = if c == 'a' { "Increment" } else { "Decrement" };
+            buf.history
+                .stop_record_named(format!("{name} number on line {row}"));
+            if let Some(end) = end {
+                view.cursor.1 = end;
+                view.cursor_col_goal = end;
+                update_cursor(buf, view);
+            }
+        }
+        [Key::Char('r')] if !CONFIG.read().unwrap().redo_on_r => return Ok(false),
+        [Key::Char('r'), Key::Char(c)] => {
+            let (buf, view) = process.get_active();
+            let n = count.unwrap_or(1);
+            let (row, col) = view.cursor;
+            let last = col + n - 1;
+            // Like in vim, nothing happens if the line is too short
+            if last <= buf.contents[row - 1].len() {
+                if c == '\n' {
+                    // The characters are replaced by a single line break
+                    buf.history.start_record();
+                    replace_in_line(buf, (row, col), last, Vec::new());
+                    insert_char(buf, view, '\n', (row, col));
+                    buf.history.stop_record_named(format!("Break line {row}"));
+                } else {
+                    replace_chars(buf, (row, col), (row, last), c);
+                    view.cursor.1 = last;
+                }
+                view.cursor_col_goal = view.cursor.1;
+                update_cursor(buf, view);
+            }
+        }
+        [Key::Char('r'), _] => (),
+        [Key::Char('%')] => {
+            let (buf, view) = process.get_active();
+            if let Some(pos) = match_of(buf, view.cursor) {
+                process.push_jump();
+                process.move_cursor(pos, false);
+            }
+        }
+        [Key::Char('f' | 'F' | 't' | 'T'), Key::Char(_)] | [Key::Char(';' | ',')] => {
+            if let Some(pos) = process.find_in_line(&pending, count.unwrap_or(1)) {
+                process.move_cursor(pos, false);
+            }
+        }
+        [Key::Char('f' | 'F' | 't' | 'T'), _] => (),
+        [Key::Char('g'), Key::Char('s'), Key::Char(c)] => process.start_label_jump(c),
+        [Key::Char('J')] | [Key::Char('g'), Key::Char('J')] => {
+            let (buf, view) = process.get_active();
+            join_lines(buf, view, count.unwrap_or(2), pending.len() == 1);
+        }
+        [Key::Char('m'), Key::Char(c)] if c.is_ascii_alphabetic() => process.set_mark(c),
+        [Key::Char(q @ ('\'' | '`')), Key::Char(c)] if c.is_ascii_alphabetic() => {
+            if let Err(msg) = process.goto_mark(c, q == '`') {
+                process.message = Some(msg);
+            }
+        }
+        [Key::Char('G')] | [Key::Char('g'), Key::Char('g')] => {
+            let last = process.get_active_buffer().contents.len();
+            let default = if key == Key::Char('G') { last } else { 1 };
+            process.push_jump();
+            process.move_cursor((count.unwrap_or(default), 1), true);
+        }
+        [Key::Ctrl('o')] | [Key::Char('\t')] => {
+            let result = if key == Key::Ctrl('o') {
+                process.jump_older(count.unwrap_or(1))
+            } else {
+                process.jump_newer(count.unwrap_or(1))
+            };
+            if let Err(msg) = result {
+                process.message = Some(msg);
+            }
+        }
+        [Key::Char('g'), Key::Char(c @ (';' | ','))] => {
+            if let Err(msg) = process.goto_change(c == ';', count.unwrap_or(1)) {
+                process.message = Some(msg);
+            }
+        }
+        [Key::Ctrl('p')] => {
+            let mut finder = Finder::new(process.signals.clone());
+            finder.update();
+            process.finder = Some(finder);
+        }
+        [Key::Char('w')] => {
+            if let Err(msg) = process.write_active(false) {
+                process.message = Some(msg);
+            }
+        }
+        [Key::Char('H')] => {
+            let buf = process.get_active_buffer();
+            buf.show_history = !buf.show_history;
+            if buf.show_history {
+                // The panel takes the keys until Enter or Esc
+                let row = buf.history.row_of(&buf.history.location).unwrap_or(1);
+                let height = term.rows() - 3;
+                if row < buf.history.scroll || row >= buf.history.scroll + height {
+                    buf.history.scroll = row.saturating_sub(height / 2).max(1);
+                }
+                let location = buf.history.location.clone();
+                process.select_history_node(location);
+            }
+        }
+        [Key::Char('g'), Key::Char(c @ ('-' | '+'))] => {
+            let steps = count.unwrap_or(1) as isize;
+            let steps = if c == '-' { -steps } else { steps };
+            process.time_travel(|history| history.step_chronological(steps));
+        }
+        [Key::Char('K')] => {
+            let view = &process.views[process.active_view];
+            let buf = &process.buffers[view.buffer];
+            let diagnostics = diagnostics_at(buf, view.cursor.0);
+            let result = process.lsp.hover(buf, view.cursor);
+            // Diagnostics show right away, the hover text joins them when it arrives
+            process.popup = diagnostics;
+            if let Err(msg) = result {
+                process.message = Some(msg);
+            }
+        }
+        [Key::Char('g'), Key::Char('d')] => {
+            let view = &process.views[process.active_view];
+            let buf = &process.buffers[view.buffer];
+            if let Err(msg) = process.lsp.definition(buf, view.cursor) {
+                process.message = Some(msg);
+            }
+        }
+        [Key::Char(c @ (']' | '[')), Key::Char('d')] => {
+            let (buf, view) = process.get_active();
+            let target = if c == ']' {
+                buf.diagnostics
+                    .iter()
+                    .find(|diag| diag.start.0 > view.cursor.0)
+            } else {
+                buf.diagnostics
+                    .iter()
+                    .rev()
+                    .find(|diag| diag.start.0 < view.cursor.0)
+            };
+            match target.map(|diag| diag.start) {
+                Some(start) => {
+                    view.cursor = start;
+                    view.cursor_col_goal = start.1;
+                    update_cursor(buf, view);
+                    process.popup = diagnostics_at(buf, start.0);
+                }
+                None => process.message = Some("No more diagnostics".to_owned()),
+            }
+        }
+        _ => {
+            process.pending.clear();
+            process.count = None;
+            let (buf, view) = process.get_active();
+            return Ok(handle_event(buf, view, Event::Key(key), count.unwrap_or(1)));
+        }
     }
+    process.pending.clear();
+    process.count = None;
+    Ok(false)
+}
+
+fn diagnostics_at(buf: &Buffer, line: usize) -> Option<String> {
+    let messages: Vec<String> = buf
+        .diagnostics
+        .iter()
+        .filter(|diag| diag.start.0 <= line && line <= diag.end.0)
+        .map(|diag| format!("{}: {}", diag.sign().0, diag.message))
+        .collect();
+    Some(messages.join("\n")).filter(|text| !text.is_empty())
 }
 
 fn write_buffer(buf: &mut Buffer) -> std::io::Result<()> {
@@ -916,9 +3434,20 @@ fn write_buffer(buf: &mut Buffer) -> std::io::Result<()> {
         }
         file.write_all("\n".as_bytes())?;
     }
-    if CONFIG.read().unwrap().undofile {
-        buf.history.save();
+    buf.history.mark_saved();
+    let config = CONFIG.read().unwrap();
+    let max_age =
+        (config.undo_max_age > 0).then(|| time::Duration::days(config.undo_max_age as i64));
+    buf.history
+        .prune(config.undolevels, max_age, config.undo_max_bytes);
+    buf.history.compact();
+    if buf.swap_written {
+        remove_swap(&buf.path);
+        buf.swap_written = false;
     }
+    buf.swap_pending = false;
+    buf.modified = false;
+    buf.disk_contents = buf.contents.clone();
     Ok(())
 }
 
@@ -943,61 +3472,265 @@ fn main() -> std::io::Result<()> {
             .unwrap_or(());
     }
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
+    let mut files = Vec::new();
+    let mut session = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--session" {
+            let Some(path) = args.next() else {
+                return Err(std::io::Error::other("No session file specified"));
+            };
+            session = Some(read_session(&path).map_err(std::io::Error::other)?);
+        } else {
+            files.push(arg);
+        }
+    }
+    if files.is_empty() && session.is_none() {
         panic!("No file specified");
     }
 
     let undofile = CONFIG.read().unwrap().undofile;
 
-    let mut buffers = Vec::new();
-    for arg in &args[1..] {
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(arg)?;
-
-        let buffer = Buffer::from_file(file, undofile)?;
-        buffers.push(buffer);
+    let mut buffers: Vec<Buffer> = Vec::new();
+    if let Some(session) = &session {
+        for state in &session.buffers {
+            buffers.push(Buffer::open(&state.path, undofile)?);
+        }
+    }
+    let session_buffers = buffers.len();
+    for file in &files {
+        if !buffers.iter().any(|buf| buf.is_file(file)) {
+            buffers.push(Buffer::open(file, undofile)?);
+        }
     }
 
+    let (signals, receiver) = mpsc::channel();
+    let file_signals = signals.clone();
+    let file_watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                for path in event.paths {
+                    file_signals.send(Signal::FileChanged(path)).unwrap_or(());
+                }
+            }
+        }
+    })
+    .ok();
     let mut process = Process {
         buffers,
-        active_buffer: 0,
+        views: vec![View::new(0)],
+        layout: Layout::View(0),
+        separators: Vec::new(),
+        active_view: 0,
+        pending: Vec::new(),
+        count: None,
+        command_line: String::new(),
+        message: None,
+        finder: None,
+        quickfix: Quickfix::default(),
+        lsp: Lsp::new(signals.clone()),
+        popup: None,
+        completion: None,
+        history_selection: None,
+        recovery: Vec::new(),
+        file_watcher,
+        watched_dirs: HashSet::new(),
+        changed_files: Vec::new(),
+        disk_changes: Vec::new(),
+        shada: Shada::read(),
+        command_history_index: None,
+        jumps: JumpList::new(),
+        last_find: None,
+        labels: None,
+        completion_sources: vec![Box::new(BufferWords), Box::new(FilePaths)],
+        signals: signals.clone(),
     };
+    for buf in &process.buffers {
+        if let Err(msg) = process.lsp.open(buf) {
+            process.message = Some(msg);
+        }
+    }
+    for index in 0..process.buffers.len() {
+        process.check_swap(index);
+        process.watch_buffer(index);
+    }
+    for index in session_buffers..process.buffers.len() {
+        process.restore_position(index);
+    }
+    match session {
+        Some(session) => process.restore_session(session),
+        None => {
+            let view = &mut process.views[0];
+            (view.cursor, view.scroll) = process.buffers[0].last_position;
+            view.cursor_col_goal = view.cursor.1;
+            view.clamp(&process.buffers[0].contents);
+        }
+    }
 
-    let stdin = stdin();
     let mut term = Terminal::from_stdout(stdout());
     print!("\x1b[?47h"); // Save terminal state
 
     //print!("\x1b[s");
 
-    redraw(&process.get_active_buffer(), &mut term);
-
-    for c in stdin.events() {
-        term.update_size();
-        let evt = c.unwrap();
-        if let Event::Key(Key::F(n)) = evt {
-            process.active_buffer = min(n as usize - 1, process.buffers.len() - 1);
-        }
-
-        let mut buf = process.get_active_buffer();
-        if buf.mode == Mode::Normal && evt == Event::Key(Key::Char('w')) {
-            write_buffer(&mut buf)?;
-        } else {
-            let quit = handle_event(&mut buf, &term, evt);
-            if quit {
+    let ticks = signals.clone();
+    thread::spawn(move || {
+        for evt in stdin().events() {
+            if signals.send(Signal::Input(evt)).is_err() {
                 break;
             }
-            redraw(&buf, &mut term);
         }
-    }
+    });
+    thread::spawn(move || loop {
+        let interval = CONFIG.read().unwrap().swap_interval.max(1);
+        thread::sleep(Duration::from_secs(interval));
+        if ticks.send(Signal::Tick).is_err() {
+            break;
+        }
+    });
+
+    install_panic_hook();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        run(&mut process, &mut term, receiver)
+    }));
     print!("\x1b[?47l"); // Restore terminal state
                          //print!("\x1b[u");
-    term.goto(term.rows().into(), 1);
+    term.goto(term.rows(), 1);
+    if result.is_err() {
+        // Keep everything that was not written, then leave raw mode before explaining
+        for buf in &mut process.buffers {
+            if buf.modified {
+                buf.swap_pending = true;
+            }
+        }
+        process.write_swaps();
+        term.flush();
+        drop(term);
+        if let Some(message) = take_panic_message() {
+            eprintln!("{message}");
+        }
+        eprintln!(
+            "Unsaved changes were written to swap files, open the files again to recover them"
+        );
+        std::process::exit(101);
+    }
+    process.remove_swaps();
+    process.remember_positions();
+    if let Err(err) = process.shada.write() {
+        eprintln!("Could not write the shada file: {err}");
+    }
     if CONFIG.read().unwrap().logging {
         print_log();
     }
+    result.unwrap()
+}
+
+fn run(
+    process: &mut Process,
+    term: &mut Terminal,
+    receiver: mpsc::Receiver<Signal>,
+) -> std::io::Result<()> {
+    process.update_layout(term);
+    redraw(process, term);
+
+    for signal in receiver {
+        term.update_size();
+        process.update_layout(term);
+        match signal {
+            Signal::Input(evt) => {
+                let quit = handle_process_event(process, term, evt.unwrap())?;
+                if quit {
+                    break;
+                }
+            }
+            Signal::Refresh => (),
+            Signal::Tick => {
+                process.write_swaps();
+                continue;
+            }
+            Signal::FileChanged(path) => process.file_event(path),
+        }
+        process.check_changed_files();
+        if let Some(finder) = &mut process.finder {
+            finder.update();
+        }
+        process.handle_lsp_events();
+        let (buf, view) = process.get_active();
+        let state = cursor_state(buf, view);
+        buf.history.settle_cursor(state);
+        process.sync_views();
+        process.update_layout(term);
+        redraw(process, term);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(name: &str, text: &str) -> Buffer {
+        let path = std::env::temp_dir().join(format!("rvim-main-{name}-{}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let buf = Buffer::open(path.to_str().unwrap(), false).unwrap();
+        std::fs::remove_file(path).unwrap();
+        buf
+    }
+
+    fn type_keys(buf: &mut Buffer, view: &mut View, keys: &[Key]) {
+        for key in keys {
+            replace_key(buf, view, *key);
+        }
+    }
+
+    fn line(buf: &Buffer) -> String {
+        buf.contents[0].iter().collect()
+    }
+
+    #[test]
+    fn backspace_restores_the_replaced_characters() {
+        let mut buf = buffer("replace", "abc\n");
+        let mut view = View::new(0);
+        buf.set_mode(&mut view, Mode::Replace);
+        type_keys(&mut buf, &mut view, &[Key::Char('x'), Key::Char('y')]);
+        assert_eq!(line(&buf), "xyc");
+        type_keys(&mut buf, &mut view, &[Key::Backspace]);
+        assert_eq!((line(&buf).as_str(), view.cursor), ("xbc", (1, 2)));
+        type_keys(&mut buf, &mut view, &[Key::Backspace, Key::Backspace]);
+        assert_eq!((line(&buf).as_str(), view.cursor), ("abc", (1, 1)));
+
+        // Typing again after that is still one change
+        type_keys(&mut buf, &mut view, &[Key::Char('z')]);
+        buf.set_mode(&mut view, Mode::Normal);
+        assert_eq!(line(&buf), "zbc");
+        let undo = buf.history.undo().unwrap();
+        do_text_action(&mut buf, &mut view, undo);
+        assert_eq!(line(&buf), "abc");
+        assert!(buf.history.undo().is_none());
+    }
+
+    #[test]
+    fn backspace_removes_characters_typed_past_the_end() {
+        let mut buf = buffer("append", "ab\n");
+        let mut view = View::new(0);
+        view.cursor = (1, 2);
+        buf.set_mode(&mut view, Mode::Replace);
+        type_keys(&mut buf, &mut view, &[Key::Char('x'), Key::Char('y')]);
+        assert_eq!(line(&buf), "axy");
+        type_keys(&mut buf, &mut view, &[Key::Backspace]);
+        assert_eq!((line(&buf).as_str(), view.cursor), ("ax", (1, 3)));
+        type_keys(&mut buf, &mut view, &[Key::Backspace]);
+        assert_eq!((line(&buf).as_str(), view.cursor), ("ab", (1, 2)));
+    }
+
+    #[test]
+    fn backspace_before_the_typed_text_only_moves_left() {
+        let mut buf = buffer("move", "abc\n");
+        let mut view = View::new(0);
+        view.cursor = (1, 3);
+        buf.set_mode(&mut view, Mode::Replace);
+        type_keys(&mut buf, &mut view, &[Key::Backspace, Key::Backspace]);
+        assert_eq!((line(&buf).as_str(), view.cursor), ("abc", (1, 1)));
+        type_keys(&mut buf, &mut view, &[Key::Backspace]);
+        assert_eq!(view.cursor, (1, 1));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::common::*;
+
+pub struct MatchPair {
+    open: String32,
+    close: String32,
+}
+
+// Reads pairs written like "(:)" or "begin:end". Pairs that open and close the same way can't
+// be told apart, so they are left out.
+pub fn parse_match_pairs(pairs: &[String]) -> Vec<MatchPair> {
+    pairs
+        .iter()
+        .filter_map(|pair| {
+            let (open, close) = pair.split_once(':')?;
+            (!open.is_empty() && !close.is_empty() && open != close).then(|| MatchPair {
+                open: open.chars().collect(),
+                close: close.chars().collect(),
+            })
+        })
+        .collect()
+}
+
+fn is_word_char(cha: &char) -> bool {
+    cha.is_alphanumeric() || *cha == '_'
+}
+
+// Whether the item starts at the index. Words like begin only count on their own.
+fn item_at(line: &[char], i: usize, item: &[char]) -> bool {
+    if !line[i..].starts_with(item) {
+        return false;
+    }
+    !item.iter().all(is_word_char)
+        || !(i > 0 && is_word_char(&line[i - 1])
+            || line.get(i + item.len()).is_some_and(is_word_char))
+}
+
+// The pair with an item starting at the index, and whether that item opens it
+fn pair_at(line: &[char], i: usize, pairs: &[MatchPair]) -> Option<(usize, bool)> {
+    pairs.iter().enumerate().find_map(|(p, pair)| {
+        if item_at(line, i, &pair.open) {
+            Some((p, true))
+        } else if item_at(line, i, &pair.close) {
+            Some((p, false))
+        } else {
+            None
+        }
+    })
+}
+
+// The item covering the index, as where it starts, its pair and whether it opens
+fn pair_under(line: &[char], i: usize, pairs: &[MatchPair]) -> Option<(usize, usize, bool)> {
+    let longest = pairs
+        .iter()
+        .map(|pair| pair.open.len().max(pair.close.len()))
+        .max()?;
+    (i.saturating_sub(longest - 1)..=i)
+        .filter_map(|start| Some((start, pair_at(line, start, pairs)?)))
+        .find(|(start, (p, opens))| {
+            let pair = &pairs[*p];
+            let len = if *opens {
+                pair.open.len()
+            } else {
+                pair.close.len()
+            };
+            start + len > i
+        })
+        .map(|(start, (p, opens))| (start, p, opens))
+}
+
+// Looks from an item for the one closing or opening it, counting nested pairs on the way.
+// Only rows from first to last are searched. Returns where it starts and its length.
+fn find_partner(
+    contents: &[String32],
+    from: Coord,
+    pair: &MatchPair,
+    opens: bool,
+    (first, last): (usize, usize),
+) -> Option<(Coord, usize)> {
+    let (same, other) = if opens {
+        (&pair.open, &pair.close)
+    } else {
+        (&pair.close, &pair.open)
+    };
+    let mut depth = 0;
+    let mut check = |line: &[char], i: usize| {
+        if item_at(line, i, same) {
+            depth += 1;
+        } else if item_at(line, i, other) {
+            if depth == 0 {
+                return true;
+            }
+            depth -= 1;
+        }
+        false
+    };
+    if opens {
+        let mut skip = from.1 - 1 + same.len();
+        for row in from.0..=last.min(contents.len()) {
+            let line = &contents[row - 1];
+            for i in skip..line.len() {
+                if check(line, i) {
+                    return Some(((row, i + 1), other.len()));
+                }
+            }
+            skip = 0;
+        }
+    } else {
+        let mut before = Some(from.1 - 1);
+        for row in (first.max(1)..=from.0).rev() {
+            let line = &contents[row - 1];
+            for i in (0..before.unwrap_or(line.len())).rev() {
+                if check(line, i) {
+                    return Some(((row, i + 1), other.len()));
+                }
+            }
+            before = None;
+        }
+    }
+    None
+}
+
+// Where % jumps to: the partner of the first item under or after the cursor on its line
+pub fn match_target(contents: &[String32], cursor: Coord, pairs: &[MatchPair]) -> Option<Coord> {
+    let line = &contents[cursor.0 - 1];
+    let col = cursor.1 - 1;
+    let (start, p, opens) = pair_under(line, col, pairs).or_else(|| {
+        (col..line.len()).find_map(|i| pair_at(line, i, pairs).map(|(p, opens)| (i, p, opens)))
+    })?;
+    let rows = (1, contents.len());
+    find_partner(contents, (cursor.0, start + 1), &pairs[p], opens, rows).map(|(pos, _)| pos)
+}
+
+// The partner of the item under the cursor, searched for within the rows, as where it starts
+// and its length
+pub fn matching_item(
+    contents: &[String32],
+    cursor: Coord,
+    pairs: &[MatchPair],
+    rows: (usize, usize),
+) -> Option<(Coord, usize)> {
+    let line = contents.get(cursor.0 - 1)?;
+    if cursor.1 > line.len() {
+        return None;
+    }
+    let (start, p, opens) = pair_under(line, cursor.1 - 1, pairs)?;
+    find_partner(contents, (cursor.0, start + 1), &pairs[p], opens, rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs() -> Vec<MatchPair> {
+        let pairs = ["(:)", "[:]", "{:}", "begin:end"].map(str::to_owned);
+        parse_match_pairs(&pairs)
+    }
+
+    fn text(text: &str) -> Vec<String32> {
+        split_text(text)
+    }
+
+    #[test]
+    fn pairs_that_cannot_be_told_apart_are_skipped() {
+        let pairs = ["\":\"", "<>", ":)", "<:>"].map(str::to_owned);
+        let parsed = parse_match_pairs(&pairs);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].open, ['<']);
+    }
+
+    #[test]
+    fn nested_brackets() {
+        let contents = text("f(a(b)c)");
+        assert_eq!(match_target(&contents, (1, 2), &pairs()), Some((1, 8)));
+        assert_eq!(match_target(&contents, (1, 8), &pairs()), Some((1, 2)));
+        assert_eq!(match_target(&contents, (1, 6), &pairs()), Some((1, 4)));
+    }
+
+    #[test]
+    fn jumps_from_the_next_item_on_the_line() {
+        let contents = text("x = [1, (2)]");
+        assert_eq!(match_target(&contents, (1, 1), &pairs()), Some((1, 12)));
+        assert_eq!(match_target(&contents, (1, 7), &pairs()), Some((1, 11)));
+        assert_eq!(match_target(&text("none here"), (1, 1), &pairs()), None);
+        assert_eq!(match_target(&text("(open"), (1, 1), &pairs()), None);
+    }
+
+    #[test]
+    fn across_lines() {
+        let contents = text("{\n  a {\n  }\n}");
+        assert_eq!(match_target(&contents, (1, 1), &pairs()), Some((4, 1)));
+        assert_eq!(match_target(&contents, (4, 1), &pairs()), Some((1, 1)));
+        assert_eq!(match_target(&contents, (2, 5), &pairs()), Some((3, 3)));
+    }
+
+    #[test]
+    fn words_only_match_on_their_own() {
+        let contents = text("begin\n  beginning\n  x_end end");
+        assert_eq!(match_target(&contents, (1, 3), &pairs()), Some((3, 9)));
+        assert_eq!(match_target(&contents, (3, 10), &pairs()), Some((1, 1)));
+    }
+
+    #[test]
+    fn matching_item_under_the_cursor() {
+        let contents = text("begin\n(\n)\nend");
+        assert_eq!(
+            matching_item(&contents, (1, 2), &pairs(), (1, 4)),
+            Some(((4, 1), 3))
+        );
+        assert_eq!(
+            matching_item(&contents, (3, 1), &pairs(), (1, 4)),
+            Some(((2, 1), 1))
+        );
+        // Only the given rows are searched, and only the item under the cursor counts
+        assert_eq!(matching_item(&contents, (1, 1), &pairs(), (1, 3)), None);
+        assert_eq!(matching_item(&text("a ()"), (1, 1), &pairs(), (1, 1)), None);
+        assert_eq!(matching_item(&text("()"), (1, 3), &pairs(), (1, 1)), None);
+    }
+}
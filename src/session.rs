@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::common::*;
+use crate::view::*;
+
+const MAX_COMMANDS: usize = 100;
+const MAX_POSITIONS: usize = 100;
+
+// Everything needed to open the same windows on the same files again
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub buffers: Vec<BufferState>,
+    pub views: Vec<ViewState>,
+    pub active_view: usize,
+    pub layout: Layout,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BufferState {
+    pub path: String,
+    pub cursor: Coord,
+    pub scroll: Coord,
+    pub clip: Vec<String>,
+    pub clip_lines: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ViewState {
+    pub buffer: usize,
+    pub cursor: Coord,
+    pub scroll: Coord,
+}
+
+pub fn write_session(path: &str, session: &Session) -> Result<(), String> {
+    let text = serde_json::to_string_pretty(session).map_err(|err| err.to_string())?;
+    fs::write(path, text).map_err(|err| format!("{path}: {err}"))
+}
+
+pub fn read_session(path: &str) -> Result<Session, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    let session: Session = serde_json::from_str(&text).map_err(|err| format!("{path}: {err}"))?;
+    // Every window must be in the layout exactly once and show a buffer that exists
+    let mut order = session.layout.views();
+    order.sort();
+    if session.buffers.is_empty()
+        || order != (0..session.views.len()).collect::<Vec<usize>>()
+        || session.active_view >= session.views.len()
+        || session
+            .views
+            .iter()
+            .any(|view| view.buffer >= session.buffers.len())
+    {
+        return Err(format!("{path}: Not a valid session"));
+    }
+    Ok(session)
+}
+
+// History shared by all sessions, like the shada file of Neovim
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Shada {
+    pub commands: Vec<String>,           // Oldest first
+    pub positions: Vec<(String, Coord)>, // Last cursor in each file, most recent first
+    #[serde(skip)]
+    loaded: usize,     // Commands that came from the file
+}
+
+fn shada_path() -> Option<PathBuf> {
+    let dir = match env::var("XDG_STATE_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env::var("HOME").ok()?).join(".local/state"),
+    };
+    Some(dir.join("rvim/shada.json"))
+}
+
+// Files are known by their full path, so they are found from any directory
+fn file_key(path: &str) -> String {
+    fs::canonicalize(path).map_or(path.to_owned(), |path| path.to_string_lossy().into_owned())
+}
+
+impl Shada {
+    pub fn read() -> Self {
+        let mut shada: Shada = shada_path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        shada.loaded = shada.commands.len();
+        shada
+    }
+
+    // Adds to what other instances wrote since this one started
+    pub fn write(&self) -> std::io::Result<()> {
+        let Some(path) = shada_path() else {
+            return Ok(());
+        };
+        let mut shada = Shada::read();
+        for command in &self.commands[self.loaded.min(self.commands.len())..] {
+            shada.add_command(command);
+        }
+        for (file, pos) in self.positions.iter().rev() {
+            shada.set_position(file, *pos);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(&shada)?)
+    }
+
+    pub fn add_command(&mut self, line: &str) {
+        if let Some(index) = self.commands.iter().position(|command| command == line) {
+            self.commands.remove(index);
+            if index < self.loaded {
+                self.loaded -= 1;
+            }
+        }
+        self.commands.push(line.to_owned());
+        if self.commands.len() > MAX_COMMANDS {
+            let extra = self.commands.len() - MAX_COMMANDS;
+            self.commands.drain(..extra);
+            self.loaded = self.loaded.saturating_sub(extra);
+        }
+    }
+
+    pub fn position(&self, path: &str) -> Option<Coord> {
+        let key = file_key(path);
+        self.positions
+            .iter()
+            .find_map(|(file, pos)| (*file == key).then_some(*pos))
+    }
+
+    pub fn set_position(&mut self, path: &str, pos: Coord) {
+        let key = file_key(path);
+        self.positions.retain(|(file, _)| *file != key);
+        self.positions.insert(0, (key, pos));
+        self.positions.truncate(MAX_POSITIONS);
+    }
+}
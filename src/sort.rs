@@ -0,0 +1,300 @@
+use std::cmp::Ordering;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::common::*;
+
+#[derive(Default)]
+pub struct SortOptions {
+    pub reverse: bool,
+    pub numeric: bool,
+    pub ignore_case: bool,
+    pub unique: bool,
+    // Lines are sorted on what comes after the first match, or on the match itself
+    pub pattern: Option<Regex>,
+    pub use_match: bool,
+}
+
+// Reads the arguments of :sort, which are flags and a pattern between slashes in any order
+pub fn parse_sort_args(args: &str, reverse: bool) -> Result<SortOptions, String> {
+    let mut options = SortOptions {
+        reverse,
+        ..SortOptions::default()
+    };
+    let mut chars = args.chars();
+    while let Some(cha) = chars.next() {
+        match cha {
+            'n' => options.numeric = true,
+            'i' => options.ignore_case = true,
+            'u' => options.unique = true,
+            'r' => options.use_match = true,
+            '/' => {
+                let mut pattern = String::new();
+                loop {
+                    match chars.next() {
+                        Some('/') => break,
+                        Some('\\') if chars.clone().next() == Some('/') => {
+                            pattern.push(chars.next().unwrap());
+                        }
+                        Some(cha) => pattern.push(cha),
+                        None => return Err("Missing / after sort pattern".to_owned()),
+                    }
+                }
+                let regex = Regex::new(&pattern).map_err(|err| err.to_string())?;
+                options.pattern = Some(regex);
+            }
+            c if c.is_whitespace() => {}
+            c => return Err(format!("Invalid sort flag: {c}")),
+        }
+    }
+    Ok(options)
+}
+
+lazy_static! {
+    // The number a line is sorted on when sorting numerically
+    static ref NUMBER: Regex = Regex::new(r"-?\d+(\.\d+)?").unwrap();
+}
+
+// Lines without a match for the pattern, or without a number when sorting numerically, come
+// first and keep their order
+enum Key {
+    Missing,
+    Number(f64),
+    Text(String),
+}
+
+fn compare_keys(a: &Key, b: &Key) -> Ordering {
+    match (a, b) {
+        (Key::Missing, Key::Missing) => Ordering::Equal,
+        (Key::Missing, _) => Ordering::Less,
+        (_, Key::Missing) => Ordering::Greater,
+        (Key::Number(a), Key::Number(b)) => a.total_cmp(b),
+        (Key::Text(a), Key::Text(b)) => a.cmp(b),
+        _ => Ordering::Equal,
+    }
+}
+
+fn sort_key(line: &[char], options: &SortOptions) -> Key {
+    let line: String = line.iter().collect();
+    let text = match &options.pattern {
+        Some(pattern) => match pattern.find(&line) {
+            Some(found) if options.use_match => found.as_str(),
+            Some(found) => &line[found.end()..],
+            None => return Key::Missing,
+        },
+        None => &line,
+    };
+    if options.numeric {
+        NUMBER
+            .find(text)
+            .and_then(|found| found.as_str().parse().ok())
+            .map_or(Key::Missing, Key::Number)
+    } else if options.ignore_case {
+        Key::Text(text.to_lowercase())
+    } else {
+        Key::Text(text.to_owned())
+    }
+}
+
+// Sorts the lines stably. Unique keeps the first of each run of lines that sort the same.
+pub fn sort_lines(lines: &[String32], options: &SortOptions) -> Vec<String32> {
+    let mut keyed: Vec<(Key, String32)> = lines
+        .iter()
+        .map(|line| (sort_key(line, options), line.clone()))
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| compare_keys(a, b));
+    if options.unique {
+        keyed.dedup_by(|(a, _), (b, _)| compare_keys(a, b) == Ordering::Equal);
+    }
+    let mut sorted: Vec<String32> = keyed.into_iter().map(|(_, line)| line).collect();
+    if options.reverse {
+        sorted.reverse();
+    }
+    sorted
+}
+
+fn split_on(line: &[char], delimiter: &[char]) -> Vec<String32> {
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut i = 0;
+    while i < line.len() {
+        if line[i..].starts_with(delimiter) {
+            fields.push(std::mem::take(&mut field));
+            i += delimiter.len();
+        } else {
+            field.push(line[i]);
+            i += 1;
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn trim_field(field: &[char], start: bool, end: bool) -> String32 {
+    let mut field = field;
+    while start && field.first().is_some_and(|c| c.is_whitespace()) {
+        field = &field[1..];
+    }
+    while end && field.last().is_some_and(|c| c.is_whitespace()) {
+        field = &field[..field.len() - 1];
+    }
+    field.to_vec()
+}
+
+// Lines up every occurrence of the delimiter in columns, with one space around it. The first
+// field keeps its indentation and lines without the delimiter are left alone.
+pub fn align_lines(lines: &[String32], delimiter: &str) -> Vec<String32> {
+    let delimiter: String32 = delimiter.chars().collect();
+    let split: Vec<Vec<String32>> = lines
+        .iter()
+        .map(|line| {
+            let fields = split_on(line, &delimiter);
+            let last = fields.len() - 1;
+            fields
+                .iter()
+                .enumerate()
+                .map(|(i, field)| trim_field(field, i > 0, i < last))
+                .collect()
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = Vec::new();
+    for fields in &split {
+        for (i, field) in fields[..fields.len() - 1].iter().enumerate() {
+            if i == widths.len() {
+                widths.push(0);
+            }
+            widths[i] = widths[i].max(field.len());
+        }
+    }
+
+    lines
+        .iter()
+        .zip(split)
+        .map(|(line, fields)| {
+            if fields.len() == 1 {
+                return line.clone();
+            }
+            let last = fields.len() - 1;
+            let mut aligned = Vec::new();
+            for (i, field) in fields.into_iter().enumerate() {
+                let empty = field.is_empty();
+                let field_start = aligned.len();
+                aligned.extend(field);
+                if i < last {
+                    aligned.resize(field_start + widths[i], ' ');
+                    aligned.push(' ');
+                    aligned.extend_from_slice(&delimiter);
+                    aligned.push(' ');
+                } else if empty {
+                    aligned.pop();
+                }
+            }
+            aligned
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String32> {
+        lines.iter().map(|line| line.chars().collect()).collect()
+    }
+
+    fn sorted(input: &[&str], args: &str) -> Vec<String> {
+        let options = parse_sort_args(args, false).unwrap();
+        sort_lines(&lines(input), &options)
+            .iter()
+            .map(|line| line.iter().collect())
+            .collect()
+    }
+
+    fn aligned(input: &[&str], delimiter: &str) -> Vec<String> {
+        align_lines(&lines(input), delimiter)
+            .iter()
+            .map(|line| line.iter().collect())
+            .collect()
+    }
+
+    #[test]
+    fn parse_flags_and_pattern() {
+        let options = parse_sort_args(" n i u /a\\/b/ r", true).unwrap();
+        assert!(options.reverse && options.numeric && options.ignore_case && options.unique);
+        assert!(options.use_match);
+        assert_eq!(options.pattern.unwrap().as_str(), "a/b");
+        assert!(!parse_sort_args("", false).unwrap().reverse);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            parse_sort_args("/abc", false).err().unwrap(),
+            "Missing / after sort pattern"
+        );
+        assert_eq!(
+            parse_sort_args("nx", false).err().unwrap(),
+            "Invalid sort flag: x"
+        );
+        assert!(parse_sort_args("/(/", false).is_err());
+    }
+
+    #[test]
+    fn sort_is_stable() {
+        assert_eq!(sorted(&["b", "A", "a"], ""), ["A", "a", "b"]);
+        assert_eq!(sorted(&["b", "a", "A"], "i"), ["a", "A", "b"]);
+        let options = parse_sort_args("", true).unwrap();
+        assert_eq!(
+            sort_lines(&lines(&["b", "c", "a"]), &options),
+            lines(&["c", "b", "a"])
+        );
+    }
+
+    #[test]
+    fn numeric_sort_puts_lines_without_numbers_first() {
+        assert_eq!(
+            sorted(&["x10", "x9", "none", "-3 y", "1.5", "empty"], "n"),
+            ["none", "empty", "-3 y", "1.5", "x9", "x10"]
+        );
+    }
+
+    #[test]
+    fn unique_keeps_the_first_line() {
+        assert_eq!(sorted(&["a", "b", "a"], "u"), ["a", "b"]);
+        assert_eq!(sorted(&["a", "A", "B"], "ui"), ["a", "B"]);
+        assert_eq!(sorted(&["v1", "v01", "v2"], "un"), ["v1", "v2"]);
+    }
+
+    #[test]
+    fn sort_on_a_pattern() {
+        assert_eq!(sorted(&["x,2", "y,1", "z"], "/,/"), ["z", "y,1", "x,2"]);
+        assert_eq!(
+            sorted(&["b 20 a", "a 3 z"], "/\\d+/ r n"),
+            ["a 3 z", "b 20 a"]
+        );
+    }
+
+    #[test]
+    fn align_on_a_delimiter() {
+        assert_eq!(
+            aligned(&["a = 1", "long = 2", "x=3"], "="),
+            ["a    = 1", "long = 2", "x    = 3"]
+        );
+        assert_eq!(
+            aligned(&["a|b|c", "aa|b|cc"], "|"),
+            ["a  | b | c", "aa | b | cc"]
+        );
+    }
+
+    #[test]
+    fn align_keeps_indentation_and_other_lines() {
+        assert_eq!(
+            aligned(&["  a=1", "no delimiter", "  bb = 2"], "="),
+            ["  a  = 1", "no delimiter", "  bb = 2"]
+        );
+        assert_eq!(aligned(&["a =", "bb = 1"], "="), ["a  =", "bb = 1"]);
+        assert_eq!(aligned(&["a -> b", "cc->d"], "->"), ["a  -> b", "cc -> d"]);
+    }
+}
@@ -0,0 +1,160 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use lazy_static::lazy_static;
+use std::fs::{self, File};
+use std::io::Write;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::buffer::*;
+use crate::common::*;
+use crate::log::*;
+use crate::undo::*;
+
+const SWAP_FILE_VERSION: u32 = 1;
+const SWAP_FILE_MAGIC: &[u8] = b"RVIMSWAP";
+
+// Unsaved contents of a buffer, kept next to its file so they survive a crash
+pub struct Swap {
+    pub contents: Vec<String32>,
+    pub history: History,
+}
+
+// ".name.swp" in the directory of the file, like Vim
+pub fn swap_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.swp"))
+}
+
+pub fn write_swap(buf: &Buffer) -> std::io::Result<()> {
+    let mut file = File::create(swap_path(&buf.path))?;
+    file.write_all(SWAP_FILE_MAGIC)?;
+    file.write_all(&SWAP_FILE_VERSION.to_le_bytes())?;
+    let mut encoder = DeflateEncoder::new(file, Compression::fast());
+    bincode::serialize_into(&mut encoder, &(&buf.contents, &buf.history))
+        .map_err(std::io::Error::other)?;
+    encoder.finish()?.sync_all()
+}
+
+pub fn remove_swap(path: &str) {
+    fs::remove_file(swap_path(path)).unwrap_or(());
+}
+
+// The swap file of a path, if there is one written after the file itself
+pub fn find_swap(path: &str) -> Option<Swap> {
+    let swap = swap_path(path);
+    let swap_time = fs::metadata(&swap).and_then(|meta| meta.modified()).ok()?;
+    if let Ok(file_time) = fs::metadata(path).and_then(|meta| meta.modified()) {
+        if file_time > swap_time {
+            return None;
+        }
+    }
+    let bytes = fs::read(&swap).ok()?;
+    let rest = bytes.strip_prefix(SWAP_FILE_MAGIC)?;
+    let version = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?);
+    if version > SWAP_FILE_VERSION {
+        return None;
+    }
+    let (contents, history) = bincode::deserialize_from(DeflateDecoder::new(&rest[4..])).ok()?;
+    Some(Swap { contents, history })
+}
+
+lazy_static! {
+    static ref PANIC_MESSAGE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+// Keeps the panic message of the main thread until the terminal is back to normal, instead of
+// printing it over the screen in raw mode. Panics in other threads only go to the log.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        if thread::current().name() == Some("main") {
+            *PANIC_MESSAGE.lock().unwrap() = Some(info.to_string());
+        } else {
+            log!("{info}");
+        }
+    }));
+}
+
+pub fn take_panic_message() -> Option<String> {
+    PANIC_MESSAGE.lock().unwrap().take()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    // A buffer of a new file in a directory of its own, named after the test
+    fn buffer(name: &str) -> Buffer {
+        let dir = std::env::temp_dir().join(format!("rvim-swap-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        let mut buf = Buffer::open(path.to_str().unwrap(), false).unwrap();
+        buf.contents.push("three".chars().collect());
+        buf.history.add_node(TextAction::InsertChar {
+            pos: (3, 1),
+            cha: 't',
+        });
+        buf
+    }
+
+    fn remove_dir(buf: &Buffer) {
+        fs::remove_dir_all(Path::new(&buf.path).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn swap_path_is_hidden_next_to_the_file() {
+        assert_eq!(swap_path("dir/file.rs"), Path::new("dir/.file.rs.swp"));
+        assert_eq!(swap_path("file"), Path::new(".file.swp"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = buffer("round-trip");
+        write_swap(&buf).unwrap();
+        let swap = find_swap(&buf.path).unwrap();
+        assert_eq!(swap.contents, buf.contents);
+        assert_eq!(swap.history.location, buf.history.location);
+        remove_swap(&buf.path);
+        assert!(find_swap(&buf.path).is_none());
+        remove_dir(&buf);
+    }
+
+    #[test]
+    fn newer_versions_and_other_files_are_rejected() {
+        let buf = buffer("version");
+        write_swap(&buf).unwrap();
+        let swap = swap_path(&buf.path);
+        let mut bytes = fs::read(&swap).unwrap();
+        let version = SWAP_FILE_MAGIC.len();
+        bytes[version..version + 4].copy_from_slice(&(SWAP_FILE_VERSION + 1).to_le_bytes());
+        fs::write(&swap, &bytes).unwrap();
+        assert!(find_swap(&buf.path).is_none());
+        fs::write(&swap, b"not a swap file").unwrap();
+        assert!(find_swap(&buf.path).is_none());
+        remove_dir(&buf);
+    }
+
+    #[test]
+    fn swap_older_than_the_file_is_ignored() {
+        let buf = buffer("mtime");
+        write_swap(&buf).unwrap();
+        File::options()
+            .write(true)
+            .open(&buf.path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(find_swap(&buf.path).is_none());
+        remove_dir(&buf);
+    }
+}
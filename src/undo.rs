@@ -1,7 +1,13 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use time::OffsetDateTime;
 
 use crate::common::*;
@@ -116,6 +122,174 @@ impl TextAction {
             },
         }
     }
+
+    // Where a position ends up after this action has been applied to the buffer
+    pub fn shift_coord(&self, pos: Coord) -> Coord {
+        use TextAction::*;
+        match self {
+            None => pos,
+            Insert { start, text, .. } => shift_for_insert(pos, *start, text),
+            Remove { start, text, .. } => shift_for_remove(pos, *start, text),
+            InsertChar { pos: start, cha } => {
+                let text = if *cha == '\n' {
+                    vec![vec![], vec![]]
+                } else {
+                    vec![vec![*cha]]
+                };
+                shift_for_insert(pos, *start, &text)
+            }
+            RemoveChar { pos: start, cha } => {
+                let text = if *cha == '\n' {
+                    vec![vec![], vec![]]
+                } else {
+                    vec![vec![*cha]]
+                };
+                shift_for_remove(pos, *start, &text)
+            }
+            InsertLines { start, stop, .. } => {
+                if pos.0 >= *start {
+                    (pos.0 + stop - start + 1, pos.1)
+                } else {
+                    pos
+                }
+            }
+            RemoveLines { start, stop, .. } => {
+                if pos.0 > *stop {
+                    (pos.0 - (stop - start + 1), pos.1)
+                } else if pos.0 >= *start {
+                    (*start, 1)
+                } else {
+                    pos
+                }
+            }
+            Composite { actions, .. } => actions
+                .iter()
+                .fold(pos, |pos, action| action.shift_coord(pos)),
+        }
+    }
+
+    // Where the action changes the text
+    pub fn position(&self) -> Option<Coord> {
+        use TextAction::*;
+        match self {
+            None => Option::None,
+            Insert { start, .. } | Remove { start, .. } => Some(*start),
+            InsertLines { start, .. } | RemoveLines { start, .. } => Some((*start, 1)),
+            InsertChar { pos, .. } | RemoveChar { pos, .. } => Some(*pos),
+            Composite { actions, .. } => actions.iter().rev().find_map(|act| act.position()),
+        }
+    }
+
+    // Applies the action to plain lines of text, the same way the editor would to a buffer
+    pub fn apply(&self, contents: &mut Vec<String32>) {
+        use TextAction::*;
+        match self {
+            None => (),
+            Insert { start, text, .. } => {
+                let mut tail = contents[start.0 - 1].split_off(start.1 - 1);
+                contents[start.0 - 1].extend(&text[0]);
+                for (i, line) in text[1..].iter().enumerate() {
+                    contents.insert(start.0 + i, line.clone());
+                }
+                contents[start.0 + text.len() - 2].append(&mut tail);
+            }
+            Remove { start, stop, .. } => {
+                let last_line = &contents[stop.0 - 1];
+                let mut extra = 0;
+                let mut tail = if stop.1 > last_line.len() {
+                    if stop.0 == contents.len() {
+                        vec![]
+                    } else {
+                        extra = 1;
+                        contents[stop.0].clone()
+                    }
+                } else {
+                    last_line[stop.1..].to_owned()
+                };
+                contents[start.0 - 1].truncate(start.1 - 1);
+                contents[start.0 - 1].append(&mut tail);
+                contents.drain(start.0..stop.0 + extra);
+            }
+            InsertLines { start, lines, .. } => {
+                contents.splice(start - 1..start - 1, lines.iter().cloned());
+            }
+            RemoveLines { start, stop, .. } => {
+                contents.drain(start - 1..*stop);
+                if contents.is_empty() {
+                    contents.push(String32::new());
+                }
+            }
+            InsertChar { pos, cha: '\n' } => {
+                let tail = contents[pos.0 - 1].split_off(pos.1 - 1);
+                contents.insert(pos.0, tail);
+            }
+            InsertChar { pos, cha } => contents[pos.0 - 1].insert(pos.1 - 1, *cha),
+            RemoveChar { pos, cha: '\n' } => {
+                let mut tail = contents.remove(pos.0);
+                contents[pos.0 - 1].append(&mut tail);
+            }
+            RemoveChar { pos, .. } => {
+                contents[pos.0 - 1].remove(pos.1 - 1);
+            }
+            Composite { actions, .. } => {
+                for action in actions {
+                    action.apply(contents);
+                }
+            }
+        }
+    }
+
+    // One line per action, with the parts of composite actions indented below their name
+    fn describe(&self, depth: usize, lines: &mut Vec<String>) {
+        let text = format!("{self}").replace('\n', "⏎");
+        lines.push(format!("{}{}", "  ".repeat(depth), text));
+        if let TextAction::Composite { actions, .. } = self {
+            for action in actions {
+                action.describe(depth + 1, lines);
+            }
+        }
+    }
+}
+
+fn shift_for_insert(pos: Coord, start: Coord, text: &[String32]) -> Coord {
+    if pos < start {
+        pos
+    } else if pos.0 == start.0 {
+        if text.len() == 1 {
+            (pos.0, pos.1 + text[0].len())
+        } else {
+            (
+                pos.0 + text.len() - 1,
+                pos.1 - start.1 + 1 + text[text.len() - 1].len(),
+            )
+        }
+    } else {
+        (pos.0 + text.len() - 1, pos.1)
+    }
+}
+
+fn shift_for_remove(pos: Coord, start: Coord, text: &[String32]) -> Coord {
+    let end = if text.len() == 1 {
+        (start.0, start.1 + text[0].len())
+    } else {
+        (start.0 + text.len() - 1, text[text.len() - 1].len() + 1)
+    };
+    if pos < start {
+        pos
+    } else if pos < end {
+        start
+    } else if pos.0 == end.0 {
+        (start.0, start.1 + pos.1 - end.1)
+    } else {
+        (pos.0 + 1 - text.len(), pos.1)
+    }
+}
+
+// Where the cursor was, and the bounds of the selection if there was one
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct CursorState {
+    pub cursor: Coord,
+    pub selection: Option<(Coord, Coord)>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -123,6 +297,14 @@ pub struct UndoNode {
     action: TextAction,
     children: Vec<UndoNode>,
     time: time::OffsetDateTime,
+    #[serde(default)]
+    before: Option<CursorState>, // Restored when the node is undone
+    #[serde(default)]
+    after: Option<CursorState>, // Restored when the node is redone
+    #[serde(default)]
+    saves: Vec<OffsetDateTime>, // When the file was written in this state
+    #[serde(default)]
+    checkpoints: Vec<String>,
 }
 
 impl UndoNode {
@@ -131,54 +313,218 @@ impl UndoNode {
             action: TextAction::None,
             children: Vec::new(),
             time: OffsetDateTime::now_utc(),
+            before: None,
+            after: None,
+            saves: Vec::new(),
+            checkpoints: Vec::new(),
         }
     }
 
-    pub fn new(action: TextAction) -> Self {
+    pub fn new(action: TextAction, before: Option<CursorState>) -> Self {
         Self {
             action,
             children: Vec::new(),
             time: OffsetDateTime::now_utc(),
+            before,
+            after: None,
+            saves: Vec::new(),
+            checkpoints: Vec::new(),
         }
     }
 }
 
+// Bumped when the undo file changes in a way older versions of the editor cannot read.
+// Version 2 and later files start with the magic bytes and the version, followed by the
+// compressed history. Earlier ones are plain JSON.
+const UNDO_FILE_VERSION: u32 = 2;
+const UNDO_FILE_MAGIC: &[u8] = b"RVIMUNDO";
+
+// ".name.un~" in the directory of the file, next to its swap file
+pub fn undo_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.un~"))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct History {
+    #[serde(default)]
+    version: u32, // Missing in files from before the version was recorded
     root: UndoNode,
     pub location: Vec<usize>, // List of indices describing path in tree
     latest_leaf: Option<Vec<usize>>,
     pub scroll: usize,
     pub locked: bool,
     pub recording: Option<Vec<TextAction>>,
+    #[serde(default, rename = "saves")]
+    old_saves: Vec<Vec<usize>>, // Version 0 kept the written locations here instead of in the nodes
+    #[serde(skip)]
+    cursor: Option<CursorState>, // As it was after the last handled event
+    #[serde(skip)]
+    record_cursor: Option<CursorState>, // As it was when the recording started
+    #[serde(skip)]
+    unsettled: bool, // The newest node is still waiting for its cursor after the change
+    #[serde(skip)]
+    restored_cursor: Option<CursorState>, // Where undo or redo wants the cursor
 }
 
 impl History {
     pub fn new() -> Self {
         Self {
+            version: UNDO_FILE_VERSION,
             root: UndoNode::empty_root(),
             location: Vec::new(),
             latest_leaf: None,
             scroll: 1,
             locked: false,
             recording: None,
+            old_saves: Vec::new(),
+            cursor: None,
+            record_cursor: None,
+            unsettled: false,
+            restored_cursor: None,
+        }
+    }
+
+    pub fn from_save(path: &str) -> Option<Self> {
+        let bytes = std::fs::read(undo_path(path)).ok()?;
+        let mut hist: History = match bytes.strip_prefix(UNDO_FILE_MAGIC) {
+            Some(rest) => {
+                let version = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?);
+                if version > UNDO_FILE_VERSION {
+                    return None;
+                }
+                bincode::deserialize_from(DeflateDecoder::new(&rest[4..])).ok()?
+            }
+            None => serde_json::from_slice(&bytes).ok()?,
+        };
+        if hist.version > UNDO_FILE_VERSION {
+            return None;
+        }
+        for loc in std::mem::take(&mut hist.old_saves) {
+            let node = hist.get_node_mut(&loc);
+            node.saves.push(node.time);
         }
+        hist.version = UNDO_FILE_VERSION;
+        Some(hist)
     }
 
-    pub fn from_save() -> Option<Self> {
-        let result = std::fs::read_to_string("savefile");
-        if let Ok(ser) = result {
-            let hist = serde_json::from_str(&ser).unwrap();
-            Some(hist)
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut f = File::create(undo_path(path))?;
+        f.write_all(UNDO_FILE_MAGIC)?;
+        f.write_all(&UNDO_FILE_VERSION.to_le_bytes())?;
+        let mut encoder = DeflateEncoder::new(f, Compression::default());
+        bincode::serialize_into(&mut encoder, self).map_err(std::io::Error::other)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    // Drops the oldest changes until the tree is within the limits. Changes on the way to the
+    // current state go last, by making the state after them the new root.
+    pub fn prune(&mut self, levels: usize, max_age: Option<time::Duration>, max_bytes: usize) {
+        let cutoff = max_age.map(|age| OffsetDateTime::now_utc() - age);
+        let is_stale = |node: &UndoNode| cutoff.is_some_and(|cutoff| node.time < cutoff);
+        let nodes = self.all_nodes();
+        let mut count = nodes.len() - 1;
+        let mut bytes: usize = if max_bytes > 0 {
+            nodes.iter().map(|(node, _)| node_size(node)).sum()
         } else {
-            None
+            0
+        };
+        let over =
+            |count: usize, bytes: usize| count > levels || bytes > max_bytes && max_bytes > 0;
+
+        // A node counts as old as the newest change below it, so children go before parents
+        let mut newest: HashMap<&[usize], OffsetDateTime> = HashMap::new();
+        let mut kept_children: HashMap<&[usize], usize> = HashMap::new();
+        for (node, loc) in nodes.iter().rev() {
+            let mut child = loc.clone();
+            child.push(0);
+            let time = (0..node.children.len())
+                .map(|i| {
+                    child[loc.len()] = i;
+                    newest[&child[..]]
+                })
+                .fold(node.time, OffsetDateTime::max);
+            newest.insert(loc, time);
+            kept_children.insert(loc, node.children.len());
+        }
+        // Leaves off the path to the current state can go without changing it
+        let mut off_path: Vec<&(&UndoNode, Vec<usize>)> = nodes
+            .iter()
+            .filter(|(_, loc)| !self.location.starts_with(loc))
+            .collect();
+        off_path.sort_by_key(|(_, loc)| (newest[&loc[..]], Reverse(loc.len())));
+        let mut dropped = HashSet::new();
+        for (node, loc) in off_path {
+            if kept_children[&loc[..]] > 0 || !(over(count, bytes) || is_stale(node)) {
+                continue;
+            }
+            *kept_children.get_mut(&loc[..loc.len() - 1]).unwrap() -= 1;
+            count -= 1;
+            bytes = bytes.saturating_sub(node_size(node));
+            dropped.insert(loc.clone());
+        }
+        let mut advance = 0;
+        while advance < self.location.len() && kept_children[&self.location[..advance]] == 1 {
+            let next = self.get_node(&self.location[..advance + 1]);
+            if !(over(count, bytes) || is_stale(next)) {
+                break;
+            }
+            count -= 1;
+            bytes = bytes.saturating_sub(node_size(next));
+            advance += 1;
+        }
+        let mut loc = Vec::new();
+        remove_nodes(&mut self.root, &mut loc, &dropped);
+        // Later siblings of dropped nodes move up, and so do the paths through them
+        let shift = |path: &[usize]| -> Option<Vec<usize>> {
+            (0..path.len())
+                .map(|depth| {
+                    let mut sibling = path[..=depth].to_vec();
+                    if dropped.contains(&sibling) {
+                        return None;
+                    }
+                    let before = (0..path[depth])
+                        .filter(|i| {
+                            sibling[depth] = *i;
+                            dropped.contains(&sibling)
+                        })
+                        .count();
+                    Some(path[depth] - before)
+                })
+                .collect()
+        };
+        self.location = shift(&self.location).unwrap_or_default();
+        self.latest_leaf = self.latest_leaf.as_deref().and_then(shift);
+        for _ in 0..advance {
+            self.advance_root();
         }
     }
 
-    pub fn save(&self) {
-        let ser = serde_json::to_string(&self).unwrap();
-        let mut f = File::create("savefile").unwrap();
-        f.write_all(ser.as_bytes()).unwrap();
+    // Makes the state after the first change the oldest one kept
+    fn advance_root(&mut self) {
+        let mut child = self.root.children.remove(0);
+        child.action = TextAction::None;
+        self.root = child;
+        self.location.remove(0);
+        if let Some(leaf) = &mut self.latest_leaf {
+            leaf.remove(0);
+        }
+    }
+
+    // Merges the typed and deleted characters in every change into longer insertions and removals
+    pub fn compact(&mut self) {
+        let mut stack = vec![&mut self.root];
+        while let Some(node) = stack.pop() {
+            if let TextAction::Composite { actions, .. } = &mut node.action {
+                *actions = compact_actions(std::mem::take(actions));
+            }
+            stack.extend(node.children.iter_mut());
+        }
     }
 
     pub fn add_node(&mut self, action: TextAction) {
@@ -188,23 +534,43 @@ impl History {
             record.push(action);
             return;
         }
+        self.push_node(action, self.cursor);
+    }
+
+    fn push_node(&mut self, action: TextAction, before: Option<CursorState>) {
         let mut cur = &mut self.root;
         for index in &self.location {
             cur = &mut cur.children[*index];
         }
-        let node = UndoNode::new(action);
+        let node = UndoNode::new(action, before);
         cur.children.push(node);
         let mut new_location = self.location.clone();
         new_location.push(cur.children.len() - 1);
         self.location = new_location;
         self.latest_leaf = None;
+        self.unsettled = true;
+    }
+
+    // Called after every event, so the next node knows where the cursor was before it
+    pub fn settle_cursor(&mut self, state: CursorState) {
+        if self.unsettled {
+            self.unsettled = false;
+            let location = self.location.clone();
+            self.get_node_mut(&location).after = Some(state);
+        }
+        self.cursor = Some(state);
+    }
+
+    // The cursor saved with the last node undone or redone
+    pub fn take_restored_cursor(&mut self) -> Option<CursorState> {
+        self.restored_cursor.take()
     }
 
     fn get_current_node(&self) -> &UndoNode {
         self.get_node(&self.location)
     }
 
-    fn get_node(&self, indices: &Vec<usize>) -> &UndoNode {
+    fn get_node(&self, indices: &[usize]) -> &UndoNode {
         let mut cur = &self.root;
         for index in indices {
             cur = &cur.children[*index];
@@ -212,12 +578,22 @@ impl History {
         cur
     }
 
+    fn get_node_mut(&mut self, indices: &[usize]) -> &mut UndoNode {
+        let mut cur = &mut self.root;
+        for index in indices {
+            cur = &mut cur.children[*index];
+        }
+        cur
+    }
+
     pub fn undo(&mut self) -> Option<TextAction> {
         if !self.location.is_empty() {
             if self.latest_leaf == None {
                 self.latest_leaf = Some(self.location.clone());
             }
-            let action = self.get_current_node().action.inverse();
+            let node = self.get_current_node();
+            let action = node.action.inverse();
+            self.restored_cursor = node.before;
             self.location.pop();
             Some(action)
         } else {
@@ -228,7 +604,10 @@ impl History {
     fn goto_child(&mut self, i: usize) -> Option<TextAction> {
         if i < self.get_current_node().children.len() {
             self.location.push(i);
-            Some(self.get_current_node().action.clone())
+            let node = self.get_current_node();
+            let action = node.action.clone();
+            self.restored_cursor = node.after;
+            Some(action)
         } else {
             None
         }
@@ -253,8 +632,6 @@ impl History {
             actions.push(action);
         }
         while self.location.len() < new_loc.len() {
-            println!("{:?}", self.location);
-            println!("{:?}", new_loc);
             let action = self.goto_child(new_loc[self.location.len()]).unwrap();
             actions.push(action);
         }
@@ -267,10 +644,257 @@ impl History {
         Some(self.goto_node(new_loc))
     }
 
+    pub fn goto_location(&mut self, location: Vec<usize>) -> Vec<TextAction> {
+        self.goto_node(location)
+    }
+
+    // The actions leading from one node to another, without moving there
+    fn path_between(&self, from: &[usize], to: &[usize]) -> Vec<TextAction> {
+        let common = from.iter().zip(to).take_while(|(a, b)| a == b).count();
+        let undos = (common..from.len())
+            .rev()
+            .map(|depth| self.get_node(&from[..=depth]).action.inverse());
+        let redos = (common..to.len()).map(|depth| self.get_node(&to[..=depth]).action.clone());
+        undos.chain(redos).collect()
+    }
+
+    // The text at another node, given the text at the current one
+    pub fn state_at(&self, location: &[usize], contents: &[String32]) -> Vec<String32> {
+        let mut scratch = contents.to_vec();
+        for action in self.path_between(&self.location, location) {
+            action.apply(&mut scratch);
+        }
+        scratch
+    }
+
+    // The whole tree as nested JSON objects, for other tools to look at
+    pub fn export_json(&self) -> String {
+        fn node_json(hist: &History, node: &UndoNode, loc: &mut Vec<usize>) -> serde_json::Value {
+            let children: Vec<serde_json::Value> = (0..node.children.len())
+                .map(|i| {
+                    loc.push(i);
+                    let child = node_json(hist, &node.children[i], loc);
+                    loc.pop();
+                    child
+                })
+                .collect();
+            serde_json::json!({
+                "id": node_id(loc),
+                "action": format!("{}", node.action),
+                "time": timestamp(node.time),
+                "current": *loc == hist.location,
+                "latest_leaf": hist.latest_leaf.as_ref() == Some(loc),
+                "saves": node.saves.iter().map(|time| timestamp(*time)).collect::<Vec<_>>(),
+                "checkpoints": node.checkpoints,
+                "children": children,
+            })
+        }
+        let json = serde_json::json!({
+            "version": UNDO_FILE_VERSION,
+            "location": self.location,
+            "root": node_json(self, &self.root, &mut Vec::new()),
+        });
+        serde_json::to_string_pretty(&json).unwrap()
+    }
+
+    // The whole tree as a Graphviz graph, oldest change at the top
+    pub fn export_dot(&self) -> String {
+        let mut dot =
+            String::from("digraph undotree {\n    node [shape=box, fontname=monospace];\n");
+        for (node, loc) in self.all_nodes() {
+            let mut label = vec![format!("{}", node.action), timestamp(node.time)];
+            for name in &node.checkpoints {
+                label.push(format!("checkpoint {name}"));
+            }
+            for time in &node.saves {
+                label.push(format!("written {}", timestamp(*time)));
+            }
+            let label: Vec<String> = label.iter().map(|line| escape_dot(line)).collect();
+            let mut attributes = format!("label=\"{}\"", label.join("\\n"));
+            if loc == self.location {
+                attributes += ", style=filled, fillcolor=lightblue";
+            }
+            if self.latest_leaf.as_ref() == Some(&loc) {
+                attributes += ", peripheries=2";
+            }
+            dot += &format!("    \"{}\" [{attributes}];\n", node_id(&loc));
+            if let Some((_, parent)) = loc.split_last() {
+                dot += &format!("    \"{}\" -> \"{}\";\n", node_id(parent), node_id(&loc));
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    // When the node was made and everything it does, for the history detail pane
+    pub fn describe_node(&self, location: &[usize]) -> Vec<String> {
+        let node = self.get_node(location);
+        let mut lines = vec![relative_time(node.time)];
+        for name in &node.checkpoints {
+            lines.push(format!("Checkpoint {name}"));
+        }
+        if let Some(time) = node.saves.last() {
+            lines.push(format!("Written {}", relative_time(*time)));
+        }
+        node.action.describe(0, &mut lines);
+        lines
+    }
+
+    // Every node with its location, parents before their children
+    fn all_nodes(&self) -> Vec<(&UndoNode, Vec<usize>)> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![(&self.root, Vec::new())];
+        while let Some((node, loc)) = stack.pop() {
+            for (i, child) in node.children.iter().enumerate() {
+                let mut child_loc = loc.clone();
+                child_loc.push(i);
+                stack.push((child, child_loc));
+            }
+            nodes.push((node, loc));
+        }
+        nodes
+    }
+
+    // Locations of all nodes, oldest first
+    fn chronological(&self) -> Vec<Vec<usize>> {
+        let mut nodes = self.all_nodes();
+        nodes.sort_by_key(|(node, _)| node.time);
+        nodes.into_iter().map(|(_, loc)| loc).collect()
+    }
+
+    // Moves through the states in the order they were made, jumping between branches
+    pub fn step_chronological(&mut self, steps: isize) -> Vec<TextAction> {
+        let nodes = self.chronological();
+        let index = nodes
+            .iter()
+            .position(|loc| *loc == self.location)
+            .unwrap_or(0) as isize;
+        let target = (index + steps).clamp(0, nodes.len() as isize - 1) as usize;
+        self.goto_node(nodes[target].clone())
+    }
+
+    // Goes to the newest state that existed the given number of seconds from the current one
+    pub fn step_time(&mut self, seconds: i64) -> Vec<TextAction> {
+        let target = self
+            .get_current_node()
+            .time
+            .saturating_add(time::Duration::seconds(seconds));
+        let loc = self
+            .chronological()
+            .into_iter()
+            .rev()
+            .find(|loc| self.get_node(loc).time <= target)
+            .unwrap_or_default();
+        self.goto_node(loc)
+    }
+
+    pub fn mark_saved(&mut self) {
+        let location = self.location.clone();
+        self.get_node_mut(&location)
+            .saves
+            .push(OffsetDateTime::now_utc());
+    }
+
+    // Locations of the states that were written, in the order they were written
+    fn saved_locations(&self) -> Vec<Vec<usize>> {
+        let mut saves: Vec<(OffsetDateTime, Vec<usize>)> = self
+            .all_nodes()
+            .into_iter()
+            .flat_map(|(node, loc)| node.saves.iter().map(move |time| (*time, loc.clone())))
+            .collect();
+        saves.sort_by_key(|(time, _)| *time);
+        saves.into_iter().map(|(_, loc)| loc).collect()
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.saved_locations().last() == Some(&self.location)
+    }
+
+    // Names the current state, moving the name if another state had it
+    pub fn add_checkpoint(&mut self, name: &str) {
+        if let Some(loc) = self.find_checkpoint(name) {
+            self.get_node_mut(&loc).checkpoints.retain(|n| n != name);
+        }
+        let location = self.location.clone();
+        self.get_node_mut(&location)
+            .checkpoints
+            .push(name.to_owned());
+    }
+
+    pub fn find_checkpoint(&self, name: &str) -> Option<Vec<usize>> {
+        self.all_nodes()
+            .into_iter()
+            .find(|(node, _)| node.checkpoints.iter().any(|n| n == name))
+            .map(|(_, loc)| loc)
+    }
+
+    // Names of all checkpoints with how old their states are, oldest first
+    pub fn list_checkpoints(&self) -> Vec<String> {
+        let mut nodes = self.all_nodes();
+        nodes.sort_by_key(|(node, _)| node.time);
+        nodes
+            .into_iter()
+            .flat_map(|(node, _)| {
+                node.checkpoints
+                    .iter()
+                    .map(|name| format!("{name}: {}", relative_time(node.time)))
+            })
+            .collect()
+    }
+
+    // Goes the given number of file writes back or forth. Going back from unsaved changes
+    // counts the last write as the first step.
+    pub fn step_saves(&mut self, steps: isize) -> Vec<TextAction> {
+        if steps == 0 {
+            return Vec::new();
+        }
+        let now = self.get_current_node().time;
+        let mut saves = self.saved_locations();
+        saves.retain(|loc| *loc != self.location);
+        saves.sort_by_key(|loc| self.get_node(loc).time);
+        saves.dedup();
+        let loc = if steps < 0 {
+            let earlier: Vec<_> = saves
+                .into_iter()
+                .filter(|loc| self.get_node(loc).time <= now)
+                .collect();
+            match earlier.len().checked_sub(steps.unsigned_abs()) {
+                Some(i) => earlier[i].clone(),
+                None => Vec::new(),
+            }
+        } else {
+            let later: Vec<_> = saves
+                .into_iter()
+                .filter(|loc| self.get_node(loc).time > now)
+                .collect();
+            match later.get(steps as usize - 1) {
+                Some(loc) => loc.clone(),
+                None => self.chronological().pop().unwrap_or_default(),
+            }
+        };
+        self.goto_node(loc)
+    }
+
     fn node_at_row(&self, row: usize) -> Option<Vec<usize>> {
+        self.node_rows()
+            .into_iter()
+            .find(|(node_row, _)| *node_row == row)
+            .map(|(_, loc)| loc)
+    }
+
+    pub fn row_of(&self, location: &[usize]) -> Option<usize> {
+        self.node_rows()
+            .into_iter()
+            .find(|(_, loc)| loc == location)
+            .map(|(row, _)| row)
+    }
+
+    // The row in the history panel of every node, top to bottom
+    pub fn node_rows(&self) -> Vec<(usize, Vec<usize>)> {
+        let mut rows = Vec::new();
         let mut next_nodes = vec![(&self.root, Vec::new())]; //node, location
         let mut cur_row = 1;
-        while !next_nodes.is_empty() && cur_row <= row {
+        while !next_nodes.is_empty() {
             let mut next_time = i128::MAX;
             let mut node_index = 0;
             for (i, (node, _)) in (&next_nodes).iter().enumerate() {
@@ -281,9 +905,7 @@ impl History {
             }
             let node = next_nodes[node_index].0;
             let loc = next_nodes[node_index].1.clone();
-            if cur_row == row {
-                return Some(loc);
-            }
+            rows.push((cur_row, loc.clone()));
             cur_row += 1;
             if node.children.is_empty() {
                 if node_index != next_nodes.len() - 1 {
@@ -299,7 +921,7 @@ impl History {
             }
             next_nodes.remove(node_index);
         }
-        None
+        rows
     }
 
     pub fn is_recording(&self) -> bool {
@@ -311,24 +933,34 @@ impl History {
             panic!("Was recording.")
         }
         self.recording = Some(vec![]);
+        self.record_cursor = self.cursor;
     }
 
     pub fn stop_record(&mut self) {
+        if let Some(records) = &self.recording {
+            let line = match records.first() {
+                Some(TextAction::InsertChar { pos, .. }) => pos.0 as i32,
+                Some(TextAction::RemoveChar { pos, .. }) => pos.0 as i32,
+                _ => -1,
+            };
+            self.stop_record_named(format!("Edit text at line {line}"));
+        } else {
+            panic!("Wasn't recording.")
+        }
+    }
+
+    // Stores everything recorded as a single undo step
+    pub fn stop_record_named(&mut self, name: String) {
         if let Some(records) = self.recording.take() {
             if records.is_empty() {
                 return;
             }
-            let first = records[0].clone();
-            let line = match first {
-                TextAction::InsertChar { pos, .. } => pos.0 as i32,
-                TextAction::RemoveChar { pos, .. } => pos.0 as i32,
-                _ => -1,
-            };
             let action = TextAction::Composite {
-                actions: records,
-                name: format!("Edit text at line {line}"),
+                actions: compact_actions(records),
+                name,
             };
-            self.add_node(action);
+            let before = self.record_cursor.take();
+            self.push_node(action, before);
         } else {
             panic!("Wasn't recording.")
         }
@@ -342,7 +974,177 @@ impl History {
     }
 }
 
-pub fn draw_history(hist: &History, surf: &impl Surface) {
+// Removes the nodes at the given locations, which must include everything below them
+fn remove_nodes(node: &mut UndoNode, loc: &mut Vec<usize>, dropped: &HashSet<Vec<usize>>) {
+    for (i, mut child) in std::mem::take(&mut node.children).into_iter().enumerate() {
+        loc.push(i);
+        if !dropped.contains(loc) {
+            remove_nodes(&mut child, loc, dropped);
+            node.children.push(child);
+        }
+        loc.pop();
+    }
+}
+
+// Roughly how much a node takes up in the undo file
+fn node_size(node: &UndoNode) -> usize {
+    bincode::serialized_size(&node.action).unwrap_or(0) as usize
+}
+
+// Position of the last character of text inserted at start, or of the line break it ends with
+pub fn text_stop(start: Coord, text: &[String32]) -> Coord {
+    let last = text.len() - 1;
+    if !text[last].is_empty() {
+        let col = if last == 0 { start.1 } else { 1 };
+        (start.0 + last, col + text[last].len() - 1)
+    } else if last == 0 {
+        start
+    } else if last == 1 {
+        (start.0, start.1 + text[0].len())
+    } else {
+        (start.0 + last - 1, text[last - 1].len() + 1)
+    }
+}
+
+// Position right after text inserted at start
+fn text_end(start: Coord, text: &[String32]) -> Coord {
+    let last = text.len() - 1;
+    if last == 0 {
+        (start.0, start.1 + text[0].len())
+    } else {
+        (start.0 + last, text[last].len() + 1)
+    }
+}
+
+fn char_text(cha: char) -> Vec<String32> {
+    if cha == '\n' {
+        vec![vec![], vec![]]
+    } else {
+        vec![vec![cha]]
+    }
+}
+
+// Where a single character action extends the run of edits before it
+#[derive(Clone, Copy)]
+enum Continuation {
+    After,
+    Before,
+}
+
+fn continuation(last: &TextAction, next: &TextAction) -> Option<Continuation> {
+    use TextAction::*;
+    match (last, next) {
+        (InsertChar { pos, cha }, InsertChar { pos: next, .. }) => {
+            (*next == text_end(*pos, &char_text(*cha))).then_some(Continuation::After)
+        }
+        (Insert { start, text, .. }, InsertChar { pos: next, .. }) => {
+            (*next == text_end(*start, text)).then_some(Continuation::After)
+        }
+        (RemoveChar { pos: start, .. } | Remove { start, .. }, RemoveChar { pos: next, cha }) => {
+            if next == start {
+                // Deleting forwards
+                Some(Continuation::After)
+            } else if (*cha == '\n' && start.1 == 1 && next.0 + 1 == start.0)
+                || (*cha != '\n' && next.0 == start.0 && next.1 + 1 == start.1)
+            {
+                // Deleting backwards
+                Some(Continuation::Before)
+            } else {
+                Option::None
+            }
+        }
+        _ => Option::None,
+    }
+}
+
+// Merges runs of single character insertions and removals into one action each
+fn compact_actions(actions: Vec<TextAction>) -> Vec<TextAction> {
+    use TextAction::*;
+    let mut result: Vec<TextAction> = Vec::new();
+    for action in actions {
+        let Some(how) = result.last().and_then(|last| continuation(last, &action)) else {
+            result.push(action);
+            continue;
+        };
+        let (insert, mut start, mut text) = match result.pop().unwrap() {
+            InsertChar { pos, cha } => (true, pos, char_text(cha)),
+            Insert { start, text, .. } => (true, start, text),
+            RemoveChar { pos, cha } => (false, pos, char_text(cha)),
+            Remove { start, text, .. } => (false, start, text),
+            _ => unreachable!(),
+        };
+        let (InsertChar { pos, cha } | RemoveChar { pos, cha }) = action else {
+            unreachable!()
+        };
+        match (how, cha) {
+            (Continuation::After, '\n') => text.push(vec![]),
+            (Continuation::After, cha) => text.last_mut().unwrap().push(cha),
+            (Continuation::Before, '\n') => text.insert(0, vec![]),
+            (Continuation::Before, cha) => text[0].insert(0, cha),
+        }
+        if let Continuation::Before = how {
+            start = pos;
+        }
+        let stop = text_stop(start, &text);
+        result.push(if insert {
+            Insert { start, stop, text }
+        } else {
+            Remove { start, stop, text }
+        });
+    }
+    result
+}
+
+// Name of a node in exported trees, made from its location
+fn node_id(loc: &[usize]) -> String {
+    let mut id = "root".to_owned();
+    for index in loc {
+        id += &format!(".{index}");
+    }
+    id
+}
+
+fn timestamp(time: OffsetDateTime) -> String {
+    format!(
+        "{} {:02}:{:02}:{:02} UTC",
+        time.date(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// How long ago a time was, as in "3 minutes ago"
+fn relative_time(time: OffsetDateTime) -> String {
+    let seconds = (OffsetDateTime::now_utc() - time).whole_seconds();
+    let (amount, unit) = match seconds {
+        ..=4 => return "just now".to_owned(),
+        5..=59 => (seconds, "second"),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{amount} {unit}{plural} ago")
+}
+
+// Marks shown after a node's description for its checkpoints and file writes
+fn badges(node: &UndoNode) -> Vec<(&str, Color)> {
+    node.checkpoints
+        .iter()
+        .map(|name| (name.as_str(), Color::Yellow))
+        .chain(node.saves.last().map(|_| ("w", Color::Green)))
+        .collect()
+}
+
+pub fn draw_history(hist: &History, selected: Option<&[usize]>, surf: &impl Surface) {
+    let selected = selected.map(|loc| hist.get_node(loc));
     let mut next_nodes = vec![&hist.root];
     let mut row = 1;
     while !next_nodes.is_empty() {
@@ -370,13 +1172,27 @@ pub fn draw_history(hist: &History, surf: &impl Surface) {
                 }
                 line_length += 1;
             }
-            if node == hist.get_current_node() {
+            if selected.is_some_and(|sel| std::ptr::eq(sel, node)) {
+                surf.set_color(Color::Black, Color::Gray);
+            } else if node == hist.get_current_node() {
                 surf.set_fg_color(Color::Cyan);
             }
+            let badges = badges(node);
+            let badge_length: usize = badges.iter().map(|(text, _)| text.len() + 3).sum();
             let message = format!("{}", node.action);
-            let reduced = preview_lines(&split_text(&message), surf.cols() - line_length - 1);
+            let reduced = preview_lines(
+                &split_text(&message),
+                surf.cols()
+                    .saturating_sub(line_length + badge_length + 1)
+                    .max(4),
+            );
             print!(" {}", reduced);
             surf.reset_colors();
+            for (text, color) in badges {
+                surf.set_fg_color(color);
+                print!(" [{text}]");
+                surf.reset_colors();
+            }
         }
         if node.children.len() == 0 && node_index != next_nodes.len() - 1 {
             row += 1;
@@ -424,3 +1240,399 @@ pub fn draw_history(hist: &History, surf: &impl Surface) {
         next_nodes.remove(node_index);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(start: Coord, text: &str) -> TextAction {
+        let text = split_text(text);
+        TextAction::Insert {
+            start,
+            stop: text_stop(start, &text),
+            text,
+        }
+    }
+
+    fn remove(start: Coord, text: &str) -> TextAction {
+        let text = split_text(text);
+        TextAction::Remove {
+            start,
+            stop: text_stop(start, &text),
+            text,
+        }
+    }
+
+    #[test]
+    fn shift_after_insert_on_same_line() {
+        let action = insert((1, 3), "abc");
+        assert_eq!(action.shift_coord((1, 2)), (1, 2));
+        assert_eq!(action.shift_coord((1, 3)), (1, 6));
+        assert_eq!(action.shift_coord((2, 1)), (2, 1));
+    }
+
+    #[test]
+    fn shift_after_insert_with_line_breaks() {
+        let action = insert((2, 4), "x\nyz");
+        assert_eq!(action.shift_coord((2, 6)), (3, 5));
+        assert_eq!(action.shift_coord((5, 2)), (6, 2));
+    }
+
+    #[test]
+    fn shift_inside_removed_text_goes_to_its_start() {
+        let action = remove((1, 2), "bc\nde");
+        assert_eq!(action.shift_coord((1, 3)), (1, 2));
+        assert_eq!(action.shift_coord((2, 1)), (1, 2));
+        assert_eq!(action.shift_coord((2, 4)), (1, 3));
+        assert_eq!(action.shift_coord((4, 1)), (3, 1));
+    }
+
+    #[test]
+    fn shift_for_line_breaks_typed_and_removed() {
+        let insert = TextAction::InsertChar {
+            pos: (1, 3),
+            cha: '\n',
+        };
+        assert_eq!(insert.shift_coord((1, 5)), (2, 3));
+        let remove = TextAction::RemoveChar {
+            pos: (1, 3),
+            cha: '\n',
+        };
+        assert_eq!(remove.shift_coord((2, 3)), (1, 5));
+    }
+
+    #[test]
+    fn shift_for_whole_lines() {
+        let insert = TextAction::InsertLines {
+            start: 3,
+            stop: 4,
+            lines: split_text("a\nb"),
+        };
+        assert_eq!(insert.shift_coord((2, 5)), (2, 5));
+        assert_eq!(insert.shift_coord((3, 5)), (5, 5));
+        let remove = TextAction::RemoveLines {
+            start: 3,
+            stop: 4,
+            lines: split_text("a\nb"),
+        };
+        assert_eq!(remove.shift_coord((4, 2)), (3, 1));
+        assert_eq!(remove.shift_coord((6, 2)), (4, 2));
+    }
+
+    #[test]
+    fn shift_through_composite_in_order() {
+        let action = TextAction::Composite {
+            actions: vec![insert((1, 1), "ab"), remove((1, 1), "a")],
+            name: String::new(),
+        };
+        assert_eq!(action.shift_coord((1, 4)), (1, 5));
+    }
+
+    // A history where each change types one letter, made the given number of hours ago
+    fn add_change(history: &mut History, cha: char, hours_ago: i64) {
+        history.add_node(TextAction::InsertChar { pos: (1, 1), cha });
+        let mut node = &mut history.root;
+        for i in &history.location {
+            node = &mut node.children[*i];
+        }
+        node.time = OffsetDateTime::now_utc() - time::Duration::hours(hours_ago);
+    }
+
+    // Changes a, b and c made 10, 9 and 8 hours ago, with c on a branch of its own
+    fn branched_history() -> History {
+        let mut history = History::new();
+        history.root.time -= time::Duration::hours(11);
+        add_change(&mut history, 'a', 10);
+        add_change(&mut history, 'b', 9);
+        history.undo();
+        history.undo();
+        add_change(&mut history, 'c', 8);
+        history
+    }
+
+    #[test]
+    fn step_chronological_jumps_between_branches() {
+        let mut history = branched_history();
+        assert_eq!(history.location, [1]);
+        assert_eq!(history.step_chronological(-1).len(), 3);
+        assert_eq!(history.location, [0, 0]);
+        history.step_chronological(-1);
+        assert_eq!(history.location, [0]);
+        history.step_chronological(10);
+        assert_eq!(history.location, [1]);
+        history.step_chronological(-10);
+        assert!(history.location.is_empty());
+        assert!(history.step_chronological(0).is_empty());
+    }
+
+    #[test]
+    fn step_time_goes_to_the_newest_state_at_the_time() {
+        let mut history = branched_history();
+        // Half an hour after a, before b was made
+        history.step_time(-90 * 60);
+        assert_eq!(history.location, [0]);
+        history.step_time(90 * 60);
+        assert_eq!(history.location, [0, 0]);
+        history.step_time(-999_999_999_999);
+        assert!(history.location.is_empty());
+        history.step_time(i64::MAX);
+        assert_eq!(history.location, [1]);
+    }
+
+    #[test]
+    fn step_saves_counts_writes() {
+        let mut history = History::new();
+        history.root.time -= time::Duration::hours(11);
+        add_change(&mut history, 'a', 10);
+        history.mark_saved();
+        add_change(&mut history, 'b', 9);
+        add_change(&mut history, 'c', 8);
+        history.mark_saved();
+        add_change(&mut history, 'd', 7);
+
+        assert!(history.step_saves(0).is_empty());
+        assert_eq!(history.location, [0, 0, 0, 0]);
+        // The last write is the first step back from unsaved changes
+        history.step_saves(-1);
+        assert_eq!(history.location, [0, 0, 0]);
+        history.step_saves(-1);
+        assert_eq!(history.location, [0]);
+        history.step_saves(-1);
+        assert!(history.location.is_empty());
+        history.step_saves(1);
+        assert_eq!(history.location, [0]);
+        // Going past the last write ends at the newest state
+        history.step_saves(2);
+        assert_eq!(history.location, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn checkpoints_move_to_the_state_named_last() {
+        let mut history = History::new();
+        add_change(&mut history, 'a', 2);
+        history.add_checkpoint("first");
+        history.add_checkpoint("both");
+        add_change(&mut history, 'b', 1);
+        history.add_checkpoint("both");
+        assert_eq!(history.find_checkpoint("first"), Some(vec![0]));
+        assert_eq!(history.find_checkpoint("both"), Some(vec![0, 0]));
+        assert_eq!(history.find_checkpoint("other"), None);
+        assert_eq!(
+            history.list_checkpoints(),
+            ["first: 2 hours ago", "both: 1 hour ago"]
+        );
+    }
+
+    #[test]
+    fn saved_is_the_state_written_last() {
+        let mut history = History::new();
+        assert!(!history.is_saved());
+        add_change(&mut history, 'a', 2);
+        history.mark_saved();
+        assert!(history.is_saved());
+        add_change(&mut history, 'b', 1);
+        assert!(!history.is_saved());
+        history.mark_saved();
+        history.undo();
+        assert!(!history.is_saved());
+        // Writing an older state again makes it the saved one
+        history.mark_saved();
+        assert!(history.is_saved());
+        history.redo();
+        assert!(!history.is_saved());
+    }
+
+    #[test]
+    fn step_saves_counts_a_state_written_twice_once() {
+        let mut history = History::new();
+        history.root.time -= time::Duration::hours(3);
+        add_change(&mut history, 'a', 2);
+        history.mark_saved();
+        add_change(&mut history, 'b', 1);
+        history.mark_saved();
+        history.undo();
+        history.mark_saved();
+        history.redo();
+        history.step_saves(-2);
+        assert!(history.location.is_empty());
+    }
+
+    fn letters(node: &UndoNode) -> String {
+        let mut text = match node.action {
+            TextAction::InsertChar { cha, .. } => cha.to_string(),
+            _ => String::new(),
+        };
+        if !node.children.is_empty() {
+            let children: Vec<String> = node.children.iter().map(letters).collect();
+            text += &format!("({})", children.join(" "));
+        }
+        text
+    }
+
+    #[test]
+    fn prune_drops_the_oldest_branches_first() {
+        let mut history = History::new();
+        add_change(&mut history, 'a', 10);
+        add_change(&mut history, 'b', 9);
+        history.undo();
+        history.undo();
+        add_change(&mut history, 'c', 8);
+        history.undo();
+        add_change(&mut history, 'd', 7);
+        add_change(&mut history, 'e', 6);
+        assert_eq!(letters(&history.root), "(a(b) c d(e))");
+
+        history.prune(3, None, 0);
+        assert_eq!(letters(&history.root), "(c d(e))");
+        assert_eq!(history.location, [1, 0]);
+        history.prune(2, None, 0);
+        assert_eq!(letters(&history.root), "(d(e))");
+        assert_eq!(history.location, [0, 0]);
+    }
+
+    #[test]
+    fn prune_moves_the_root_along_the_current_path_last() {
+        let mut history = History::new();
+        for (i, cha) in "abcd".chars().enumerate() {
+            add_change(&mut history, cha, 10 - i as i64);
+        }
+        history.prune(2, None, 0);
+        assert_eq!(letters(&history.root), "(c(d))");
+        assert_eq!(history.location, [0, 0]);
+        assert!(history.undo().is_some());
+        assert!(history.undo().is_some());
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn prune_drops_changes_older_than_the_max_age() {
+        let mut history = History::new();
+        add_change(&mut history, 'a', 50);
+        history.undo();
+        add_change(&mut history, 'b', 30);
+        add_change(&mut history, 'c', 1);
+        history.prune(100, Some(time::Duration::days(1)), 0);
+        assert_eq!(letters(&history.root), "(c)");
+        assert_eq!(history.location, [0]);
+    }
+
+    #[test]
+    fn prune_keeps_the_redo_branch_when_it_can() {
+        let mut history = History::new();
+        add_change(&mut history, 'a', 10);
+        history.undo();
+        add_change(&mut history, 'b', 9);
+        add_change(&mut history, 'c', 8);
+        history.undo();
+        history.prune(2, None, 0);
+        assert_eq!(letters(&history.root), "(b(c))");
+        assert_eq!(history.location, [0]);
+        assert!(history.redo().is_some());
+        assert_eq!(history.location, [0, 0]);
+    }
+
+    fn typed(start: Coord, text: &str) -> Vec<TextAction> {
+        let mut pos = start;
+        text.chars()
+            .map(|cha| {
+                let action = TextAction::InsertChar { pos, cha };
+                pos = if cha == '\n' {
+                    (pos.0 + 1, 1)
+                } else {
+                    (pos.0, pos.1 + 1)
+                };
+                action
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compact_typed_text_into_one_insert() {
+        let actions = compact_actions(typed((2, 3), "ab\nc"));
+        assert_eq!(actions, [insert((2, 3), "ab\nc")]);
+    }
+
+    #[test]
+    fn compact_backspaces_and_forward_deletes() {
+        let backspaces = ['c', 'b', 'a']
+            .into_iter()
+            .enumerate()
+            .map(|(i, cha)| TextAction::RemoveChar {
+                pos: (1, 3 - i),
+                cha,
+            })
+            .collect();
+        assert_eq!(compact_actions(backspaces), [remove((1, 1), "abc")]);
+
+        let deletes = ['a', 'b']
+            .into_iter()
+            .map(|cha| TextAction::RemoveChar { pos: (1, 1), cha })
+            .collect();
+        assert_eq!(compact_actions(deletes), [remove((1, 1), "ab")]);
+    }
+
+    #[test]
+    fn compact_backspace_over_a_line_break() {
+        let actions = vec![
+            TextAction::RemoveChar {
+                pos: (2, 1),
+                cha: 'b',
+            },
+            TextAction::RemoveChar {
+                pos: (1, 2),
+                cha: '\n',
+            },
+        ];
+        assert_eq!(compact_actions(actions), [remove((1, 2), "\nb")]);
+    }
+
+    #[test]
+    fn compact_keeps_separate_edits_apart() {
+        let mut actions = typed((1, 1), "a");
+        actions.extend(typed((3, 1), "b"));
+        actions.push(TextAction::RemoveChar {
+            pos: (3, 2),
+            cha: 'b',
+        });
+        assert_eq!(compact_actions(actions.clone()), actions);
+    }
+
+    #[test]
+    fn dot_labels_escape_quotes_and_line_breaks() {
+        assert_eq!(escape_dot(r#"a "b" \ c"#), r#"a \"b\" \\ c"#);
+        assert_eq!(escape_dot("one\ntwo"), "one\\ntwo");
+        let mut history = History::new();
+        history.add_node(TextAction::InsertChar {
+            pos: (1, 1),
+            cha: '"',
+        });
+        history.add_checkpoint("line\nbreak");
+        let dot = history.export_dot();
+        let node = dot
+            .lines()
+            .find(|line| line.starts_with("    \"root.0\" ["))
+            .unwrap();
+        assert!(node.contains(r#"[label="Insert \" at (1, 1)\n"#));
+        assert!(node.contains(r#"\ncheckpoint line\nbreak", style=filled"#));
+        assert!(dot.contains(r#"    "root" -> "root.0";"#));
+    }
+
+    #[test]
+    fn json_export_nests_children() {
+        let mut history = History::new();
+        add_change(&mut history, 'a', 2);
+        history.undo();
+        add_change(&mut history, 'b', 1);
+        history.mark_saved();
+        let json: serde_json::Value = serde_json::from_str(&history.export_json()).unwrap();
+        assert_eq!(json["location"], serde_json::json!([1]));
+        assert_eq!(json["root"]["id"], "root");
+        let children = json["root"]["children"].as_array().unwrap();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0]["id"], "root.0");
+        assert_eq!(children[0]["action"], "Insert a at (1, 1)");
+        assert_eq!(children[0]["current"], false);
+        assert_eq!(children[1]["current"], true);
+        assert_eq!(children[1]["saves"].as_array().unwrap().len(), 1);
+    }
+}
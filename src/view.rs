@@ -0,0 +1,318 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+
+use crate::common::*;
+
+// Height and width below which a window is not split any further
+const MIN_ROWS: usize = 3;
+const MIN_COLS: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Horizontal, // Windows stacked on top of each other
+    Vertical,   // Windows side by side
+}
+
+#[derive(Clone)]
+pub struct View {
+    pub buffer: usize,
+    pub cursor: Coord,
+    pub cursor_col_goal: usize,
+    pub scroll: Coord,
+    pub selection_start: Coord,
+    pub rect: Rect,
+}
+
+impl View {
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            buffer,
+            cursor: (1, 1),
+            cursor_col_goal: 1,
+            scroll: (1, 1),
+            selection_start: (1, 1),
+            rect: Rect {
+                top: 1,
+                left: 1,
+                bottom: 1,
+                right: 1,
+            },
+        }
+    }
+
+    // Rows kept between the cursor and the top or bottom edge while scrolling
+    pub fn margin(&self) -> usize {
+        min(10, self.rect.height().saturating_sub(1) / 2)
+    }
+
+    // Position of the cursor on the terminal
+    pub fn screen_cursor(&self) -> Coord {
+        (
+            self.rect.top + self.cursor.0.saturating_sub(self.scroll.0),
+            self.rect.left + 6 + self.cursor.1.saturating_sub(self.scroll.1),
+        )
+    }
+
+    // Keeps the cursor and scroll inside the buffer after it was changed elsewhere
+    pub fn clamp(&mut self, contents: &[String32]) {
+        let rows = max(contents.len(), 1);
+        self.cursor.0 = self.cursor.0.clamp(1, rows);
+        let len = contents.get(self.cursor.0 - 1).map_or(0, |line| line.len());
+        self.cursor.1 = self.cursor.1.clamp(1, max(len, 1));
+        self.selection_start.0 = self.selection_start.0.clamp(1, rows);
+        self.scroll.0 = self.scroll.0.clamp(1, rows);
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Layout {
+    View(usize),
+    Split {
+        direction: Direction,
+        ratio: f64,
+        first: Box<Layout>,
+        second: Box<Layout>,
+    },
+}
+
+fn split_rect(rect: &Rect, direction: Direction, ratio: f64) -> (Rect, Rect, Rect) {
+    match direction {
+        Direction::Horizontal => {
+            let available = rect.height().saturating_sub(1).max(2);
+            let first_size = ((available as f64 * ratio).round() as usize).clamp(1, available - 1);
+            let sep = rect.top + first_size;
+            (
+                Rect {
+                    bottom: sep - 1,
+                    ..rect.clone()
+                },
+                Rect {
+                    top: sep + 1,
+                    ..rect.clone()
+                },
+                Rect {
+                    top: sep,
+                    bottom: sep,
+                    ..rect.clone()
+                },
+            )
+        }
+        Direction::Vertical => {
+            let available = rect.width().saturating_sub(1).max(2);
+            let first_size = ((available as f64 * ratio).round() as usize).clamp(1, available - 1);
+            let sep = rect.left + first_size;
+            (
+                Rect {
+                    right: sep - 1,
+                    ..rect.clone()
+                },
+                Rect {
+                    left: sep + 1,
+                    ..rect.clone()
+                },
+                Rect {
+                    left: sep,
+                    right: sep,
+                    ..rect.clone()
+                },
+            )
+        }
+    }
+}
+
+impl Layout {
+    pub fn contains(&self, view: usize) -> bool {
+        match self {
+            Layout::View(v) => *v == view,
+            Layout::Split { first, second, .. } => first.contains(view) || second.contains(view),
+        }
+    }
+
+    pub fn views(&self) -> Vec<usize> {
+        match self {
+            Layout::View(v) => vec![*v],
+            Layout::Split { first, second, .. } => {
+                let mut views = first.views();
+                views.append(&mut second.views());
+                views
+            }
+        }
+    }
+
+    // Computes the rectangle of every view and of the separators between them
+    pub fn arrange(&self, rect: Rect, views: &mut Vec<(usize, Rect)>, separators: &mut Vec<Rect>) {
+        match self {
+            Layout::View(v) => views.push((*v, rect)),
+            Layout::Split {
+                direction,
+                ratio,
+                first,
+                second,
+            } => {
+                let (first_rect, second_rect, sep) = split_rect(&rect, *direction, *ratio);
+                first.arrange(first_rect, views, separators);
+                second.arrange(second_rect, views, separators);
+                separators.push(sep);
+            }
+        }
+    }
+
+    pub fn split(&mut self, target: usize, new: usize, direction: Direction, rect: &Rect) -> bool {
+        match self {
+            Layout::View(v) if *v == target => {
+                let too_small = match direction {
+                    Direction::Horizontal => rect.height() < 2 * MIN_ROWS + 1,
+                    Direction::Vertical => rect.width() < 2 * MIN_COLS + 1,
+                };
+                if too_small {
+                    return false;
+                }
+                *self = Layout::Split {
+                    direction,
+                    ratio: 0.5,
+                    first: Box::new(Layout::View(new)),
+                    second: Box::new(Layout::View(target)),
+                };
+                true
+            }
+            Layout::View(_) => false,
+            Layout::Split {
+                direction: dir,
+                ratio,
+                first,
+                second,
+            } => {
+                let (first_rect, second_rect, _) = split_rect(rect, *dir, *ratio);
+                first.split(target, new, direction, &first_rect)
+                    || second.split(target, new, direction, &second_rect)
+            }
+        }
+    }
+
+    // Removes a view, letting its sibling take over the space. Views with a higher index
+    // are renumbered, since they are stored in a Vec.
+    pub fn remove(&mut self, target: usize) -> bool {
+        let removed = self.unlink(target);
+        if removed {
+            self.renumber(target);
+        }
+        removed
+    }
+
+    fn unlink(&mut self, target: usize) -> bool {
+        match self {
+            Layout::View(_) => false,
+            Layout::Split { first, second, .. } => {
+                if matches!(**first, Layout::View(v) if v == target) {
+                    *self = std::mem::replace(second.as_mut(), Layout::View(0));
+                    true
+                } else if matches!(**second, Layout::View(v) if v == target) {
+                    *self = std::mem::replace(first.as_mut(), Layout::View(0));
+                    true
+                } else {
+                    first.unlink(target) || second.unlink(target)
+                }
+            }
+        }
+    }
+
+    fn renumber(&mut self, removed: usize) {
+        match self {
+            Layout::View(v) => {
+                if *v > removed {
+                    *v -= 1;
+                }
+            }
+            Layout::Split { first, second, .. } => {
+                first.renumber(removed);
+                second.renumber(removed);
+            }
+        }
+    }
+
+    // Grows the view by delta rows or columns, taking the space from its neighbour in
+    // the innermost split of the given direction
+    pub fn resize(
+        &mut self,
+        target: usize,
+        direction: Direction,
+        delta: isize,
+        rect: &Rect,
+    ) -> bool {
+        let Layout::Split {
+            direction: dir,
+            ratio,
+            first,
+            second,
+        } = self
+        else {
+            return false;
+        };
+        let (first_rect, second_rect, _) = split_rect(rect, *dir, *ratio);
+        let in_first = first.contains(target);
+        let resized = if in_first {
+            first.resize(target, direction, delta, &first_rect)
+        } else {
+            second.resize(target, direction, delta, &second_rect)
+        };
+        if resized || *dir != direction {
+            return resized;
+        }
+
+        let (total, first_size) = match direction {
+            Direction::Horizontal => (rect.height() - 1, first_rect.height()),
+            Direction::Vertical => (rect.width() - 1, first_rect.width()),
+        };
+        let wanted = if in_first {
+            first_size as isize + delta
+        } else {
+            first_size as isize - delta
+        };
+        let new_size = wanted.clamp(1, total as isize - 1);
+        *ratio = new_size as f64 / total as f64;
+        true
+    }
+
+    pub fn equalize(&mut self) {
+        if let Layout::Split {
+            ratio,
+            first,
+            second,
+            ..
+        } = self
+        {
+            *ratio = 0.5;
+            first.equalize();
+            second.equalize();
+        }
+    }
+}
+
+// Finds the neighbouring view in a direction, preferring the one next to the cursor
+pub fn view_in_direction(
+    rects: &[(usize, Rect)],
+    from: usize,
+    cursor: Coord,
+    key: char,
+) -> Option<usize> {
+    let (_, cur) = rects.iter().find(|(v, _)| *v == from)?;
+    rects
+        .iter()
+        .filter(|(v, rect)| {
+            *v != from
+                && match key {
+                    'h' => rect.right < cur.left && rect.top <= cursor.0 && cursor.0 <= rect.bottom,
+                    'l' => rect.left > cur.right && rect.top <= cursor.0 && cursor.0 <= rect.bottom,
+                    'k' => rect.bottom < cur.top && rect.left <= cursor.1 && cursor.1 <= rect.right,
+                    'j' => rect.top > cur.bottom && rect.left <= cursor.1 && cursor.1 <= rect.right,
+                    _ => false,
+                }
+        })
+        .min_by_key(|(_, rect)| match key {
+            'h' => cur.left - rect.right,
+            'l' => rect.left - cur.right,
+            'k' => cur.top - rect.bottom,
+            _ => rect.top - cur.bottom,
+        })
+        .map(|(v, _)| *v)
+}
@@ -0,0 +1,249 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+struct IgnoreRule {
+    pattern: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool, // Matched against the whole relative path instead of just the name
+}
+
+// The rules of a single .gitignore, relative to the directory it is in
+struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    fn read(dir: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(dir.join(".gitignore")).ok()?;
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            let mut line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let negated = line.starts_with('!');
+            if negated {
+                line = &line[1..];
+            }
+            let dir_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let anchored = line.contains('/');
+            let line = line.trim_start_matches('/');
+            rules.push(IgnoreRule {
+                pattern: line.chars().collect(),
+                negated,
+                dir_only,
+                anchored,
+            });
+        }
+        Some(Self {
+            base: dir.to_owned(),
+            rules,
+        })
+    }
+}
+
+fn is_ignored(ignores: &[IgnoreFile], path: &Path, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for ignore in ignores {
+        let Ok(relative) = path.strip_prefix(&ignore.base) else {
+            continue;
+        };
+        let relative: Vec<char> = relative
+            .to_string_lossy()
+            .replace('\\', "/")
+            .chars()
+            .collect();
+        let name: Vec<char> = path
+            .file_name()
+            .map(|name| name.to_string_lossy().chars().collect())
+            .unwrap_or_default();
+        for rule in &ignore.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let text = if rule.anchored { &relative } else { &name };
+            if glob_match(&rule.pattern, text) {
+                ignored = !rule.negated;
+            }
+        }
+    }
+    ignored
+}
+
+// Matches gitignore style globs, where only ** crosses directory separators
+pub fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let mut rest = &pattern[2..];
+            if rest.first() == Some(&'/') {
+                rest = &rest[1..];
+                // "**/" may also match no directories at all
+                if glob_match(rest, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => !text.is_empty() && text[0] != '/' && glob_match(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let Some(close) = pattern.iter().skip(1).position(|c| *c == ']') else {
+                return !text.is_empty() && text[0] == '[' && glob_match(&pattern[1..], &text[1..]);
+            };
+            let class = &pattern[1..close + 1];
+            let Some(&cha) = text.first() else {
+                return false;
+            };
+            let (negated, class) = match class.first() {
+                Some('!') | Some('^') => (true, &class[1..]),
+                _ => (false, class),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    found |= class[i] <= cha && cha <= class[i + 2];
+                    i += 3;
+                } else {
+                    found |= class[i] == cha;
+                    i += 1;
+                }
+            }
+            found != negated && glob_match(&pattern[close + 2..], &text[1..])
+        }
+        Some('\\') if pattern.len() > 1 => {
+            !text.is_empty() && text[0] == pattern[1] && glob_match(&pattern[2..], &text[1..])
+        }
+        Some(c) => !text.is_empty() && text[0] == *c && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+// Visits every file below root that is not ignored by a .gitignore, until visit returns false
+pub fn walk_files(root: &Path, visit: &mut impl FnMut(&Path) -> bool) {
+    let mut ignores = Vec::new();
+    walk_dir(root, &mut ignores, visit);
+}
+
+fn walk_dir(
+    dir: &Path,
+    ignores: &mut Vec<IgnoreFile>,
+    visit: &mut impl FnMut(&Path) -> bool,
+) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return true;
+    };
+    let pushed = match IgnoreFile::read(dir) {
+        Some(ignore) => {
+            ignores.push(ignore);
+            true
+        }
+        None => false,
+    };
+
+    let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut keep_going = true;
+    for entry in entries {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let is_dir = file_type.is_dir();
+        if entry.file_name() == ".git" || is_ignored(ignores, &path, is_dir) {
+            continue;
+        }
+        keep_going = if is_dir {
+            walk_dir(&path, ignores, visit)
+        } else if file_type.is_file() {
+            visit(&path)
+        } else {
+            true
+        };
+        if !keep_going {
+            break;
+        }
+    }
+
+    if pushed {
+        ignores.pop();
+    }
+    keep_going
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        glob_match(&pattern, &text)
+    }
+
+    #[test]
+    fn star_stays_within_a_directory() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(matches("**/target", "target"));
+        assert!(matches("**/target", "a/b/target"));
+        assert!(matches("docs/**", "docs/a/b.md"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+    }
+
+    #[test]
+    fn question_mark_and_classes() {
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file/.txt"));
+        assert!(matches("[abc].o", "b.o"));
+        assert!(matches("[a-c].o", "c.o"));
+        assert!(!matches("[!a-c].o", "c.o"));
+        assert!(matches("[^a-c].o", "d.o"));
+        // A class that is never closed is taken literally
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn escaped_characters_match_literally() {
+        assert!(matches("\\*.txt", "*.txt"));
+        assert!(!matches("\\*.txt", "a.txt"));
+    }
+
+    #[test]
+    fn later_rules_override_earlier_ones() {
+        let rule = |pattern: &str, negated| IgnoreRule {
+            pattern: pattern.chars().collect(),
+            negated,
+            dir_only: false,
+            anchored: false,
+        };
+        let ignores = [IgnoreFile {
+            base: PathBuf::from("/repo"),
+            rules: vec![rule("*.log", false), rule("keep.log", true)],
+        }];
+        assert!(is_ignored(&ignores, Path::new("/repo/a/debug.log"), false));
+        assert!(!is_ignored(&ignores, Path::new("/repo/keep.log"), false));
+        assert!(!is_ignored(&ignores, Path::new("/repo/main.rs"), false));
+    }
+}
@@ -0,0 +1,320 @@
+// Runs the editor in a pseudo terminal against the mock language server in
+// tests/support/mock_lsp.rs, which logs everything it is sent along with its own copy of the
+// document. The server is built as an example, which cargo test does before running the tests.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::fd::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn mock_server() -> PathBuf {
+    let path = Path::new(env!("CARGO_BIN_EXE_rvim"))
+        .parent()
+        .unwrap()
+        .join("examples/mock-lsp");
+    assert!(
+        path.exists(),
+        "The mock server is missing, build it with cargo build --example mock-lsp"
+    );
+    path
+}
+
+struct Editor {
+    child: Child,
+    input: File,
+    output: Arc<Mutex<Vec<u8>>>,
+    dir: PathBuf,
+}
+
+impl Editor {
+    // Opens the file with the given text in a directory of its own, named after the test
+    fn open(name: &str, text: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rvim-lsp-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("config/rvim")).unwrap();
+        fs::write(
+            dir.join("config/rvim/config.toml"),
+            format!(
+                "[language_servers]\nmock = \"'{}' '{}'\"\n",
+                mock_server().display(),
+                dir.join("server.log").display()
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("main.mock"), text).unwrap();
+
+        let (mut master, mut slave) = (0, 0);
+        let size = libc::winsize {
+            ws_row: 30,
+            ws_col: 100,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        assert_eq!(result, 0, "Could not open a pseudo terminal");
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+        let child = Command::new(env!("CARGO_BIN_EXE_rvim"))
+            .arg("main.mock")
+            .current_dir(&dir)
+            .env("XDG_CONFIG_HOME", dir.join("config"))
+            .env("XDG_STATE_HOME", dir.join("state"))
+            .stdin(Stdio::from(slave.try_clone().unwrap()))
+            .stdout(Stdio::from(slave.try_clone().unwrap()))
+            .stderr(Stdio::from(slave))
+            .spawn()
+            .unwrap();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = master.try_clone().unwrap();
+        let sink = output.clone();
+        thread::spawn(move || {
+            let mut chunk = [0; 4096];
+            // Fails once the editor has exited and the terminal is closed
+            while let Ok(n @ 1..) = reader.read(&mut chunk) {
+                sink.lock().unwrap().extend_from_slice(&chunk[..n]);
+            }
+        });
+
+        let editor = Self {
+            child,
+            input: master,
+            output,
+            dir,
+        };
+        editor.wait_for("the document to be opened", || editor.document().is_some());
+        editor
+    }
+
+    // Escape is sent on its own, or it would be read as the start of a key sequence
+    fn keys(&mut self, keys: &str) {
+        for (i, part) in keys.split('\x1b').enumerate() {
+            if i > 0 {
+                thread::sleep(Duration::from_millis(50));
+                self.input.write_all(b"\x1b").unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            self.input.write_all(part.as_bytes()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    fn wait_for(&self, what: &str, mut done: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < TIMEOUT, "Timed out waiting for {what}");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn output_len(&self) -> usize {
+        self.output.lock().unwrap().len()
+    }
+
+    fn output_since(&self, start: usize) -> String {
+        String::from_utf8_lossy(&self.output.lock().unwrap()[start..]).into_owned()
+    }
+
+    // Everything the server was sent, and its copies of the document, oldest first
+    fn log(&self) -> Vec<Value> {
+        fs::read_to_string(self.dir.join("server.log"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+
+    fn methods(&self) -> Vec<String> {
+        self.log()
+            .iter()
+            .filter_map(|entry| entry["method"].as_str().map(str::to_owned))
+            .collect()
+    }
+
+    // The document as the server last saw it
+    fn document(&self) -> Option<String> {
+        self.log()
+            .iter()
+            .rev()
+            .find_map(|entry| entry["text"].as_str().map(str::to_owned))
+    }
+
+    fn wait_for_document(&self, text: &str) {
+        self.wait_for(&format!("the server to see {text:?}"), || {
+            self.document().as_deref() == Some(text)
+        });
+    }
+
+    fn file(&self) -> String {
+        fs::read_to_string(self.dir.join("main.mock")).unwrap()
+    }
+
+    fn quit(mut self) -> Vec<String> {
+        self.keys(":q!\r");
+        let start = Instant::now();
+        while self.child.try_wait().unwrap().is_none() {
+            if start.elapsed() > TIMEOUT {
+                let _ = self.child.kill();
+                panic!("The editor did not exit");
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        self.methods()
+    }
+}
+
+impl Drop for Editor {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if !thread::panicking() {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+fn dir_uri(dir: &Path) -> String {
+    format!("file://{}", fs::canonicalize(dir).unwrap().display())
+}
+
+#[test]
+fn initialize_handshake() {
+    let editor = Editor::open("handshake", "one\ntwo\n");
+    let log = editor.log();
+    assert_eq!(
+        editor.methods()[..3],
+        ["initialize", "initialized", "textDocument/didOpen"]
+    );
+    let params = &log[0]["params"];
+    assert_eq!(params["rootUri"], dir_uri(&editor.dir));
+    assert_eq!(
+        params["capabilities"]["general"]["positionEncodings"],
+        serde_json::json!(["utf-32", "utf-16"])
+    );
+    let document = &log[2]["params"]["textDocument"];
+    assert_eq!(document["languageId"], "mock");
+    assert_eq!(document["version"], 0);
+    assert_eq!(editor.document().unwrap(), "one\ntwo\n");
+
+    let methods = editor.quit();
+    assert_eq!(methods[methods.len() - 2..], ["shutdown", "exit"]);
+}
+
+#[test]
+fn incremental_changes() {
+    let mut editor = Editor::open("changes", "alpha\nbeta\ngamma\n");
+    editor.keys("ifirst \x1b");
+    editor.wait_for_document("first alpha\nbeta\ngamma\n");
+    editor.keys("jox\ry\x1b");
+    editor.wait_for_document("first alpha\nbeta\nx\ny\ngamma\n");
+    editor.keys("kkkdjjjp");
+    editor.wait_for_document("beta\nx\ny\ngamma\nfirst alpha\n");
+    // Joins the first two lines
+    editor.keys("kkki\x7f \x1b");
+    editor.wait_for_document("beta x\ny\ngamma\nfirst alpha\n");
+    editor.keys("u");
+    editor.wait_for_document("beta\nx\ny\ngamma\nfirst alpha\n");
+    editor.keys("\x12jjjjjd:w\r");
+    editor.wait_for_document("beta x\ny\ngamma\n");
+    assert_eq!(editor.file(), "beta x\ny\ngamma\n");
+
+    // Every change was sent as a range, with the version going up by one each time
+    let changes: Vec<Value> = editor
+        .log()
+        .into_iter()
+        .filter(|entry| entry["method"] == "textDocument/didChange")
+        .collect();
+    assert!(!changes.is_empty());
+    for (i, change) in changes.iter().enumerate() {
+        assert_eq!(change["params"]["textDocument"]["version"], i + 1);
+        for content in change["params"]["contentChanges"].as_array().unwrap() {
+            assert!(content.get("range").is_some(), "Full text sent: {content}");
+        }
+    }
+    assert!(editor.quit().contains(&"textDocument/didSave".to_owned()));
+}
+
+#[test]
+fn diagnostics_in_the_gutter() {
+    let mut editor = Editor::open("diagnostics", "fine\nan error here\na warning\n");
+    // The red E of the error and the yellow W of the warning
+    editor.wait_for("the diagnostic signs", || {
+        let output = editor.output_since(0);
+        output.contains("\x1b[31mE") && output.contains("\x1b[33mW")
+    });
+    // The diagnostic is mapped to the word it covers
+    editor.keys("]dix\x1b:w\r");
+    editor.wait_for_document("fine\nan xerror here\na warning\n");
+    editor.wait_for("the file to be written", || {
+        editor.file() == "fine\nan xerror here\na warning\n"
+    });
+
+    editor.keys("d");
+    editor.wait_for_document("fine\na warning\n");
+    let start = editor.output_len();
+    editor.keys("k");
+    editor.wait_for("a redraw", || {
+        editor.output_since(start).contains("\x1b[33mW")
+    });
+    assert!(!editor.output_since(start).contains("\x1b[31mE"));
+}
+
+#[test]
+fn hover() {
+    let mut editor = Editor::open("hover", "let value = 1;\n");
+    let start = editor.output_len();
+    editor.keys("llllK");
+    editor.wait_for("the hover text", || {
+        editor.output_since(start).contains("hover: value")
+    });
+}
+
+#[test]
+fn go_to_definition() {
+    let mut editor = Editor::open("definition", "fn target() {}\n\ntarget();\n");
+    editor.keys("jj");
+    let start = editor.output_len();
+    editor.keys("gd");
+    // The status line shows the cursor once the answer has arrived
+    editor.wait_for("the jump", || editor.output_since(start).contains(" 1:4"));
+    editor.keys("iX\x1b");
+    editor.wait_for_document("fn Xtarget() {}\n\ntarget();\n");
+}
+
+#[test]
+fn rename_is_one_undo_step() {
+    let text = "fn old() {}\nold();\nlet x = old;\n";
+    let mut editor = Editor::open("rename", text);
+    editor.keys("lll:rename new\r");
+    editor.wait_for_document("fn new() {}\nnew();\nlet x = new;\n");
+    editor.keys("u");
+    editor.wait_for_document(text);
+    editor.keys("\x12");
+    editor.wait_for_document("fn new() {}\nnew();\nlet x = new;\n");
+}
+
+#[test]
+fn formatting_is_one_undo_step() {
+    let text = "a  \nb\t\nc\nd \n";
+    let mut editor = Editor::open("format", text);
+    editor.keys(":format\r");
+    editor.wait_for_document("a\nb\nc\nd\n");
+    editor.keys("u");
+    editor.wait_for_document(text);
+}
@@ -0,0 +1,244 @@
+// A scripted language server for the integration tests. It keeps its documents up to date from
+// the changes it is sent, and answers from their text:
+// - every word "error" or "warning" gets a diagnostic
+// - hover shows the word under the cursor
+// - definition goes to the word after a "fn"
+// - rename replaces every occurrence of the word
+// - formatting removes trailing whitespace
+// Each message is appended to the log file given as the argument, and so is the text of a
+// document after it changes.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{stdin, stdout, BufRead, Write};
+
+use serde_json::{json, Value};
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn send(message: Value) {
+    let body = message.to_string();
+    let mut out = stdout().lock();
+    let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = out.flush();
+}
+
+fn log(file: &mut File, entry: &Value) {
+    let _ = writeln!(file, "{entry}");
+}
+
+fn is_word_char(cha: char) -> bool {
+    cha.is_alphanumeric() || cha == '_'
+}
+
+// The index of the character at a position, which counts characters as agreed on initialize
+fn offset(text: &[char], pos: &Value) -> usize {
+    let line = pos["line"].as_u64().unwrap_or(0) as usize;
+    let character = pos["character"].as_u64().unwrap_or(0) as usize;
+    let mut start = 0;
+    for _ in 0..line {
+        match text[start..].iter().position(|c| *c == '\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let end = text[start..]
+        .iter()
+        .position(|c| *c == '\n')
+        .map_or(text.len(), |i| start + i);
+    (start + character).min(end)
+}
+
+fn position(text: &[char], offset: usize) -> Value {
+    let before = &text[..offset];
+    let line = before.iter().filter(|c| **c == '\n').count();
+    let line_start = before.iter().rposition(|c| *c == '\n').map_or(0, |i| i + 1);
+    json!({"line": line, "character": offset - line_start})
+}
+
+fn range(text: &[char], start: usize, end: usize) -> Value {
+    json!({"start": position(text, start), "end": position(text, end)})
+}
+
+// Where every whole word equal to the given one starts
+fn occurrences(text: &[char], word: &[char]) -> Vec<usize> {
+    (0..text.len())
+        .filter(|i| {
+            text[*i..].starts_with(word)
+                && !(*i > 0 && is_word_char(text[i - 1]))
+                && !text.get(i + word.len()).is_some_and(|c| is_word_char(*c))
+        })
+        .collect()
+}
+
+fn word_at(text: &[char], offset: usize) -> Option<(usize, Vec<char>)> {
+    let mut start = offset;
+    while start > 0 && is_word_char(text[start - 1]) {
+        start -= 1;
+    }
+    let mut end = offset;
+    while end < text.len() && is_word_char(text[end]) {
+        end += 1;
+    }
+    (end > start).then(|| (start, text[start..end].to_vec()))
+}
+
+fn publish_diagnostics(uri: &str, text: &[char]) {
+    let mut diagnostics = Vec::new();
+    for (word, severity) in [("error", 1), ("warning", 2)] {
+        let word: Vec<char> = word.chars().collect();
+        for start in occurrences(text, &word) {
+            diagnostics.push(json!({
+                "range": range(text, start, start + word.len()),
+                "severity": severity,
+                "message": format!("found {}", word.iter().collect::<String>()),
+            }));
+        }
+    }
+    send(json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    }));
+}
+
+fn respond(method: &str, params: &Value, documents: &HashMap<String, Vec<char>>) -> Value {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+    let empty = Vec::new();
+    let text = documents.get(uri).unwrap_or(&empty);
+    let word = || word_at(text, offset(text, &params["position"]));
+    match method {
+        "initialize" => json!({
+            "capabilities": {
+                "positionEncoding": "utf-32",
+                "textDocumentSync": {"openClose": true, "change": 2, "save": true},
+                "hoverProvider": true,
+                "definitionProvider": true,
+                "renameProvider": true,
+                "documentFormattingProvider": true,
+            },
+        }),
+        "textDocument/hover" => match word() {
+            Some((_, word)) => {
+                let value = format!("hover: {}", word.iter().collect::<String>());
+                json!({"contents": {"kind": "plaintext", "value": value}})
+            }
+            None => Value::Null,
+        },
+        "textDocument/definition" => {
+            let Some((_, word)) = word() else {
+                return Value::Null;
+            };
+            let declaration: Vec<char> = "fn ".chars().chain(word.iter().copied()).collect();
+            match occurrences(text, &declaration).first() {
+                Some(start) => {
+                    let start = start + 3;
+                    json!([{"uri": uri, "range": range(text, start, start + word.len())}])
+                }
+                None => Value::Null,
+            }
+        }
+        "textDocument/rename" => {
+            let Some((_, word)) = word() else {
+                return Value::Null;
+            };
+            let edits: Vec<Value> = occurrences(text, &word)
+                .into_iter()
+                .map(|start| {
+                    json!({
+                        "range": range(text, start, start + word.len()),
+                        "newText": params["newName"],
+                    })
+                })
+                .collect();
+            json!({"changes": {uri: edits}})
+        }
+        "textDocument/formatting" => {
+            let mut edits = Vec::new();
+            let mut start = 0;
+            for line in text.split(|c| *c == '\n') {
+                let end = start + line.len();
+                let trimmed = line
+                    .iter()
+                    .rposition(|c| !c.is_whitespace())
+                    .map_or(0, |i| i + 1);
+                if trimmed < line.len() {
+                    edits.push(json!({"range": range(text, start + trimmed, end), "newText": ""}));
+                }
+                start = end + 1;
+            }
+            Value::Array(edits)
+        }
+        _ => Value::Null,
+    }
+}
+
+fn main() {
+    let path = std::env::args().nth(1).expect("Usage: mock-lsp log_file");
+    let mut log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .expect("Could not open the log file");
+    let mut documents: HashMap<String, Vec<char>> = HashMap::new();
+    let mut reader = stdin().lock();
+    while let Some(message) = read_message(&mut reader) {
+        log(&mut log_file, &message);
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        if let Some(id) = message.get("id") {
+            let result = respond(method, params, &documents);
+            send(json!({"jsonrpc": "2.0", "id": id, "result": result}));
+            continue;
+        }
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_owned();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                documents.insert(uri.clone(), text.chars().collect());
+            }
+            "textDocument/didChange" => {
+                let text = documents.entry(uri.clone()).or_default();
+                for change in params["contentChanges"].as_array().into_iter().flatten() {
+                    let new: Vec<char> = change["text"].as_str().unwrap_or("").chars().collect();
+                    if change.get("range").is_some() {
+                        let start = offset(text, &change["range"]["start"]);
+                        let end = offset(text, &change["range"]["end"]);
+                        text.splice(start..end.max(start), new);
+                    } else {
+                        *text = new;
+                    }
+                }
+            }
+            "exit" => break,
+            _ => continue,
+        }
+        let text = &documents[&uri];
+        log(
+            &mut log_file,
+            &json!({"document": uri, "text": text.iter().collect::<String>()}),
+        );
+        publish_diagnostics(&uri, text);
+    }
+}